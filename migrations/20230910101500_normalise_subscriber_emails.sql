@@ -0,0 +1,46 @@
+-- We wrap the whole migration in a transaction to make sure
+-- it succeeds or fails atomically.
+BEGIN;
+    ALTER TABLE subscriptions ADD COLUMN email_normalised TEXT NULL;
+    -- Backfill the normalised form of historical entities. Punycode conversion
+    -- is not available in SQL, so existing IDN domains are only lowercased.
+    UPDATE subscriptions
+        SET email_normalised = lower(trim(email));
+
+    -- Detect rows that collapse onto the same normalised address, keeping a
+    -- confirmed subscription over a pending one and the oldest one otherwise.
+    CREATE TEMPORARY TABLE duplicate_subscriptions ON COMMIT DROP AS
+        SELECT id, survivor_id FROM (
+            SELECT
+                id,
+                first_value(id) OVER (
+                    PARTITION BY email_normalised
+                    ORDER BY (status = 'confirmed') DESC, subscribed_at ASC, id ASC
+                ) AS survivor_id
+            FROM subscriptions
+        ) ranked
+        WHERE id <> survivor_id;
+
+    DO $$
+    BEGIN
+        RAISE NOTICE 'Merging % duplicate subscriptions',
+            (SELECT count(*) FROM duplicate_subscriptions);
+    END
+    $$;
+
+    -- Merge duplicates into the surviving subscription
+    UPDATE subscription_tokens
+        SET subscriber_id = duplicate_subscriptions.survivor_id
+        FROM duplicate_subscriptions
+        WHERE subscription_tokens.subscriber_id = duplicate_subscriptions.id;
+    DELETE FROM subscriptions
+        USING duplicate_subscriptions
+        WHERE subscriptions.id = duplicate_subscriptions.id;
+
+    -- Uniqueness is now enforced on the normalised form, while `email` keeps
+    -- the address as it was submitted.
+    ALTER TABLE subscriptions ALTER COLUMN email_normalised SET NOT NULL;
+    ALTER TABLE subscriptions
+        ADD CONSTRAINT subscriptions_email_normalised_key UNIQUE (email_normalised);
+    ALTER TABLE subscriptions DROP CONSTRAINT subscriptions_email_key;
+COMMIT;
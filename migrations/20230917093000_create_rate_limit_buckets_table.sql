@@ -0,0 +1,7 @@
+-- Token buckets shared by every instance of the app when rate limiting is backed by Postgres
+CREATE TABLE rate_limit_buckets (
+    key TEXT NOT NULL,
+    tokens DOUBLE PRECISION NOT NULL,
+    updated_at timestamptz NOT NULL,
+    PRIMARY KEY (key)
+);
@@ -0,0 +1,15 @@
+-- Every published issue is recorded, scheduled ones wait here until `send_at`
+CREATE TABLE newsletter_issues (
+    newsletter_issue_id uuid NOT NULL,
+    title TEXT NOT NULL,
+    text_content TEXT NOT NULL,
+    html_content TEXT NOT NULL,
+    -- One of 'scheduled', 'sending', 'sent', 'cancelled' or 'failed'
+    status TEXT NOT NULL,
+    send_at timestamptz NULL,
+    created_at timestamptz NOT NULL,
+    published_at timestamptz NULL,
+    PRIMARY KEY (newsletter_issue_id)
+);
+-- The scheduler only ever looks for scheduled issues that are due
+CREATE INDEX newsletter_issues_due_idx ON newsletter_issues (send_at) WHERE status = 'scheduled';
@@ -0,0 +1,2 @@
+-- Drafts are newsletter issues with status 'draft', edited until they are published
+ALTER TABLE newsletter_issues ADD COLUMN updated_at timestamptz NULL;
@@ -0,0 +1,8 @@
+-- Overrides for the email templates shipped in `templates/email`, keyed by file name
+-- (e.g. `newsletter.html`). They are read and validated when the application starts.
+CREATE TABLE email_templates (
+    name TEXT NOT NULL,
+    source TEXT NOT NULL,
+    updated_at timestamptz NOT NULL,
+    PRIMARY KEY (name)
+);
@@ -0,0 +1,5 @@
+-- Tracking is on unless the editor turns it off when publishing.
+-- `tracked_recipients` is the denominator of the open and click rates.
+ALTER TABLE newsletter_issues
+    ADD COLUMN tracking_enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    ADD COLUMN tracked_recipients INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE subscriptions ADD COLUMN tracking_opt_out BOOLEAN NOT NULL DEFAULT FALSE;
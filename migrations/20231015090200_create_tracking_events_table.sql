@@ -0,0 +1,14 @@
+CREATE TABLE tracking_events (
+    tracking_event_id uuid NOT NULL,
+    newsletter_issue_id uuid NOT NULL
+        REFERENCES newsletter_issues (newsletter_issue_id),
+    subscriber_id uuid NOT NULL
+        REFERENCES subscriptions (id),
+    -- 'open' or 'click'
+    kind TEXT NOT NULL,
+    -- The link that was followed, for clicks
+    url TEXT NULL,
+    occurred_at timestamptz NOT NULL,
+    PRIMARY KEY (tracking_event_id)
+);
+CREATE INDEX tracking_events_issue_idx ON tracking_events (newsletter_issue_id);
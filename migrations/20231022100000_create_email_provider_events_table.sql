@@ -0,0 +1,10 @@
+-- Every webhook call from the email provider is kept as received, for auditing
+CREATE TABLE email_provider_events (
+    email_provider_event_id uuid NOT NULL,
+    -- e.g. 'Bounce', 'SpamComplaint' or 'SubscriptionChange'
+    record_type TEXT NOT NULL,
+    email TEXT NULL,
+    payload TEXT NOT NULL,
+    received_at timestamptz NOT NULL,
+    PRIMARY KEY (email_provider_event_id)
+);
@@ -0,0 +1,13 @@
+-- Addresses and domains we must never email again, whatever their subscription status
+CREATE TABLE suppressions (
+    suppression_id uuid NOT NULL,
+    -- Exactly one of the two is set: the normalised address, or a lowercase A-label domain
+    email TEXT NULL UNIQUE,
+    domain TEXT NULL UNIQUE,
+    reason TEXT NOT NULL,
+    added_by uuid NOT NULL
+        REFERENCES users (user_id),
+    created_at timestamptz NOT NULL,
+    PRIMARY KEY (suppression_id),
+    CHECK ((email IS NULL) <> (domain IS NULL))
+);
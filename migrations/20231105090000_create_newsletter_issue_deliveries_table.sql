@@ -0,0 +1,17 @@
+-- One row per recipient of an issue, so that the outcome of a publish can be inspected
+CREATE TABLE newsletter_issue_deliveries (
+    newsletter_issue_id uuid NOT NULL
+        REFERENCES newsletter_issues (newsletter_issue_id),
+    subscriber_id uuid NOT NULL
+        REFERENCES subscriptions (id),
+    -- One of 'queued', 'sent', 'failed' or 'bounced'
+    status TEXT NOT NULL,
+    -- The `MessageID` returned by Postmark, used to match bounce webhooks
+    provider_message_id TEXT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT NULL,
+    updated_at timestamptz NOT NULL,
+    PRIMARY KEY (newsletter_issue_id, subscriber_id)
+);
+CREATE INDEX newsletter_issue_deliveries_message_idx
+    ON newsletter_issue_deliveries (provider_message_id);
@@ -0,0 +1,16 @@
+-- Emails waiting for the relay worker, e.g. because the provider was unavailable
+CREATE TABLE email_outbox (
+    email_outbox_id uuid NOT NULL,
+    recipient TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    html_body TEXT NOT NULL,
+    text_body TEXT NOT NULL,
+    -- 'pending' until sent, when the row is deleted, or 'failed' once we gave up
+    status TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT NULL,
+    next_attempt_at timestamptz NOT NULL,
+    created_at timestamptz NOT NULL,
+    PRIMARY KEY (email_outbox_id)
+);
+CREATE INDEX email_outbox_due_idx ON email_outbox (next_attempt_at) WHERE status = 'pending';
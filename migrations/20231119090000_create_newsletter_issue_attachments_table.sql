@@ -0,0 +1,13 @@
+-- Files sent along with every email of an issue
+CREATE TABLE newsletter_issue_attachments (
+    newsletter_issue_id uuid NOT NULL
+        REFERENCES newsletter_issues (newsletter_issue_id),
+    -- Keeps the attachments in the order they were uploaded
+    position INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    content BYTEA NOT NULL,
+    -- Set for inline images, referenced from the HTML as `cid:<content_id>`
+    content_id TEXT NULL,
+    PRIMARY KEY (newsletter_issue_id, position)
+);
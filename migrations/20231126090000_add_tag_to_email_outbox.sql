@@ -0,0 +1,2 @@
+-- Postmark tag the email is sent with, e.g. 'confirmation'
+ALTER TABLE email_outbox ADD COLUMN tag TEXT NULL;
@@ -0,0 +1,4 @@
+-- Name of the sender identity an issue is sent from, the one configured for newsletters when NULL
+ALTER TABLE newsletter_issues ADD COLUMN sender_identity TEXT NULL;
+-- Lets the relay pick the sender identity of queued emails, e.g. 'confirmation'
+ALTER TABLE email_outbox ADD COLUMN email_kind TEXT NULL;
@@ -0,0 +1,2 @@
+-- Email backend that accepted the delivery, e.g. 'primary' or 'secondary'
+ALTER TABLE newsletter_issue_deliveries ADD COLUMN provider TEXT NULL;
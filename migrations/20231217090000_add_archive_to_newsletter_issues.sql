@@ -0,0 +1,15 @@
+-- Published issues appear in the public archive at `/issues/{slug}` unless `archived` is turned off.
+-- The slug is derived from the title when the issue is published.
+ALTER TABLE newsletter_issues
+    ADD COLUMN archived BOOLEAN NOT NULL DEFAULT TRUE,
+    ADD COLUMN slug TEXT NULL UNIQUE;
+-- Issues published so far get their id appended, which keeps their slugs unique
+UPDATE newsletter_issues
+SET slug = concat_ws(
+    '-',
+    NULLIF(trim(BOTH '-' FROM lower(regexp_replace(title, '[^a-zA-Z0-9]+', '-', 'g'))), ''),
+    left(newsletter_issue_id::text, 8)
+)
+WHERE published_at IS NOT NULL;
+CREATE INDEX newsletter_issues_archive_idx ON newsletter_issues (published_at DESC)
+    WHERE archived AND slug IS NOT NULL;
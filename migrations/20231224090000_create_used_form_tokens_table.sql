@@ -0,0 +1,8 @@
+-- Nonces of the form tokens already submitted, so that each token is accepted only once.
+-- Rows are useless once the token expired and are removed as new ones come in.
+CREATE TABLE used_form_tokens (
+    nonce TEXT NOT NULL,
+    expires_at timestamptz NOT NULL,
+    PRIMARY KEY (nonce)
+);
+CREATE INDEX used_form_tokens_expires_at_idx ON used_form_tokens (expires_at);
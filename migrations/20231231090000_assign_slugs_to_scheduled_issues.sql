@@ -0,0 +1,9 @@
+-- Slugs are now assigned when an issue is published rather than once it has been sent,
+-- so issues scheduled before this change need one too.
+UPDATE newsletter_issues
+SET slug = concat_ws(
+    '-',
+    NULLIF(trim(BOTH '-' FROM lower(regexp_replace(title, '[^a-zA-Z0-9]+', '-', 'g'))), ''),
+    left(newsletter_issue_id::text, 8)
+)
+WHERE slug IS NULL AND status <> 'draft';
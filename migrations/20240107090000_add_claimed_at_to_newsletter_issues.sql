@@ -0,0 +1,4 @@
+-- When the instance delivering an issue last showed signs of life. The scheduler takes over
+-- issues left in 'sending' whose lease has run out, e.g. because the instance died.
+ALTER TABLE newsletter_issues ADD COLUMN claimed_at timestamptz NULL;
+UPDATE newsletter_issues SET claimed_at = now() WHERE status = 'sending';
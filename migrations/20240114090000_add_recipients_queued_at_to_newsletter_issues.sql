@@ -0,0 +1,8 @@
+-- Set once the recipients of an issue have been queued, so that taking over a stalled
+-- delivery does not add the subscribers who confirmed since
+ALTER TABLE newsletter_issues ADD COLUMN recipients_queued_at timestamptz NULL;
+UPDATE newsletter_issues i SET recipients_queued_at = now()
+WHERE EXISTS (
+    SELECT 1 FROM newsletter_issue_deliveries d
+    WHERE d.newsletter_issue_id = i.newsletter_issue_id
+);
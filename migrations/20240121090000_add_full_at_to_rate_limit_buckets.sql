@@ -0,0 +1,6 @@
+-- When a bucket will have refilled completely. Full buckets are equivalent to missing ones
+-- and are deleted periodically, see `rate_limiting::store::delete_full_buckets`.
+ALTER TABLE rate_limit_buckets ADD COLUMN full_at timestamptz NULL;
+-- Every route refills its bucket within a day
+UPDATE rate_limit_buckets SET full_at = updated_at + interval '1 day';
+ALTER TABLE rate_limit_buckets ALTER COLUMN full_at SET NOT NULL;
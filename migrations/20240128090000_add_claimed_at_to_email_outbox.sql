@@ -0,0 +1,3 @@
+-- Emails being sent are moved to 'sending' instead of staying locked for the whole call to
+-- the provider. The relay worker claims them again once `claimed_at` is older than its lease.
+ALTER TABLE email_outbox ADD COLUMN claimed_at timestamptz NULL;
@@ -0,0 +1,308 @@
+use crate::email_templates::escape_html;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use lol_html::{element, RewriteStrSettings};
+use sqlx::{Acquire, PgConnection};
+use tera::Tera;
+use uuid::Uuid;
+
+// Compiled in, unlike the email templates they are not meant to be edited by the editors
+const TEMPLATES: [(&str, &str); 4] = [
+    ("issues.html", include_str!("../templates/web/issues.html")),
+    ("issue.html", include_str!("../templates/web/issue.html")),
+    (
+        "feed.atom.xml",
+        include_str!("../templates/web/feed.atom.xml"),
+    ),
+    (
+        "feed.rss.xml",
+        include_str!("../templates/web/feed.rss.xml"),
+    ),
+];
+
+// Slugs are shortened to this many bytes, before a suffix is added to keep them unique
+const MAX_SLUG_LENGTH: usize = 80;
+// Issues with the same title published at the same time race for the same suffix
+const MAX_SLUG_ATTEMPTS: u32 = 5;
+
+pub struct ArchivedIssue {
+    pub slug: String,
+    pub title: String,
+    pub html_content: String,
+    pub published_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+struct IssueContext<'a> {
+    slug: &'a str,
+    title: &'a str,
+    // Sanitised when the issue was published, inserted as is with `{{ html_content | safe }}`
+    html_content: String,
+    // RFC 3339, as Atom and the `datetime` attribute expect
+    published_at: String,
+    // RFC 2822, as RSS expects
+    pub_date: String,
+    published_on: String,
+}
+
+impl<'a> IssueContext<'a> {
+    fn new(issue: &'a ArchivedIssue, base_url: &str) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            slug: &issue.slug,
+            title: &issue.title,
+            html_content: resolve_inline_images(&issue.html_content, base_url, &issue.slug)?,
+            published_at: issue.published_at.to_rfc3339(),
+            pub_date: issue.published_at.to_rfc2822(),
+            published_on: issue.published_at.format("%B %-d, %Y").to_string(),
+        })
+    }
+}
+
+/// Inline images are referenced as `cid:<content id>`, which only email clients understand.
+/// Point them to `/issues/{slug}/inline/{content id}` instead.
+fn resolve_inline_images(html: &str, base_url: &str, slug: &str) -> Result<String, anyhow::Error> {
+    let base_url = url::Url::parse(base_url).context("The base URL of the archive is invalid.")?;
+    lol_html::rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![element!("img[src^='cid:']", |image| {
+                let Some(src) = image.get_attribute("src") else {
+                    return Ok(());
+                };
+                let mut url = base_url.clone();
+                url.path_segments_mut()
+                    .map_err(|_| "The base URL of the archive cannot have a path")?
+                    .pop_if_empty()
+                    .extend(["issues", slug, "inline", &src["cid:".len()..]]);
+                image.set_attribute("src", url.as_str())?;
+                Ok(())
+            })],
+            ..RewriteStrSettings::default()
+        },
+    )
+    .context("Failed to resolve the inline images of an archived issue.")
+}
+
+/// Renders the public archive of published issues and its Atom and RSS feeds.
+pub struct ArchivePages {
+    tera: Tera,
+    title: String,
+    base_url: String,
+    // How many of the latest issues the feeds carry
+    pub feed_length: u32,
+}
+
+impl ArchivePages {
+    pub fn new(title: String, base_url: String, feed_length: u32) -> Result<Self, anyhow::Error> {
+        let mut tera = Tera::default();
+        tera.add_raw_templates(TEMPLATES)
+            .context("Failed to parse the archive templates.")?;
+        tera.set_escape_fn(escape_html);
+        Ok(Self {
+            tera,
+            title,
+            base_url,
+            feed_length,
+        })
+    }
+
+    pub fn index(&self, issues: &[ArchivedIssue]) -> Result<String, anyhow::Error> {
+        let issues = self.contexts(issues)?;
+        self.render("issues.html", |context| {
+            context.insert("issues", &issues);
+        })
+    }
+
+    pub fn issue(&self, issue: &ArchivedIssue) -> Result<String, anyhow::Error> {
+        let issue = IssueContext::new(issue, &self.base_url)?;
+        self.render("issue.html", |context| {
+            context.insert("issue", &issue);
+        })
+    }
+
+    /// `issues` must be sorted from the most recent.
+    pub fn atom_feed(&self, issues: &[ArchivedIssue]) -> Result<String, anyhow::Error> {
+        let updated = issues
+            .first()
+            .map(|issue| issue.published_at)
+            .unwrap_or_else(Utc::now);
+        let issues = self.contexts(issues)?;
+        self.render("feed.atom.xml", |context| {
+            context.insert("issues", &issues);
+            context.insert("updated", &updated.to_rfc3339());
+        })
+    }
+
+    pub fn rss_feed(&self, issues: &[ArchivedIssue]) -> Result<String, anyhow::Error> {
+        let issues = self.contexts(issues)?;
+        self.render("feed.rss.xml", |context| {
+            context.insert("issues", &issues);
+        })
+    }
+
+    fn contexts<'a>(
+        &self,
+        issues: &'a [ArchivedIssue],
+    ) -> Result<Vec<IssueContext<'a>>, anyhow::Error> {
+        issues
+            .iter()
+            .map(|issue| IssueContext::new(issue, &self.base_url))
+            .collect()
+    }
+
+    fn render(
+        &self,
+        template: &str,
+        fill: impl FnOnce(&mut tera::Context),
+    ) -> Result<String, anyhow::Error> {
+        let mut context = tera::Context::new();
+        context.insert("site_title", &self.title);
+        context.insert("base_url", &self.base_url);
+        fill(&mut context);
+        self.tera
+            .render(template, &context)
+            .with_context(|| format!("Failed to render the {} archive template.", template))
+    }
+}
+
+/// Turn a title into the last segment of the issue's URL, e.g. `Our 2nd issue!` into
+/// `our-2nd-issue`. Characters other than ASCII letters and digits are dropped.
+pub fn slugify(title: &str) -> String {
+    let mut slug = String::with_capacity(title.len());
+    for word in title
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+    {
+        if slug.len() + word.len() >= MAX_SLUG_LENGTH {
+            break;
+        }
+        if !slug.is_empty() {
+            slug.push('-');
+        }
+        slug.push_str(&word.to_ascii_lowercase());
+    }
+    if slug.is_empty() {
+        slug.push_str("issue");
+    }
+    slug
+}
+
+/// Give the issue the slug of `title`, followed by the first free number if another issue
+/// already has it. Issues that have a slug keep it.
+/// Meant to run in the transaction that publishes the issue, each attempt in a savepoint so
+/// that losing the race for a slug does not abort the whole transaction.
+#[tracing::instrument(name = "Assign slug", skip(connection, title))]
+pub async fn assign_slug(
+    connection: &mut PgConnection,
+    newsletter_issue_id: Uuid,
+    title: &str,
+) -> Result<(), sqlx::Error> {
+    let slug = slugify(title);
+    let mut attempt = 1;
+    loop {
+        let mut savepoint = connection.begin().await?;
+        // Slugs only contain characters that `LIKE` takes literally
+        let taken: Vec<String> = sqlx::query!(
+            r#"SELECT slug AS "slug!" FROM newsletter_issues WHERE slug = $1 OR slug LIKE $1 || '-%'"#,
+            slug
+        )
+        .fetch_all(&mut *savepoint)
+        .await?
+        .into_iter()
+        .map(|r| r.slug)
+        .collect();
+        let candidate = std::iter::once(slug.clone())
+            .chain((2..).map(|n| format!("{}-{}", slug, n)))
+            .find(|candidate| !taken.contains(candidate))
+            .unwrap();
+        let outcome = sqlx::query!(
+            r#"
+            UPDATE newsletter_issues SET slug = $2
+            WHERE newsletter_issue_id = $1 AND slug IS NULL
+            "#,
+            newsletter_issue_id,
+            candidate
+        )
+        .execute(&mut *savepoint)
+        .await;
+        match outcome {
+            Ok(_) => return savepoint.commit().await,
+            Err(sqlx::Error::Database(error))
+                if error.code().as_deref() == Some("23505") && attempt < MAX_SLUG_ATTEMPTS =>
+            {
+                savepoint.rollback().await?;
+                attempt += 1;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{slugify, ArchivePages, ArchivedIssue};
+    use chrono::{TimeZone, Utc};
+
+    fn issue() -> ArchivedIssue {
+        ArchivedIssue {
+            slug: "fish-and-chips".into(),
+            title: "Fish & Chips".into(),
+            html_content: "<p>Hello</p>".into(),
+            published_at: Utc.with_ymd_and_hms(2023, 12, 17, 9, 0, 0).unwrap(),
+        }
+    }
+
+    fn pages() -> ArchivePages {
+        ArchivePages::new(
+            "Zero To Production".into(),
+            "https://example.com".into(),
+            20,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn slugs_keep_lowercase_ascii_words() {
+        assert_eq!(slugify("Our 2nd issue!"), "our-2nd-issue");
+        assert_eq!(slugify("  Rust -- Déjà vu  "), "rust-d-j-vu");
+        assert_eq!(slugify("¡¿?!"), "issue");
+    }
+
+    #[test]
+    fn long_slugs_are_cut_between_words() {
+        let slug = slugify(&"word ".repeat(40));
+        assert!(slug.len() < 80);
+        assert!(slug.ends_with("word"));
+    }
+
+    #[test]
+    fn issue_pages_embed_the_html_body_and_escape_the_title() {
+        let page = pages().issue(&issue()).unwrap();
+        assert!(page.contains("<h1>Fish &amp; Chips</h1>"));
+        assert!(page.contains("<p>Hello</p>"));
+        assert!(page.contains("December 17, 2023"));
+    }
+
+    #[test]
+    fn inline_images_point_to_the_archive() {
+        let issue = ArchivedIssue {
+            html_content: r#"<p><img src="cid:logo one.png" alt="Logo"></p>"#.into(),
+            ..issue()
+        };
+        let page = pages().issue(&issue).unwrap();
+        assert!(page.contains(
+            r#"<img src="https://example.com/issues/fish-and-chips/inline/logo%20one.png" alt="Logo">"#
+        ));
+    }
+
+    #[test]
+    fn feeds_escape_the_html_body() {
+        let pages = pages();
+        let atom = pages.atom_feed(&[issue()]).unwrap();
+        assert!(atom.contains("<content type=\"html\">&lt;p&gt;Hello&lt;/p&gt;</content>"));
+        assert!(atom.contains("<id>https://example.com/issues/fish-and-chips</id>"));
+        assert!(atom.contains("<updated>2023-12-17T09:00:00+00:00</updated>"));
+        let rss = pages.rss_feed(&[issue()]).unwrap();
+        assert!(rss.contains("<pubDate>Sun, 17 Dec 2023 09:00:00 +0000</pubDate>"));
+    }
+}
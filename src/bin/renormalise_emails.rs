@@ -0,0 +1,17 @@
+//! One-off job bringing `email_normalised` in line with the current normalisation rules,
+//! see `email_normalisation::renormalise_subscriber_emails`. Run it once after deploying
+//! a change to those rules.
+use zero2prod::configuration::get_configuration;
+use zero2prod::email_normalisation::renormalise_subscriber_emails;
+use zero2prod::startup::get_connection_pool;
+use zero2prod::telemetry::*;
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let subscriber = get_subscriber("renormalise_emails".into(), "info".into(), std::io::stdout);
+    init_subscriber(subscriber);
+
+    let configuration = get_configuration().expect("Failed to read the configuration");
+    let connection_pool = get_connection_pool(&configuration.database);
+    renormalise_subscriber_emails(&connection_pool).await
+}
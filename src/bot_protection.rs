@@ -0,0 +1,359 @@
+use chrono::{DateTime, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use reqwest::Client;
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use sqlx::PgPool;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum FormTokenError {
+    #[error("The form token is missing or invalid, please reload the form.")]
+    Invalid,
+    #[error("The form was submitted too quickly, please try again.")]
+    TooFast,
+    #[error("The form has expired, please reload it.")]
+    Expired,
+    #[error("The form was already submitted, please reload it.")]
+    AlreadyUsed,
+}
+
+/// A form token that passed verification, to be burnt with `burn_form_token`.
+#[derive(Debug, PartialEq)]
+pub struct VerifiedFormToken {
+    nonce: String,
+    expires_at: DateTime<Utc>,
+}
+
+// Issues and checks the signed timestamp embedded in the subscription form.
+// A token is `<unix timestamp>.<nonce>.<base64 HMAC-SHA256 of both>`; the random nonce
+// lets each token be accepted only once.
+pub struct FormTokenSigner {
+    secret: Secret<String>,
+    min_fill_seconds: i64,
+    max_age_seconds: i64,
+}
+
+impl FormTokenSigner {
+    pub fn new(
+        secret: Secret<String>,
+        min_fill_time: std::time::Duration,
+        max_age: std::time::Duration,
+    ) -> Self {
+        Self {
+            secret,
+            min_fill_seconds: min_fill_time.as_secs() as i64,
+            max_age_seconds: max_age.as_secs() as i64,
+        }
+    }
+
+    pub fn issue(&self) -> String {
+        self.issue_at(Utc::now())
+    }
+
+    pub fn issue_at(&self, issued_at: DateTime<Utc>) -> String {
+        let timestamp = issued_at.timestamp();
+        let mut rng = thread_rng();
+        let nonce: String = std::iter::repeat_with(|| rng.sample(Alphanumeric))
+            .map(char::from)
+            .take(22)
+            .collect();
+        let signature = self.mac(timestamp, &nonce).finalize().into_bytes();
+        format!(
+            "{}.{}.{}",
+            timestamp,
+            nonce,
+            base64::Engine::encode(&base64::engine::general_purpose::URL_SAFE_NO_PAD, signature)
+        )
+    }
+
+    pub fn verify(&self, token: &str) -> Result<VerifiedFormToken, FormTokenError> {
+        self.verify_at(token, Utc::now())
+    }
+
+    fn verify_at(
+        &self,
+        token: &str,
+        now: DateTime<Utc>,
+    ) -> Result<VerifiedFormToken, FormTokenError> {
+        let mut parts = token.splitn(3, '.');
+        let (Some(timestamp), Some(nonce), Some(signature)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(FormTokenError::Invalid);
+        };
+        let timestamp: i64 = timestamp.parse().map_err(|_| FormTokenError::Invalid)?;
+        let signature =
+            base64::Engine::decode(&base64::engine::general_purpose::URL_SAFE_NO_PAD, signature)
+                .map_err(|_| FormTokenError::Invalid)?;
+        // `verify_slice` compares in constant time
+        self.mac(timestamp, nonce)
+            .verify_slice(&signature)
+            .map_err(|_| FormTokenError::Invalid)?;
+
+        let elapsed_seconds = now.timestamp() - timestamp;
+        if elapsed_seconds < self.min_fill_seconds {
+            Err(FormTokenError::TooFast)
+        } else if elapsed_seconds > self.max_age_seconds {
+            Err(FormTokenError::Expired)
+        } else {
+            Ok(VerifiedFormToken {
+                nonce: nonce.to_owned(),
+                expires_at: Utc
+                    .timestamp_opt(timestamp + self.max_age_seconds, 0)
+                    .single()
+                    .ok_or(FormTokenError::Invalid)?,
+            })
+        }
+    }
+
+    fn mac(&self, timestamp: i64, nonce: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.expose_secret().as_bytes())
+            .expect("HMAC can take a key of any size");
+        mac.update(format!("{}.{}", timestamp, nonce).as_bytes());
+        mac
+    }
+}
+
+/// Record that `token` was submitted. Fails with `AlreadyUsed` if it was before.
+#[tracing::instrument(name = "Burn form token", skip(pool, token))]
+pub async fn burn_form_token(
+    pool: &PgPool,
+    token: &VerifiedFormToken,
+) -> Result<Result<(), FormTokenError>, sqlx::Error> {
+    // Expired nonces could not be replayed anyway
+    sqlx::query!("DELETE FROM used_form_tokens WHERE expires_at < now()")
+        .execute(pool)
+        .await?;
+    let inserted = sqlx::query!(
+        r#"
+        INSERT INTO used_form_tokens (nonce, expires_at)
+        VALUES ($1, $2)
+        ON CONFLICT DO NOTHING
+        "#,
+        token.nonce,
+        token.expires_at
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    Ok(if inserted == 1 {
+        Ok(())
+    } else {
+        Err(FormTokenError::AlreadyUsed)
+    })
+}
+
+// Server-side verification of hCaptcha/Turnstile-style challenge responses.
+// Both services accept the same `secret`, `response` and `remoteip` form fields.
+pub struct ChallengeVerifier {
+    http_client: Client,
+    verify_url: String,
+    secret: Secret<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct ChallengeOutcome {
+    success: bool,
+    #[serde(rename = "error-codes", default)]
+    error_codes: Vec<String>,
+}
+
+impl ChallengeVerifier {
+    pub fn new(verify_url: String, secret: Secret<String>, timeout: std::time::Duration) -> Self {
+        let http_client = Client::builder().timeout(timeout).build().unwrap();
+        Self {
+            http_client,
+            verify_url,
+            secret,
+        }
+    }
+
+    #[tracing::instrument(name = "Verify challenge response", skip(self, response))]
+    pub async fn verify(
+        &self,
+        response: &str,
+        remote_ip: Option<&str>,
+    ) -> Result<bool, reqwest::Error> {
+        let mut form = vec![
+            ("secret", self.secret.expose_secret().as_str()),
+            ("response", response),
+        ];
+        if let Some(remote_ip) = remote_ip {
+            form.push(("remoteip", remote_ip));
+        }
+        let outcome: ChallengeOutcome = self
+            .http_client
+            .post(&self.verify_url)
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if !outcome.success {
+            tracing::info!(error_codes = ?outcome.error_codes, "The challenge was not passed");
+        }
+        Ok(outcome.success)
+    }
+}
+
+pub struct BotProtection {
+    pub form_tokens: FormTokenSigner,
+    pub challenge: Option<ChallengeVerifier>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChallengeVerifier, FormTokenError, FormTokenSigner};
+    use chrono::{Duration, Utc};
+    use claims::{assert_err, assert_ok, assert_ok_eq};
+    use secrecy::Secret;
+    use wiremock::{
+        matchers::{body_string_contains, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn signer() -> FormTokenSigner {
+        FormTokenSigner::new(
+            Secret::new("a-very-secret-key".into()),
+            std::time::Duration::from_secs(3),
+            std::time::Duration::from_secs(3600),
+        )
+    }
+
+    #[test]
+    fn a_token_older_than_the_minimum_fill_time_is_accepted() {
+        let token = signer().issue_at(Utc::now() - Duration::seconds(10));
+        assert_ok!(signer().verify(&token));
+    }
+
+    #[test]
+    fn a_token_submitted_too_quickly_is_rejected() {
+        let token = signer().issue();
+        assert_eq!(signer().verify(&token), Err(FormTokenError::TooFast));
+    }
+
+    #[test]
+    fn an_expired_token_is_rejected() {
+        let token = signer().issue_at(Utc::now() - Duration::hours(2));
+        assert_eq!(signer().verify(&token), Err(FormTokenError::Expired));
+    }
+
+    #[test]
+    fn a_token_with_a_tampered_timestamp_is_rejected() {
+        let token = signer().issue_at(Utc::now() - Duration::seconds(10));
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = format!(
+            "{}.{}",
+            (Utc::now() - Duration::seconds(20)).timestamp(),
+            signature
+        );
+        assert_eq!(signer().verify(&forged), Err(FormTokenError::Invalid));
+    }
+
+    #[test]
+    fn a_token_signed_with_another_secret_is_rejected() {
+        let other = FormTokenSigner::new(
+            Secret::new("another-key".into()),
+            std::time::Duration::from_secs(3),
+            std::time::Duration::from_secs(3600),
+        );
+        let token = other.issue_at(Utc::now() - Duration::seconds(10));
+        assert_eq!(signer().verify(&token), Err(FormTokenError::Invalid));
+    }
+
+    #[test]
+    fn a_token_with_a_tampered_nonce_is_rejected() {
+        let token = signer().issue_at(Utc::now() - Duration::seconds(10));
+        let (timestamp, rest) = token.split_once('.').unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+        let forged = format!("{}.{}.{}", timestamp, "a".repeat(22), signature);
+        assert_eq!(signer().verify(&forged), Err(FormTokenError::Invalid));
+    }
+
+    #[test]
+    fn every_token_gets_its_own_nonce() {
+        let issued_at = Utc::now() - Duration::seconds(10);
+        assert_ne!(signer().issue_at(issued_at), signer().issue_at(issued_at));
+    }
+
+    #[test]
+    fn garbage_tokens_are_rejected() {
+        for token in ["", "123", "abc.def", "123.!!!", "123.abc.!!!"] {
+            assert_eq!(signer().verify(token), Err(FormTokenError::Invalid));
+        }
+    }
+
+    #[tokio::test]
+    async fn challenge_verification_forwards_the_secret_and_response() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let verifier = ChallengeVerifier::new(
+            mock_server.uri(),
+            Secret::new("challenge-secret".into()),
+            std::time::Duration::from_millis(200),
+        );
+        Mock::given(method("POST"))
+            .and(body_string_contains("secret=challenge-secret"))
+            .and(body_string_contains("response=user-token"))
+            .and(body_string_contains("remoteip=127.0.0.1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "success": true
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = verifier.verify("user-token", Some("127.0.0.1")).await;
+
+        // Assert
+        assert_ok_eq!(outcome, true);
+    }
+
+    #[tokio::test]
+    async fn a_failed_challenge_is_reported() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let verifier = ChallengeVerifier::new(
+            mock_server.uri(),
+            Secret::new("challenge-secret".into()),
+            std::time::Duration::from_millis(200),
+        );
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "success": false,
+                "error-codes": ["invalid-input-response"]
+            })))
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = verifier.verify("user-token", None).await;
+
+        // Assert
+        assert_ok_eq!(outcome, false);
+    }
+
+    #[tokio::test]
+    async fn challenge_verification_fails_if_the_provider_errors() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let verifier = ChallengeVerifier::new(
+            mock_server.uri(),
+            Secret::new("challenge-secret".into()),
+            std::time::Duration::from_millis(200),
+        );
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = verifier.verify("user-token", None).await;
+
+        // Assert
+        assert_err!(outcome);
+    }
+}
@@ -4,14 +4,28 @@ use sqlx::{
     postgres::{PgConnectOptions, PgSslMode},
     ConnectOptions,
 };
+use std::collections::HashMap;
+use std::net::IpAddr;
 
+use crate::bot_protection::{ChallengeVerifier, FormTokenSigner};
 use crate::domain::SubscriberEmail;
+use crate::email_client::{
+    CircuitBreaker, EmailBackend, EmailKind, MessageStreams, SendThrottle, Sender, SenderIdentities,
+};
 
 #[derive(serde::Deserialize, Clone)]
 pub struct Settings {
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
     pub email_client: EmailClientSettings,
+    pub signup_policy: SignupPolicySettings,
+    pub bot_protection: BotProtectionSettings,
+    pub rate_limit: RateLimitSettings,
+    pub newsletter_scheduler: NewsletterSchedulerSettings,
+    pub email_outbox: EmailOutboxSettings,
+    pub email_templates: EmailTemplateSettings,
+    pub postmark_webhook: PostmarkWebhookSettings,
+    pub archive: ArchiveSettings,
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -32,16 +46,183 @@ pub struct ApplicationSettings {
     pub port: u16,
     pub host: String,
     pub base_url: String,
+    // Signs the unsubscribe links embedded in every newsletter
+    pub hmac_secret: Secret<String>,
 }
 
 #[derive(serde::Deserialize, Clone)]
 pub struct EmailClientSettings {
     pub base_url: String,
     pub sender_email: String,
+    // Display name shown next to `sender_email`
+    pub sender_name: Option<String>,
+    // Replies go to `sender_email` when missing
+    pub reply_to_email: Option<String>,
+    pub authorization_token: Secret<String>,
+    pub timeout_milliseconds: u64,
+    // Whether the provider accepts recipients with a non-ASCII local part (RFC 6531)
+    pub supports_smtputf8: bool,
+    // Client-side limits, kept below the provider's own so that it never has to refuse us
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub messages_per_second: f64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_in_flight: usize,
+    // Longest we hold back for a `Retry-After`, however far off the provider asks for
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_retry_after_seconds: u64,
+    pub circuit_breaker: CircuitBreakerSettings,
+    // Takes over when `base_url` fails with a transport error or a 5xx response
+    pub secondary: Option<EmailBackendSettings>,
+    pub message_streams: MessageStreamSettings,
+    // Further identities to send from, by name
+    #[serde(default)]
+    pub sender_identities: HashMap<String, SenderIdentitySettings>,
+    // The identity each kind of email comes from, `sender_email` when missing
+    #[serde(default)]
+    pub senders_by_kind: HashMap<EmailKind, String>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct EmailBackendSettings {
+    pub base_url: String,
     pub authorization_token: Secret<String>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SenderIdentitySettings {
+    pub email: String,
+    pub name: Option<String>,
+    pub reply_to_email: Option<String>,
+    // Falls back to `email_client.message_streams`
+    pub message_streams: Option<MessageStreamSettings>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct MessageStreamSettings {
+    // IDs of the Postmark message streams for one-to-one and bulk emails
+    pub transactional: String,
+    pub broadcast: String,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct CircuitBreakerSettings {
+    // Consecutive transport errors or 5xx responses before we stop calling the provider
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub failure_threshold: u32,
+    // How long to fail fast before letting a probe request through
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub open_seconds: u64,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SignupPolicySettings {
+    // Each file holds one entry per line, `#` starts a comment
+    pub blocked_domains_path: String,
+    pub allowed_domains_path: String,
+    pub role_accounts_path: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub reload_interval_seconds: u64,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct BotProtectionSettings {
+    // Key used to sign the timestamp embedded in the subscription form
+    pub form_token_secret: Secret<String>,
+    // Submissions faster than this are assumed to come from bots
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub min_fill_seconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_form_age_seconds: u64,
+    // Optional hCaptcha/Turnstile-style challenge, disabled when missing
+    #[serde(default)]
+    pub challenge: Option<ChallengeSettings>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct ChallengeSettings {
+    pub verify_url: String,
+    pub secret: Secret<String>,
     pub timeout_milliseconds: u64,
 }
 
+#[derive(serde::Deserialize, Clone)]
+pub struct RateLimitSettings {
+    pub store: RateLimitStoreKind,
+    // How often the store deletes the buckets that have refilled completely
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub cleanup_interval_seconds: u64,
+    // Proxies allowed to report the client address through `X-Forwarded-For`
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    pub subscriptions: RouteLimitSettings,
+    pub subscriptions_confirm: RouteLimitSettings,
+    pub subscriptions_form_token: RouteLimitSettings,
+    pub newsletter: RouteLimitSettings,
+    // Applied to each submitted address on top of the per-IP limit, disabled when missing
+    #[serde(default)]
+    pub subscriptions_per_email: Option<RouteLimitSettings>,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitStoreKind {
+    InMemory,
+    Postgres,
+}
+
+// A token bucket holding up to `burst` requests, refilled at `requests_per_minute`.
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct RouteLimitSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub burst: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub requests_per_minute: u32,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct NewsletterSchedulerSettings {
+    // How long the scheduler waits before looking for due issues again
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub poll_interval_milliseconds: u64,
+    // Issues in 'sending' whose delivery has shown no progress for this long are taken over
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub lease_seconds: u64,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct EmailOutboxSettings {
+    // How long the relay waits before looking for due emails again
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub poll_interval_milliseconds: u64,
+    // Transient failures are retried until an email has been attempted this many times
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_attempts: i32,
+    // Emails left in 'sending' for this long are claimed again
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub lease_seconds: u64,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct EmailTemplateSettings {
+    // Templates stored in the `email_templates` table take precedence over these files
+    pub directory: String,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct ArchiveSettings {
+    // Heading of the public archive and title of its feeds
+    pub title: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub feed_length: u32,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct PostmarkWebhookSettings {
+    // The 'Basic' credentials configured on the webhook in Postmark
+    pub username: String,
+    pub password: Secret<String>,
+}
+
 impl DatabaseSettings {
     pub fn without_db(&self) -> PgConnectOptions {
         let ssl_mode = if self.require_ssl {
@@ -67,13 +248,154 @@ impl DatabaseSettings {
 }
 
 impl EmailClientSettings {
-    pub fn sender(&self) -> Result<SubscriberEmail, String> {
-        SubscriberEmail::parse(self.sender_email.clone())
+    /// Parses every sender identity, so that a typo fails at startup rather than when sending.
+    pub fn senders(&self) -> Result<SenderIdentities, String> {
+        let default = sender(
+            &self.sender_email,
+            self.sender_name.as_ref(),
+            self.reply_to_email.as_ref(),
+            &self.message_streams,
+        )?;
+        let named = self
+            .sender_identities
+            .iter()
+            .map(|(name, identity)| {
+                let sender = sender(
+                    &identity.email,
+                    identity.name.as_ref(),
+                    identity.reply_to_email.as_ref(),
+                    identity
+                        .message_streams
+                        .as_ref()
+                        .unwrap_or(&self.message_streams),
+                )
+                .map_err(|e| format!("Invalid sender identity {}: {}", name, e))?;
+                Ok((name.clone(), sender))
+            })
+            .collect::<Result<_, String>>()?;
+        SenderIdentities::new(default, named, self.senders_by_kind.clone())
     }
 
     pub fn timeout(&self) -> std::time::Duration {
         std::time::Duration::from_millis(self.timeout_milliseconds)
     }
+
+    pub fn throttle(&self) -> Result<SendThrottle, String> {
+        // The rate is turned into the interval between two requests
+        let valid_rate = self.messages_per_second > 0.0
+            && std::time::Duration::try_from_secs_f64(1.0 / self.messages_per_second).is_ok();
+        if !valid_rate {
+            return Err(format!(
+                "The sending rate must be a positive number of messages per second, got {}.",
+                self.messages_per_second
+            ));
+        }
+        if self.max_in_flight == 0 {
+            return Err("At least one request must be allowed in flight.".into());
+        }
+        Ok(SendThrottle::new(
+            self.messages_per_second,
+            self.max_in_flight,
+            std::time::Duration::from_secs(self.max_retry_after_seconds),
+        ))
+    }
+
+    pub fn circuit_breaker(&self) -> CircuitBreaker {
+        CircuitBreaker::new(
+            self.circuit_breaker.failure_threshold,
+            std::time::Duration::from_secs(self.circuit_breaker.open_seconds),
+        )
+    }
+
+    pub fn primary_backend(&self) -> EmailBackend {
+        EmailBackend {
+            name: "primary".into(),
+            base_url: self.base_url.clone(),
+            authorization_token: self.authorization_token.clone(),
+            circuit_breaker: self.circuit_breaker(),
+        }
+    }
+
+    pub fn secondary_backend(&self) -> Option<EmailBackend> {
+        self.secondary.as_ref().map(|secondary| EmailBackend {
+            name: "secondary".into(),
+            base_url: secondary.base_url.clone(),
+            authorization_token: secondary.authorization_token.clone(),
+            circuit_breaker: self.circuit_breaker(),
+        })
+    }
+}
+
+fn sender(
+    email: &str,
+    name: Option<&String>,
+    reply_to_email: Option<&String>,
+    message_streams: &MessageStreamSettings,
+) -> Result<Sender, String> {
+    Ok(Sender {
+        email: SubscriberEmail::parse(email.to_owned())?,
+        name: name.cloned(),
+        reply_to: reply_to_email
+            .cloned()
+            .map(SubscriberEmail::parse)
+            .transpose()?,
+        message_streams: MessageStreams {
+            transactional: message_streams.transactional.clone(),
+            broadcast: message_streams.broadcast.clone(),
+        },
+    })
+}
+
+impl SignupPolicySettings {
+    pub fn reload_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.reload_interval_seconds)
+    }
+}
+
+impl NewsletterSchedulerSettings {
+    pub fn poll_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.poll_interval_milliseconds)
+    }
+
+    pub fn lease(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.lease_seconds)
+    }
+}
+
+impl RateLimitSettings {
+    pub fn cleanup_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.cleanup_interval_seconds)
+    }
+}
+
+impl EmailOutboxSettings {
+    pub fn poll_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.poll_interval_milliseconds)
+    }
+
+    pub fn lease(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.lease_seconds)
+    }
+}
+
+impl BotProtectionSettings {
+    pub fn form_token_signer(&self) -> FormTokenSigner {
+        FormTokenSigner::new(
+            self.form_token_secret.clone(),
+            std::time::Duration::from_secs(self.min_fill_seconds),
+            std::time::Duration::from_secs(self.max_form_age_seconds),
+        )
+    }
+
+    pub fn challenge_verifier(&self) -> Option<ChallengeVerifier> {
+        self.challenge.as_ref().map(|challenge| {
+            ChallengeVerifier::new(
+                challenge.verify_url.clone(),
+                challenge.secret.clone(),
+                std::time::Duration::from_millis(challenge.timeout_milliseconds),
+            )
+        })
+    }
 }
 
 pub fn get_configuration() -> Result<Settings, config::ConfigError> {
@@ -133,3 +455,21 @@ impl TryFrom<String> for Environment {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::get_configuration;
+
+    #[test]
+    fn invalid_throttle_settings_are_rejected() {
+        let mut settings = get_configuration().unwrap().email_client;
+        assert!(settings.throttle().is_ok());
+        for rate in [0.0, -1.0, f64::NAN, 1e-320] {
+            settings.messages_per_second = rate;
+            assert!(settings.throttle().is_err(), "{} was accepted", rate);
+        }
+        settings.messages_per_second = 10.0;
+        settings.max_in_flight = 0;
+        assert!(settings.throttle().is_err());
+    }
+}
@@ -1,34 +1,107 @@
-use validator::validate_email;
+// RFC 5321 limits, which RFC 6531 keeps in octets for internationalised addresses
+const MAX_LOCAL_PART_LENGTH: usize = 64;
+const MAX_ADDRESS_LENGTH: usize = 254;
 
 #[derive(Debug)]
-pub struct SubscriberEmail(String);
+pub struct SubscriberEmail {
+    // The address exactly as it was submitted, kept for display purposes
+    original: String,
+    // The original local part with the domain lowercased and converted to its ASCII (punycode) form
+    address: String,
+}
 
 impl SubscriberEmail {
+    /// Parses both plain ASCII addresses and internationalised (EAI) addresses as
+    /// described in RFC 6531: the local part may contain any non-ASCII UTF-8 character
+    /// and the domain may be an IDN, which is converted to its A-label form.
     pub fn parse(s: String) -> Result<SubscriberEmail, String> {
-        if validate_email(&s) {
-            Ok(Self(s))
-        } else {
-            Err(format!("{} is not a valid subscriber email.", s))
+        let invalid = || format!("{} is not a valid subscriber email.", s);
+        let (local_part, domain) = s.rsplit_once('@').ok_or_else(invalid)?;
+        if !is_valid_local_part(local_part) {
+            return Err(invalid());
+        }
+        // UTS #46 processing lowercases the domain and maps IDNs to their A-label form
+        let domain = idna::Config::default()
+            .use_std3_ascii_rules(true)
+            .verify_dns_length(true)
+            .check_hyphens(true)
+            .to_ascii(domain)
+            .map_err(|_| format!("{} does not have a valid domain.", s))?;
+        if domain.is_empty() || domain.ends_with('.') {
+            return Err(invalid());
+        }
+        let address = format!("{}@{}", local_part, domain);
+        if address.len() > MAX_ADDRESS_LENGTH {
+            return Err(invalid());
         }
+        Ok(Self {
+            original: s,
+            address,
+        })
+    }
+
+    /// The address as the subscriber typed it.
+    pub fn original(&self) -> &str {
+        &self.original
+    }
+
+    /// Case-insensitive form of the address, used to detect duplicate subscriptions.
+    pub fn normalised(&self) -> String {
+        self.address.to_lowercase()
+    }
+
+    /// The local part, before the last `@`.
+    pub fn local_part(&self) -> &str {
+        self.address
+            .rsplit_once('@')
+            .map_or("", |(local_part, _)| local_part)
+    }
+
+    /// The domain in its lowercase A-label form.
+    pub fn domain(&self) -> &str {
+        self.address
+            .rsplit_once('@')
+            .map_or("", |(_, domain)| domain)
+    }
+
+    /// Domains are always converted to A-labels, so only a non-ASCII local part
+    /// requires the SMTPUTF8 extension to deliver to this address.
+    pub fn requires_smtputf8(&self) -> bool {
+        !self.address.is_ascii()
     }
 }
 
+// A `dot-atom` local part; quoted local parts are not accepted.
+fn is_valid_local_part(local_part: &str) -> bool {
+    local_part.len() <= MAX_LOCAL_PART_LENGTH
+        && local_part
+            .split('.')
+            .all(|atom| !atom.is_empty() && atom.chars().all(is_atext))
+}
+
+// RFC 5322 `atext`, extended by RFC 6531 to any non-ASCII UTF-8 character.
+fn is_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric()
+        || "!#$%&'*+-/=?^_`{|}~".contains(c)
+        || (!c.is_ascii() && !c.is_control() && !c.is_whitespace())
+}
+
 impl AsRef<str> for SubscriberEmail {
     fn as_ref(&self) -> &str {
-        &self.0
+        &self.address
     }
 }
 
 impl std::fmt::Display for SubscriberEmail {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.0.fmt(f)
+        self.original.fmt(f)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::SubscriberEmail;
-    use claims::assert_err;
+    use claims::{assert_err, assert_ok};
     // use fake::faker::internet::en::SafeEmail;
     // use fake::Fake;
 
@@ -50,6 +123,84 @@ mod tests {
         assert_err!(SubscriberEmail::parse(email));
     }
 
+    #[test]
+    fn the_domain_is_lowercased_but_the_local_part_is_kept() {
+        let email = SubscriberEmail::parse("Alice@Example.COM".to_string()).unwrap();
+        assert_eq!(email.as_ref(), "Alice@example.com");
+        assert_eq!(email.original(), "Alice@Example.COM");
+    }
+
+    #[test]
+    fn addresses_differing_only_in_case_share_a_normalised_form() {
+        let first = SubscriberEmail::parse("Alice@Example.com".to_string()).unwrap();
+        let second = SubscriberEmail::parse("alice@example.com".to_string()).unwrap();
+        assert_eq!(first.normalised(), second.normalised());
+    }
+
+    #[test]
+    fn internationalised_domains_are_converted_to_punycode() {
+        let email = SubscriberEmail::parse("ursula@Bücher.example".to_string()).unwrap();
+        assert_eq!(email.as_ref(), "ursula@xn--bcher-kva.example");
+        assert_eq!(email.normalised(), "ursula@xn--bcher-kva.example");
+    }
+
+    #[test]
+    fn internationalised_local_parts_are_accepted() {
+        let email = SubscriberEmail::parse("用户@例子.测试".to_string()).unwrap();
+        assert_eq!(email.as_ref(), "用户@xn--fsqu00a.xn--0zwm56d");
+        assert!(email.requires_smtputf8());
+    }
+
+    #[test]
+    fn ascii_addresses_do_not_require_smtputf8() {
+        let email = SubscriberEmail::parse("ursula@Bücher.example".to_string()).unwrap();
+        assert!(!email.requires_smtputf8());
+    }
+
+    #[test]
+    fn malformed_dot_atoms_are_rejected() {
+        for email in [
+            "ursula..le@domain.com",
+            ".ursula@domain.com",
+            "ursula.@domain.com",
+        ] {
+            assert_err!(SubscriberEmail::parse(email.to_string()));
+        }
+    }
+
+    #[test]
+    fn whitespace_and_control_characters_are_rejected() {
+        for email in [
+            "ursula le@domain.com",
+            "ursula\u{7}@domain.com",
+            "ursula@dom ain.com",
+        ] {
+            assert_err!(SubscriberEmail::parse(email.to_string()));
+        }
+    }
+
+    #[test]
+    fn invalid_domains_are_rejected() {
+        for email in [
+            "ursula@",
+            "ursula@-domain.com",
+            "ursula@domain..com",
+            "ursula@domain.com.",
+            "ursula@dom_ain.com",
+        ] {
+            assert_err!(SubscriberEmail::parse(email.to_string()));
+        }
+    }
+
+    #[test]
+    fn a_local_part_longer_than_64_octets_is_rejected() {
+        // 22 three-byte characters are 66 octets
+        let email = format!("{}@domain.com", "用".repeat(22));
+        assert_err!(SubscriberEmail::parse(email));
+        let email = format!("{}@domain.com", "用".repeat(21));
+        assert_ok!(SubscriberEmail::parse(email));
+    }
+
     // #[derive(Debug, Clone)]
     // struct ValidEmailFixture(pub String);
 
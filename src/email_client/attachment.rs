@@ -0,0 +1,80 @@
+use base64::Engine;
+
+// Content ids end up in the HTML and in URLs, keep them short
+const MAX_CONTENT_ID_LENGTH: usize = 100;
+
+/// A file sent along with an email.
+///
+/// Every backend speaks Postmark's JSON API, so attachments only map to its `Attachments`
+/// field. There is no MIME encoding (multipart/mixed, multipart/related) until a backend
+/// that takes raw messages comes along.
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub name: String,
+    pub content_type: String,
+    pub content: Vec<u8>,
+    // Set for inline images, which the HTML body references as `cid:<content_id>`
+    pub content_id: Option<String>,
+}
+
+impl Attachment {
+    /// Encode the content once, for an attachment sent to many recipients.
+    pub fn encode(&self) -> EncodedAttachment {
+        EncodedAttachment {
+            name: self.name.clone(),
+            content_type: self.content_type.clone(),
+            content: base64::engine::general_purpose::STANDARD.encode(&self.content),
+            content_id: self.content_id.clone(),
+        }
+    }
+}
+
+/// An attachment as Postmark takes it, with its content in base64.
+#[derive(Debug, Clone)]
+pub struct EncodedAttachment {
+    pub name: String,
+    pub content_type: String,
+    pub content: String,
+    pub content_id: Option<String>,
+}
+
+/// How many bytes `len` bytes take once encoded in padded base64.
+pub fn base64_len(len: usize) -> usize {
+    len.div_ceil(3) * 4
+}
+
+/// Whether `content_id` can be used as is in a `cid:` URL and in the archive's URLs.
+pub fn is_valid_content_id(content_id: &str) -> bool {
+    !content_id.is_empty()
+        && content_id.len() <= MAX_CONTENT_ID_LENGTH
+        && !content_id.starts_with('.')
+        && content_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base64_len, is_valid_content_id, Attachment};
+
+    #[test]
+    fn the_encoded_length_matches_the_encoding() {
+        for len in 0..10 {
+            let attachment = Attachment {
+                name: "report.pdf".into(),
+                content_type: "application/pdf".into(),
+                content: vec![0; len],
+                content_id: None,
+            };
+            assert_eq!(attachment.encode().content.len(), base64_len(len));
+        }
+    }
+
+    #[test]
+    fn content_ids_are_restricted_to_url_safe_characters() {
+        assert!(is_valid_content_id("logo-2.png"));
+        for content_id in ["", "logo one.png", "../logo.png", "logo>.png", "lögo.png"] {
+            assert!(!is_valid_content_id(content_id), "{}", content_id);
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use super::{CircuitBreaker, CircuitSnapshot};
+use secrecy::Secret;
+
+/// A provider exposing Postmark's email API.
+pub struct EmailBackend {
+    // Recorded with every delivery, e.g. 'primary'
+    pub name: String,
+    pub base_url: String,
+    pub authorization_token: Secret<String>,
+    // Each backend fails on its own, so it gets its own circuit breaker
+    pub circuit_breaker: CircuitBreaker,
+}
+
+/// Point-in-time view of a backend, exposed in the metrics.
+#[derive(Debug, serde::Serialize)]
+pub struct BackendSnapshot {
+    pub name: String,
+    pub circuit_breaker: CircuitSnapshot,
+}
+
+impl EmailBackend {
+    pub fn snapshot(&self) -> BackendSnapshot {
+        BackendSnapshot {
+            name: self.name.clone(),
+            circuit_breaker: self.circuit_breaker.snapshot(),
+        }
+    }
+}
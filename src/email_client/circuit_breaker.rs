@@ -0,0 +1,208 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Stops calling the email provider after `failure_threshold` consecutive failures,
+/// so that callers fail fast instead of each waiting for the request to time out.
+/// Once `open_duration` has elapsed a single probe request is let through: its outcome
+/// closes the circuit again or keeps it open for another `open_duration`.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_duration: Duration,
+    state: Mutex<CircuitState>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CircuitState {
+    Closed { consecutive_failures: u32 },
+    Open { until: Instant },
+    // A probe request is in flight, everybody else keeps failing fast
+    HalfOpen,
+}
+
+/// Allows a single request through the circuit breaker. Its outcome is reported with
+/// `record_success` or `record_failure`; a permit dropped without either, e.g. because the
+/// request was cancelled, counts as a failure if it was the probe, so that the circuit
+/// cannot stay half-open forever.
+#[must_use]
+pub struct CircuitPermit<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+    recorded: bool,
+}
+
+impl CircuitPermit<'_> {
+    pub fn record_success(mut self) {
+        self.recorded = true;
+        self.breaker.record_success();
+    }
+
+    pub fn record_failure(mut self) {
+        self.recorded = true;
+        self.breaker.record_failure();
+    }
+}
+
+impl Drop for CircuitPermit<'_> {
+    fn drop(&mut self) {
+        if self.probe && !self.recorded {
+            self.breaker.record_failure();
+        }
+    }
+}
+
+/// Point-in-time view of the circuit breaker, exposed in the metrics.
+#[derive(Debug, serde::Serialize)]
+pub struct CircuitSnapshot {
+    // One of 'closed', 'open' or 'half_open'
+    pub state: &'static str,
+    pub consecutive_failures: u32,
+    pub failure_threshold: u32,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_duration: Duration) -> Self {
+        assert!(
+            failure_threshold > 0,
+            "The failure threshold must be positive"
+        );
+        Self {
+            failure_threshold,
+            open_duration,
+            state: Mutex::new(CircuitState::Closed {
+                consecutive_failures: 0,
+            }),
+        }
+    }
+
+    /// A permit to send a request to the provider, if one may be sent right now.
+    pub fn allow_request(&self) -> Option<CircuitPermit<'_>> {
+        let mut state = self.state.lock().unwrap();
+        let probe = match *state {
+            CircuitState::Closed { .. } => false,
+            CircuitState::Open { until } if Instant::now() >= until => {
+                *state = CircuitState::HalfOpen;
+                true
+            }
+            CircuitState::Open { .. } | CircuitState::HalfOpen => return None,
+        };
+        Some(CircuitPermit {
+            breaker: self,
+            probe,
+            recorded: false,
+        })
+    }
+
+    fn record_success(&self) {
+        *self.state.lock().unwrap() = CircuitState::Closed {
+            consecutive_failures: 0,
+        };
+    }
+
+    fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        *state = match *state {
+            CircuitState::Closed {
+                consecutive_failures,
+            } if consecutive_failures + 1 < self.failure_threshold => CircuitState::Closed {
+                consecutive_failures: consecutive_failures + 1,
+            },
+            // The threshold was reached, or the probe failed
+            CircuitState::Closed { .. } | CircuitState::HalfOpen => {
+                tracing::warn!("The email provider keeps failing, opening the circuit");
+                CircuitState::Open {
+                    until: Instant::now() + self.open_duration,
+                }
+            }
+            // A request allowed before the circuit opened failed in the meantime
+            open @ CircuitState::Open { .. } => open,
+        };
+    }
+
+    pub fn snapshot(&self) -> CircuitSnapshot {
+        let state = *self.state.lock().unwrap();
+        let (name, consecutive_failures) = match state {
+            CircuitState::Closed {
+                consecutive_failures,
+            } => ("closed", consecutive_failures),
+            CircuitState::Open { .. } => ("open", self.failure_threshold),
+            CircuitState::HalfOpen => ("half_open", self.failure_threshold),
+        };
+        CircuitSnapshot {
+            state: name,
+            consecutive_failures,
+            failure_threshold: self.failure_threshold,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CircuitBreaker;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn the_circuit_opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(30));
+        for _ in 0..2 {
+            breaker.allow_request().unwrap().record_failure();
+        }
+        assert_eq!(breaker.snapshot().state, "closed");
+        breaker.allow_request().unwrap().record_failure();
+        assert_eq!(breaker.snapshot().state, "open");
+        assert!(breaker.allow_request().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_success_resets_the_failure_count() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+        breaker.allow_request().unwrap().record_failure();
+        breaker.allow_request().unwrap().record_success();
+        breaker.allow_request().unwrap().record_failure();
+        assert_eq!(breaker.snapshot().state, "closed");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_single_probe_is_let_through_once_the_circuit_cools_down() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(30));
+        breaker.allow_request().unwrap().record_failure();
+        tokio::time::advance(Duration::from_secs(30)).await;
+
+        let probe = breaker.allow_request().unwrap();
+        assert!(breaker.allow_request().is_none());
+        assert_eq!(breaker.snapshot().state, "half_open");
+        probe.record_success();
+        assert!(breaker.allow_request().is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_probe_keeps_the_circuit_open() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(30));
+        breaker.allow_request().unwrap().record_failure();
+        tokio::time::advance(Duration::from_secs(30)).await;
+
+        breaker.allow_request().unwrap().record_failure();
+        assert!(breaker.allow_request().is_none());
+        tokio::time::advance(Duration::from_secs(29)).await;
+        assert!(breaker.allow_request().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_dropped_probe_counts_as_a_failure() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(30));
+        breaker.allow_request().unwrap().record_failure();
+        tokio::time::advance(Duration::from_secs(30)).await;
+
+        drop(breaker.allow_request().unwrap());
+        assert_eq!(breaker.snapshot().state, "open");
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(breaker.allow_request().is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropped_requests_do_not_count_while_the_circuit_is_closed() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(30));
+        drop(breaker.allow_request().unwrap());
+        assert_eq!(breaker.snapshot().state, "closed");
+    }
+}
@@ -0,0 +1,1259 @@
+mod attachment;
+mod backend;
+mod circuit_breaker;
+mod sender;
+mod throttle;
+
+pub use attachment::{base64_len, is_valid_content_id, Attachment, EncodedAttachment};
+pub use backend::{BackendSnapshot, EmailBackend};
+pub use circuit_breaker::{CircuitBreaker, CircuitPermit, CircuitSnapshot};
+pub use sender::{EmailKind, MessageStream, MessageStreams, Sender, SenderIdentities};
+pub use throttle::{SendThrottle, ThrottleSnapshot};
+
+use crate::domain::SubscriberEmail;
+use reqwest::{header, Client, StatusCode};
+use secrecy::ExposeSecret;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+// Postmark is asked again after each pause, up to this many requests in total
+const MAX_RATE_LIMITED_ATTEMPTS: u32 = 3;
+
+#[derive(thiserror::Error, Debug)]
+pub enum SendEmailError {
+    #[error("{0} requires SMTPUTF8, which the email backend does not support.")]
+    Smtputf8Unsupported(String),
+    /// Postmark understood the request and refused it.
+    #[error("Postmark rejected the email with error code {}: {message}", code.as_u16())]
+    Rejected {
+        code: PostmarkErrorCode,
+        message: String,
+    },
+    #[error("Postmark is rate limiting our requests.")]
+    RateLimited { retry_after: Option<Duration> },
+    #[error("Postmark answered with an unexpected status: {0}.")]
+    UnexpectedStatus(StatusCode),
+    #[error("There is no sender identity named {0}.")]
+    UnknownSender(String),
+    /// Postmark failed too many times in a row, so it was not called at all.
+    #[error("Postmark is unavailable, not sending until it recovers.")]
+    CircuitOpen,
+    /// Postmark could not be reached, or its answer could not be read.
+    #[error(transparent)]
+    RequestError(#[from] reqwest::Error),
+}
+
+impl SendEmailError {
+    /// Whether sending the same email again later may succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            SendEmailError::RateLimited { .. }
+            | SendEmailError::RequestError(_)
+            | SendEmailError::CircuitOpen => true,
+            SendEmailError::UnexpectedStatus(status) => status.is_server_error(),
+            SendEmailError::Smtputf8Unsupported(_)
+            | SendEmailError::Rejected { .. }
+            | SendEmailError::UnknownSender(_) => false,
+        }
+    }
+
+    // Whether Postmark itself is in trouble, as opposed to refusing this one email
+    fn is_provider_failure(&self) -> bool {
+        match self {
+            SendEmailError::RequestError(_) => true,
+            SendEmailError::UnexpectedStatus(status) => status.is_server_error(),
+            _ => false,
+        }
+    }
+
+    // Whether the email certainly was not sent, so that another backend can send it without
+    // risking a duplicate. A timeout may strike after Postmark accepted the request.
+    fn is_safe_to_fail_over(&self) -> bool {
+        match self {
+            SendEmailError::CircuitOpen => true,
+            SendEmailError::RequestError(error) => error.is_connect(),
+            SendEmailError::UnexpectedStatus(status) => status.is_server_error(),
+            _ => false,
+        }
+    }
+
+    /// Whether the recipient should no longer be emailed.
+    pub fn suppresses_recipient(&self) -> bool {
+        matches!(
+            self,
+            SendEmailError::Rejected {
+                code: PostmarkErrorCode::InactiveRecipient,
+                ..
+            }
+        )
+    }
+
+    /// Whether every other email would fail the same way, whoever the recipient.
+    pub fn affects_every_recipient(&self) -> bool {
+        match self {
+            SendEmailError::Rejected { code, .. } => code.affects_every_recipient(),
+            SendEmailError::UnknownSender(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// The API error codes Postmark returns along with a 4xx status,
+/// see https://postmarkapp.com/developer/api/overview#error-codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PostmarkErrorCode {
+    InvalidApiToken,
+    InvalidEmailRequest,
+    SenderSignatureNotFound,
+    SenderSignatureNotConfirmed,
+    InvalidJson,
+    IncompatibleJson,
+    NotAllowedToSend,
+    InactiveRecipient,
+    InvalidMessageStream,
+    Other(u16),
+}
+
+impl PostmarkErrorCode {
+    pub fn from_u16(code: u16) -> Self {
+        match code {
+            10 => Self::InvalidApiToken,
+            300 => Self::InvalidEmailRequest,
+            400 => Self::SenderSignatureNotFound,
+            401 => Self::SenderSignatureNotConfirmed,
+            402 => Self::InvalidJson,
+            403 => Self::IncompatibleJson,
+            405 => Self::NotAllowedToSend,
+            406 => Self::InactiveRecipient,
+            1235 => Self::InvalidMessageStream,
+            code => Self::Other(code),
+        }
+    }
+
+    pub fn as_u16(&self) -> u16 {
+        match self {
+            Self::InvalidApiToken => 10,
+            Self::InvalidEmailRequest => 300,
+            Self::SenderSignatureNotFound => 400,
+            Self::SenderSignatureNotConfirmed => 401,
+            Self::InvalidJson => 402,
+            Self::IncompatibleJson => 403,
+            Self::NotAllowedToSend => 405,
+            Self::InactiveRecipient => 406,
+            Self::InvalidMessageStream => 1235,
+            Self::Other(code) => *code,
+        }
+    }
+
+    // Problems with our account or configuration rather than with one email
+    fn affects_every_recipient(&self) -> bool {
+        matches!(
+            self,
+            Self::InvalidApiToken
+                | Self::SenderSignatureNotFound
+                | Self::SenderSignatureNotConfirmed
+                | Self::NotAllowedToSend
+                | Self::InvalidMessageStream
+        )
+    }
+}
+
+/// What Postmark reports back once it accepted an email.
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct SentEmail {
+    #[serde(rename = "MessageID")]
+    pub message_id: Option<String>,
+    // Name of the backend that accepted the email
+    #[serde(skip)]
+    pub provider: String,
+}
+
+/// Everything about an email besides its recipient and content.
+#[derive(Default)]
+pub struct MessageOptions<'a> {
+    // Picks the sender identity configured for this kind of email
+    pub kind: Option<EmailKind>,
+    // A sender identity by name, which takes precedence over `kind`
+    pub sender: Option<&'a str>,
+    pub stream: MessageStream,
+    // Overrides the sender's `reply_to`
+    pub reply_to: Option<&'a SubscriberEmail>,
+    pub cc: &'a [SubscriberEmail],
+    pub bcc: &'a [SubscriberEmail],
+    // Groups emails in Postmark's statistics, e.g. 'newsletter'
+    pub tag: Option<&'a str>,
+    // Returned by Postmark in its webhooks
+    pub metadata: &'a [(&'a str, String)],
+    pub attachments: &'a [EncodedAttachment],
+    // Announced in the `List-Unsubscribe` header, and POSTed to by one-click
+    // unsubscription (RFC 8058)
+    pub unsubscribe_url: Option<&'a str>,
+}
+
+pub struct EmailClient {
+    http_client: Client,
+    // Tried in order: the primary, then the secondary if there is one
+    backends: Vec<EmailBackend>,
+    senders: SenderIdentities,
+    // Whether the backends can deliver to addresses with a non-ASCII local part
+    supports_smtputf8: bool,
+    throttle: SendThrottle,
+}
+
+impl EmailClient {
+    pub fn new(
+        primary: EmailBackend,
+        secondary: Option<EmailBackend>,
+        senders: SenderIdentities,
+        timeout: std::time::Duration,
+        supports_smtputf8: bool,
+        throttle: SendThrottle,
+    ) -> Self {
+        let http_client = Client::builder().timeout(timeout).build().unwrap();
+        Self {
+            http_client,
+            backends: std::iter::once(primary).chain(secondary).collect(),
+            senders,
+            supports_smtputf8,
+            throttle,
+        }
+    }
+
+    pub fn senders(&self) -> &SenderIdentities {
+        &self.senders
+    }
+
+    /// How many emails may be sent concurrently.
+    pub fn max_in_flight(&self) -> usize {
+        self.throttle.max_in_flight()
+    }
+
+    pub fn throttle_snapshot(&self) -> ThrottleSnapshot {
+        self.throttle.snapshot()
+    }
+
+    pub fn backend_snapshots(&self) -> Vec<BackendSnapshot> {
+        self.backends.iter().map(EmailBackend::snapshot).collect()
+    }
+
+    /// Signals whether this backend is able to deliver to `recipient` at all.
+    pub fn can_deliver_to(&self, recipient: &SubscriberEmail) -> bool {
+        self.supports_smtputf8 || !recipient.requires_smtputf8()
+    }
+
+    pub async fn send_email(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+    ) -> Result<SentEmail, SendEmailError> {
+        let options = MessageOptions::default();
+        self.send_email_with_options(recipient, subject, html_content, text_content, &options)
+            .await
+    }
+
+    pub async fn send_email_with_options(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        options: &MessageOptions<'_>,
+    ) -> Result<SentEmail, SendEmailError> {
+        let copied = options.cc.iter().chain(options.bcc);
+        if let Some(address) = std::iter::once(recipient)
+            .chain(copied)
+            .find(|address| !self.can_deliver_to(address))
+        {
+            return Err(SendEmailError::Smtputf8Unsupported(address.to_string()));
+        }
+        let sender = self.senders.resolve(options.sender, options.kind)?;
+        let request_body = SendEmailRequest {
+            from: sender.mailbox(),
+            to: recipient.as_ref(),
+            cc: address_list(options.cc),
+            bcc: address_list(options.bcc),
+            reply_to: options
+                .reply_to
+                .or(sender.reply_to.as_ref())
+                .map(AsRef::as_ref),
+            subject,
+            html_body: html_content,
+            text_body: text_content,
+            tag: options.tag,
+            metadata: options
+                .metadata
+                .iter()
+                .map(|(key, value)| (*key, value.as_str()))
+                .collect(),
+            message_stream: sender.message_streams.id(options.stream),
+            attachments: options
+                .attachments
+                .iter()
+                .map(PostmarkAttachment::from)
+                .collect(),
+            headers: list_unsubscribe_headers(options.unsubscribe_url),
+        };
+        // Only failures of the provider itself are worth trying elsewhere:
+        // the next backend would refuse the email the same way.
+        let mut last_error = SendEmailError::CircuitOpen;
+        for backend in &self.backends {
+            match self.send_through(backend, &request_body).await {
+                Err(error) if error.is_safe_to_fail_over() => {
+                    tracing::warn!(
+                        error.cause_chain = ?error,
+                        backend = %backend.name,
+                        "Failed to send an email, trying the next backend if there is one",
+                    );
+                    last_error = error;
+                }
+                outcome => {
+                    return outcome.map(|sent| SentEmail {
+                        provider: backend.name.clone(),
+                        ..sent
+                    })
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn send_through(
+        &self,
+        backend: &EmailBackend,
+        request_body: &SendEmailRequest<'_>,
+    ) -> Result<SentEmail, SendEmailError> {
+        let mut attempt = 1;
+        loop {
+            let Some(circuit) = backend.circuit_breaker.allow_request() else {
+                return Err(SendEmailError::CircuitOpen);
+            };
+            let permit = self.throttle.acquire().await;
+            let outcome = self.post_email(backend, request_body).await;
+            drop(permit);
+            match &outcome {
+                Err(error) if error.is_provider_failure() => circuit.record_failure(),
+                _ => circuit.record_success(),
+            }
+            match outcome {
+                Err(SendEmailError::RateLimited { retry_after }) => {
+                    // Every other request waits as well, as Postmark limits the account as a whole
+                    self.throttle.pause(retry_after);
+                    tracing::warn!(attempt, "Postmark is rate limiting our requests");
+                    if attempt == MAX_RATE_LIMITED_ATTEMPTS {
+                        return Err(SendEmailError::RateLimited { retry_after });
+                    }
+                    attempt += 1;
+                }
+                outcome => return outcome,
+            }
+        }
+    }
+
+    async fn post_email(
+        &self,
+        backend: &EmailBackend,
+        request_body: &SendEmailRequest<'_>,
+    ) -> Result<SentEmail, SendEmailError> {
+        let url = format!("{}/email", backend.base_url);
+        // The .json method serializes our `SendEmailRequest` into JSON, but also sets the "Content-Type" header to "application/json"
+        let response = self
+            .http_client
+            .post(url)
+            .header(
+                "X-Postmark-Server-Token",
+                backend.authorization_token.expose_secret(),
+            )
+            // .header("Accept", "application/json")
+            .json(request_body)
+            .send()
+            .await?;
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = response
+                .headers()
+                .get(header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| parse_retry_after(value, SystemTime::now()))
+                .map(|retry_after| retry_after.min(self.throttle.max_pause()));
+            return Err(SendEmailError::RateLimited { retry_after });
+        }
+        let body = response.bytes().await?;
+        if status.is_success() {
+            // A missing or unexpected body does not undo the delivery
+            return Ok(serde_json::from_slice(&body).unwrap_or_default());
+        }
+        match serde_json::from_slice::<ApiError>(&body) {
+            Ok(error) if status.is_client_error() => Err(SendEmailError::Rejected {
+                code: PostmarkErrorCode::from_u16(error.error_code),
+                message: error.message,
+            }),
+            _ => Err(SendEmailError::UnexpectedStatus(status)),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+// JSON requests require pascal case
+#[serde(rename_all = "PascalCase")]
+struct SendEmailRequest<'a> {
+    from: String,
+    to: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cc: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bcc: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to: Option<&'a str>,
+    subject: &'a str,
+    html_body: &'a str,
+    text_body: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<&'a str>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: BTreeMap<&'a str, &'a str>,
+    message_stream: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<PostmarkAttachment<'a>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<PostmarkHeader>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct PostmarkHeader {
+    name: &'static str,
+    value: String,
+}
+
+fn list_unsubscribe_headers(unsubscribe_url: Option<&str>) -> Vec<PostmarkHeader> {
+    let Some(unsubscribe_url) = unsubscribe_url else {
+        return Vec::new();
+    };
+    vec![
+        PostmarkHeader {
+            name: "List-Unsubscribe",
+            value: format!("<{}>", unsubscribe_url),
+        },
+        PostmarkHeader {
+            name: "List-Unsubscribe-Post",
+            value: "List-Unsubscribe=One-Click".into(),
+        },
+    ]
+}
+
+// Postmark takes several recipients as a single comma-separated field
+fn address_list(addresses: &[SubscriberEmail]) -> Option<String> {
+    (!addresses.is_empty()).then(|| {
+        addresses
+            .iter()
+            .map(AsRef::as_ref)
+            .collect::<Vec<_>>()
+            .join(", ")
+    })
+}
+
+// `Retry-After` is either a number of seconds or an HTTP date, which may already be past
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value.trim()).ok()?;
+    Some(date.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct PostmarkAttachment<'a> {
+    name: &'a str,
+    // Base64-encoded
+    content: &'a str,
+    content_type: &'a str,
+    // Postmark expects the `cid:` prefix used in the HTML
+    #[serde(rename = "ContentID", skip_serializing_if = "Option::is_none")]
+    content_id: Option<String>,
+}
+
+impl<'a> From<&'a EncodedAttachment> for PostmarkAttachment<'a> {
+    fn from(attachment: &'a EncodedAttachment) -> Self {
+        Self {
+            name: &attachment.name,
+            content: &attachment.content,
+            content_type: &attachment.content_type,
+            content_id: attachment
+                .content_id
+                .as_ref()
+                .map(|content_id| format!("cid:{}", content_id)),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ApiError {
+    error_code: u16,
+    message: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse_retry_after, Attachment, CircuitBreaker, EmailBackend, EmailClient, MessageOptions,
+        MessageStream, MessageStreams, PostmarkErrorCode, SendEmailError, SendThrottle, Sender,
+        SenderIdentities,
+    };
+    use crate::domain::SubscriberEmail;
+    use claims::{assert_err, assert_ok};
+    use fake::{
+        faker::{
+            internet::en::SafeEmail,
+            lorem::en::{Paragraph, Sentence},
+        },
+        Fake, Faker,
+    };
+    use secrecy::Secret;
+    use wiremock::{
+        matchers::{any, body_partial_json, header_exists, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    struct SendEmailBodyMatcher;
+
+    // Anything that implements `Match` can be used as a matcher in `given` and `and`. Thus, we create our own matcher just for checking that the request body is valid JSON.
+    impl wiremock::Match for SendEmailBodyMatcher {
+        fn matches(&self, request: &wiremock::Request) -> bool {
+            // Try to parse the body as JSON value
+            let result: Result<serde_json::Value, _> = serde_json::from_slice(&request.body);
+            if let Ok(body) = result {
+                // Check that call the mandatory fields are populated without inspecting their value
+                body.get("From").is_some()
+                    && body.get("To").is_some()
+                    && body.get("Subject").is_some()
+                    && body.get("HtmlBody").is_some()
+                    && body.get("TextBody").is_some()
+            } else {
+                false
+            }
+        }
+    }
+
+    // Generate a random email subject
+    fn subject() -> String {
+        Sentence(1..2).fake()
+    }
+
+    // Generate a random email content
+    fn content() -> String {
+        Paragraph(1..10).fake()
+    }
+
+    // Generate a random subscriber email
+    fn email() -> SubscriberEmail {
+        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
+    }
+
+    fn sender() -> Sender {
+        Sender {
+            email: email(),
+            name: None,
+            reply_to: None,
+            message_streams: MessageStreams {
+                transactional: "outbound".into(),
+                broadcast: "broadcast".into(),
+            },
+        }
+    }
+
+    // Get a test instance of `EmailClient`
+    fn email_client(base_url: String) -> EmailClient {
+        email_client_sending_as(base_url, sender())
+    }
+
+    fn email_client_sending_as(base_url: String, sender: Sender) -> EmailClient {
+        EmailClient::new(
+            backend("primary", base_url),
+            None,
+            SenderIdentities::only(sender),
+            std::time::Duration::from_millis(200),
+            false,
+            SendThrottle::new(100.0, 2, std::time::Duration::from_secs(60)),
+        )
+    }
+
+    fn email_client_with_failover(primary_url: String, secondary_url: String) -> EmailClient {
+        EmailClient::new(
+            backend("primary", primary_url),
+            Some(backend("secondary", secondary_url)),
+            SenderIdentities::only(sender()),
+            std::time::Duration::from_millis(200),
+            false,
+            SendThrottle::new(100.0, 2, std::time::Duration::from_secs(60)),
+        )
+    }
+
+    fn backend(name: &str, base_url: String) -> EmailBackend {
+        EmailBackend {
+            name: name.into(),
+            base_url,
+            authorization_token: Secret::new(Faker.fake()),
+            circuit_breaker: CircuitBreaker::new(3, std::time::Duration::from_secs(30)),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_email_sends_the_expected_request() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .and(path("/email"))
+            .and(method("POST"))
+            .and(SendEmailBodyMatcher)
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let _ = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+    }
+
+    #[tokio::test]
+    async fn send_email_with_attachments_sends_them_base64_encoded() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let attachments = [
+            Attachment {
+                name: "report.pdf".into(),
+                content_type: "application/pdf".into(),
+                content: b"%PDF-1.7".to_vec(),
+                content_id: None,
+            },
+            Attachment {
+                name: "logo.png".into(),
+                content_type: "image/png".into(),
+                content: b"PNG".to_vec(),
+                content_id: Some("logo".into()),
+            },
+        ]
+        .map(|attachment| attachment.encode());
+
+        Mock::given(body_partial_json(serde_json::json!({
+            "Attachments": [
+                {
+                    "Name": "report.pdf",
+                    "Content": "JVBERi0xLjc=",
+                    "ContentType": "application/pdf"
+                },
+                {
+                    "Name": "logo.png",
+                    "Content": "UE5H",
+                    "ContentType": "image/png",
+                    "ContentID": "cid:logo"
+                }
+            ]
+        })))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+        // Act
+        let outcome = email_client
+            .send_email_with_options(
+                &email(),
+                &subject(),
+                &content(),
+                &content(),
+                &MessageOptions {
+                    attachments: &attachments,
+                    ..MessageOptions::default()
+                },
+            )
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_with_options_sets_the_postmark_fields() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let sender = Sender {
+            email: SubscriberEmail::parse("newsletter@example.com".into()).unwrap(),
+            name: Some(r#"Zero "2" Prod"#.into()),
+            reply_to: Some(SubscriberEmail::parse("editor@example.com".into()).unwrap()),
+            ..sender()
+        };
+        let email_client = email_client_sending_as(mock_server.uri(), sender);
+        let cc = [
+            SubscriberEmail::parse("ursula@example.com".into()).unwrap(),
+            SubscriberEmail::parse("le.guin@example.com".into()).unwrap(),
+        ];
+        let bcc = [SubscriberEmail::parse("archive@example.com".into()).unwrap()];
+
+        Mock::given(body_partial_json(serde_json::json!({
+            "From": r#""Zero \"2\" Prod" <newsletter@example.com>"#,
+            "Cc": "ursula@example.com, le.guin@example.com",
+            "Bcc": "archive@example.com",
+            "ReplyTo": "editor@example.com",
+            "Tag": "newsletter",
+            "Metadata": { "newsletter_issue_id": "42" },
+            "MessageStream": "broadcast"
+        })))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+        // Act
+        let outcome = email_client
+            .send_email_with_options(
+                &email(),
+                &subject(),
+                &content(),
+                &content(),
+                &MessageOptions {
+                    stream: MessageStream::Broadcast,
+                    cc: &cc,
+                    bcc: &bcc,
+                    tag: Some("newsletter"),
+                    metadata: &[("newsletter_issue_id", "42".into())],
+                    ..MessageOptions::default()
+                },
+            )
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn emails_go_through_the_transactional_stream_by_default() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let reply_to = email();
+
+        Mock::given(body_partial_json(serde_json::json!({
+            "MessageStream": "outbound",
+            "ReplyTo": reply_to.as_ref()
+        })))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+        // Act
+        let outcome = email_client
+            .send_email_with_options(
+                &email(),
+                &subject(),
+                &content(),
+                &content(),
+                &MessageOptions {
+                    reply_to: Some(&reply_to),
+                    ..MessageOptions::default()
+                },
+            )
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_succeeds_if_the_server_returns_a_200() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_returns_the_message_id_assigned_by_the_server() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "To": "receiver@example.com",
+                "SubmittedAt": "2023-11-05T09:00:00.0000000-05:00",
+                "MessageID": "b7bc2f4a-e38e-4336-af7d-e6c392c2f817",
+                "ErrorCode": 0,
+                "Message": "OK"
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_eq!(
+            outcome.unwrap().message_id.as_deref(),
+            Some("b7bc2f4a-e38e-4336-af7d-e6c392c2f817")
+        );
+    }
+
+    #[tokio::test]
+    async fn send_email_surfaces_the_postmark_error_code() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422).set_body_json(serde_json::json!({
+                "ErrorCode": 406,
+                "Message": "You tried to send to a recipient that has been marked as inactive."
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        let error = outcome.unwrap_err();
+        assert!(matches!(
+            error,
+            SendEmailError::Rejected {
+                code: PostmarkErrorCode::InactiveRecipient,
+                ..
+            }
+        ));
+        assert!(error.suppresses_recipient());
+        assert!(!error.is_transient());
+    }
+
+    #[tokio::test]
+    async fn send_email_reports_when_it_is_still_rate_limited_after_retrying() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        let error = outcome.unwrap_err();
+        assert!(matches!(
+            error,
+            SendEmailError::RateLimited {
+                retry_after: Some(retry_after)
+            } if retry_after.is_zero()
+        ));
+        assert!(error.is_transient());
+        assert_eq!(email_client.throttle_snapshot().rate_limited_responses, 3);
+    }
+
+    #[tokio::test]
+    async fn send_email_caps_how_long_it_waits_for_retry_after() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = EmailClient::new(
+            backend("primary", mock_server.uri()),
+            None,
+            SenderIdentities::only(sender()),
+            std::time::Duration::from_millis(200),
+            false,
+            SendThrottle::new(100.0, 2, std::time::Duration::from_millis(10)),
+        );
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "86400"))
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert!(matches!(
+            outcome,
+            Err(SendEmailError::RateLimited {
+                retry_after: Some(retry_after)
+            }) if retry_after == std::time::Duration::from_millis(10)
+        ));
+    }
+
+    // The requests need real time to go through, so the runtime is kept busy while they do:
+    // a paused clock only moves forward on its own once every task is waiting.
+    #[tokio::test(start_paused = true)]
+    async fn send_email_waits_for_retry_after_before_trying_again() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "30"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let (recipient, subject, content) = (email(), subject(), content());
+        let send = email_client.send_email(&recipient, &subject, &content, &content);
+        tokio::pin!(send);
+        while email_client.throttle_snapshot().rate_limited_responses == 0 {
+            tokio::select! {
+                outcome = &mut send => panic!("Sent despite the 429: {:?}", outcome),
+                _ = tokio::task::yield_now() => {}
+            }
+        }
+        tokio::time::advance(std::time::Duration::from_secs(29)).await;
+        for _ in 0..100 {
+            tokio::select! {
+                outcome = &mut send => panic!("Sent before Retry-After: {:?}", outcome),
+                _ = tokio::task::yield_now() => {}
+            }
+        }
+        let requests_within_retry_after = mock_server.received_requests().await.unwrap().len();
+        tokio::time::advance(std::time::Duration::from_secs(2)).await;
+        let outcome = loop {
+            tokio::select! {
+                outcome = &mut send => break outcome,
+                _ = tokio::task::yield_now() => {}
+            }
+        };
+
+        // Assert
+        assert_eq!(requests_within_retry_after, 1);
+        assert_ok!(outcome);
+    }
+
+    #[test]
+    fn retry_after_is_read_as_seconds_or_as_an_http_date() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        // Sun, 06 Nov 1994 08:49:37 GMT
+        let now = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:50:07 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Sunday, 06-Nov-94 08:50:07 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn error_codes_round_trip() {
+        for code in [10, 300, 400, 401, 402, 403, 405, 406, 1235, 701] {
+            assert_eq!(PostmarkErrorCode::from_u16(code).as_u16(), code);
+        }
+        assert_eq!(
+            PostmarkErrorCode::from_u16(701),
+            PostmarkErrorCode::Other(701)
+        );
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_the_server_returns_a_500() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_fast_once_the_server_keeps_failing() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        for _ in 0..3 {
+            let _ = email_client
+                .send_email(&email(), &subject(), &content(), &content())
+                .await;
+        }
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert!(matches!(outcome, Err(SendEmailError::CircuitOpen)));
+        assert_eq!(
+            email_client.backend_snapshots()[0].circuit_breaker.state,
+            "open"
+        );
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_over_to_the_secondary_backend_on_a_5xx() {
+        // Arrange
+        let primary = MockServer::start().await;
+        let secondary = MockServer::start().await;
+        let email_client = email_client_with_failover(primary.uri(), secondary.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&primary)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&secondary)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_eq!(outcome.unwrap().provider, "secondary");
+    }
+
+    #[tokio::test]
+    async fn send_email_does_not_fail_over_when_the_email_is_rejected() {
+        // Arrange
+        let primary = MockServer::start().await;
+        let secondary = MockServer::start().await;
+        let email_client = email_client_with_failover(primary.uri(), secondary.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422))
+            .expect(1)
+            .mount(&primary)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&secondary)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_does_not_fail_over_when_the_primary_backend_times_out() {
+        // Arrange
+        let primary = MockServer::start().await;
+        let secondary = MockServer::start().await;
+        let email_client = email_client_with_failover(primary.uri(), secondary.uri());
+
+        // The primary backend may well have sent the email by the time we give up on it
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(1)))
+            .expect(1)
+            .mount(&primary)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&secondary)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_over_when_the_primary_backend_cannot_be_reached() {
+        // Arrange
+        // Nothing listens on a port once its listener is gone
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let primary_url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let secondary = MockServer::start().await;
+        let email_client = email_client_with_failover(primary_url, secondary.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&secondary)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_eq!(outcome.unwrap().provider, "secondary");
+    }
+
+    #[tokio::test]
+    async fn send_email_records_the_primary_backend_when_it_succeeds() {
+        // Arrange
+        let primary = MockServer::start().await;
+        let secondary = MockServer::start().await;
+        let email_client = email_client_with_failover(primary.uri(), secondary.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&primary)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_eq!(outcome.unwrap().provider, "primary");
+    }
+
+    #[tokio::test]
+    async fn send_email_times_out_if_the_server_takes_too_long() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        let response = ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(180));
+        Mock::given(any())
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_refuses_recipients_requiring_smtputf8_when_unsupported() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let recipient = SubscriberEmail::parse("用户@例子.测试".into()).unwrap();
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(&recipient, &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert!(matches!(
+            outcome,
+            Err(SendEmailError::Smtputf8Unsupported(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn send_email_delivers_to_recipients_requiring_smtputf8_when_supported() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = EmailClient::new(
+            backend("primary", mock_server.uri()),
+            None,
+            SenderIdentities::only(sender()),
+            std::time::Duration::from_millis(200),
+            true,
+            SendThrottle::new(100.0, 2, std::time::Duration::from_secs(60)),
+        );
+        let recipient = SubscriberEmail::parse("用户@例子.测试".into()).unwrap();
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(&recipient, &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+    }
+}
@@ -0,0 +1,221 @@
+use super::SendEmailError;
+use crate::domain::SubscriberEmail;
+use std::collections::HashMap;
+
+/// Who an email comes from.
+#[derive(Debug)]
+pub struct Sender {
+    pub email: SubscriberEmail,
+    // Shown by email clients instead of the bare address
+    pub name: Option<String>,
+    // Where replies go when the email does not ask for another address
+    pub reply_to: Option<SubscriberEmail>,
+    pub message_streams: MessageStreams,
+}
+
+impl Sender {
+    /// The `From` header value, e.g. `"Zero To Production" <newsletter@example.com>`.
+    pub fn mailbox(&self) -> String {
+        match &self.name {
+            Some(name) => {
+                // Quoted, so that commas or angle brackets in the name cannot be taken for addresses
+                let name = name.replace('\\', "\\\\").replace('"', "\\\"");
+                format!("\"{}\" <{}>", name, self.email.as_ref())
+            }
+            None => self.email.as_ref().to_owned(),
+        }
+    }
+}
+
+/// Postmark keeps bulk and one-to-one emails apart, each in its own message stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum MessageStream {
+    // Emails a recipient triggered, e.g. a confirmation email
+    #[default]
+    Transactional,
+    // Emails sent to many recipients at once, i.e. newsletter issues
+    Broadcast,
+}
+
+/// The IDs of the message streams set up in Postmark.
+#[derive(Debug, Clone)]
+pub struct MessageStreams {
+    pub transactional: String,
+    pub broadcast: String,
+}
+
+impl MessageStreams {
+    pub(super) fn id(&self, stream: MessageStream) -> &str {
+        match stream {
+            MessageStream::Transactional => &self.transactional,
+            MessageStream::Broadcast => &self.broadcast,
+        }
+    }
+}
+
+/// The kinds of emails we send, each of which can come from its own sender identity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailKind {
+    Confirmation,
+    Newsletter,
+    TestSend,
+}
+
+impl EmailKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EmailKind::Confirmation => "confirmation",
+            EmailKind::Newsletter => "newsletter",
+            EmailKind::TestSend => "test_send",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "confirmation" => Some(EmailKind::Confirmation),
+            "newsletter" => Some(EmailKind::Newsletter),
+            "test_send" => Some(EmailKind::TestSend),
+            _ => None,
+        }
+    }
+}
+
+/// Every address we send from, by name.
+/// An email comes from the identity it names, else from the one configured for its kind,
+/// else from the default identity.
+#[derive(Debug)]
+pub struct SenderIdentities {
+    default: Sender,
+    named: HashMap<String, Sender>,
+    by_kind: HashMap<EmailKind, String>,
+}
+
+impl SenderIdentities {
+    /// Fails when a kind refers to an identity that does not exist.
+    pub fn new(
+        default: Sender,
+        named: HashMap<String, Sender>,
+        by_kind: HashMap<EmailKind, String>,
+    ) -> Result<Self, String> {
+        for (kind, name) in &by_kind {
+            if !named.contains_key(name) {
+                return Err(format!(
+                    "{} emails are meant to come from {}, which is not a sender identity.",
+                    kind.as_str(),
+                    name
+                ));
+            }
+        }
+        Ok(Self {
+            default,
+            named,
+            by_kind,
+        })
+    }
+
+    /// A single identity, used for every email.
+    pub fn only(default: Sender) -> Self {
+        Self {
+            default,
+            named: HashMap::new(),
+            by_kind: HashMap::new(),
+        }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.named.contains_key(name)
+    }
+
+    pub fn resolve(
+        &self,
+        name: Option<&str>,
+        kind: Option<EmailKind>,
+    ) -> Result<&Sender, SendEmailError> {
+        let name =
+            name.or_else(|| kind.and_then(|kind| self.by_kind.get(&kind).map(|n| n.as_str())));
+        match name {
+            Some(name) => self
+                .named
+                .get(name)
+                .ok_or_else(|| SendEmailError::UnknownSender(name.to_owned())),
+            None => Ok(&self.default),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EmailKind, MessageStreams, Sender, SenderIdentities};
+    use crate::domain::SubscriberEmail;
+    use std::collections::HashMap;
+
+    fn sender(email: &str) -> Sender {
+        Sender {
+            email: SubscriberEmail::parse(email.into()).unwrap(),
+            name: None,
+            reply_to: None,
+            message_streams: MessageStreams {
+                transactional: "outbound".into(),
+                broadcast: "broadcast".into(),
+            },
+        }
+    }
+
+    fn identities() -> SenderIdentities {
+        SenderIdentities::new(
+            sender("hello@example.com"),
+            HashMap::from([
+                ("news".into(), sender("news@example.com")),
+                ("books".into(), sender("books@example.com")),
+            ]),
+            HashMap::from([(EmailKind::Newsletter, "news".into())]),
+        )
+        .unwrap()
+    }
+
+    fn resolved(name: Option<&str>, kind: Option<EmailKind>) -> String {
+        let identities = identities();
+        let sender = identities.resolve(name, kind).unwrap();
+        sender.email.as_ref().to_owned()
+    }
+
+    #[test]
+    fn a_named_identity_takes_precedence_over_the_kind() {
+        assert_eq!(
+            resolved(Some("books"), Some(EmailKind::Newsletter)),
+            "books@example.com"
+        );
+        assert_eq!(
+            resolved(None, Some(EmailKind::Newsletter)),
+            "news@example.com"
+        );
+        assert_eq!(
+            resolved(None, Some(EmailKind::Confirmation)),
+            "hello@example.com"
+        );
+    }
+
+    #[test]
+    fn unknown_identities_are_an_error() {
+        assert!(identities().resolve(Some("podcast"), None).is_err());
+        let outcome = SenderIdentities::new(
+            sender("hello@example.com"),
+            HashMap::new(),
+            HashMap::from([(EmailKind::Confirmation, "news".into())]),
+        );
+        assert!(outcome.is_err());
+    }
+
+    #[test]
+    fn senders_without_a_name_use_the_bare_address() {
+        assert_eq!(sender("hello@example.com").mailbox(), "hello@example.com");
+    }
+
+    #[test]
+    fn display_names_are_quoted() {
+        let mut sender = sender("hello@example.com");
+        sender.name = Some(r#"Zero "2" Prod"#.into());
+        assert_eq!(sender.mailbox(), r#""Zero \"2\" Prod" <hello@example.com>"#);
+    }
+}
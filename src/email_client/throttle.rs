@@ -0,0 +1,204 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::Instant;
+
+// How long to hold back when Postmark answers 429 without a `Retry-After` header
+const DEFAULT_PAUSE: Duration = Duration::from_secs(5);
+
+/// Keeps every request to the email provider, from any task, under a sending rate
+/// and a maximum number of requests in flight.
+pub struct SendThrottle {
+    // Time between the start of two consecutive requests
+    interval: Duration,
+    max_in_flight: usize,
+    // Upper bound on a single pause, whatever `Retry-After` says
+    max_pause: Duration,
+    in_flight: Semaphore,
+    state: Mutex<ThrottleState>,
+}
+
+struct ThrottleState {
+    // Earliest time the next request may start
+    next_slot: Instant,
+    paused_until: Option<Instant>,
+    // How far the pauses pushed the schedule back so far. Requests waiting for their slot
+    // start that much later than planned, in the same order.
+    total_pause: Duration,
+    rate_limited_responses: u64,
+}
+
+/// Point-in-time view of the throttle, exposed in the metrics.
+#[derive(Debug, serde::Serialize)]
+pub struct ThrottleSnapshot {
+    pub messages_per_second: f64,
+    pub max_in_flight: usize,
+    pub in_flight: usize,
+    // Milliseconds left before requests resume after a 429, zero when not paused
+    pub paused_for_milliseconds: u128,
+    pub rate_limited_responses: u64,
+}
+
+impl SendThrottle {
+    /// `EmailClientSettings::throttle` checks the settings before they get here.
+    pub fn new(messages_per_second: f64, max_in_flight: usize, max_pause: Duration) -> Self {
+        assert!(
+            messages_per_second > 0.0,
+            "The sending rate must be positive"
+        );
+        assert!(
+            max_in_flight > 0,
+            "At least one request must be allowed in flight"
+        );
+        Self {
+            interval: Duration::from_secs_f64(1.0 / messages_per_second),
+            max_in_flight,
+            max_pause,
+            in_flight: Semaphore::new(max_in_flight),
+            state: Mutex::new(ThrottleState {
+                next_slot: Instant::now(),
+                paused_until: None,
+                total_pause: Duration::ZERO,
+                rate_limited_responses: 0,
+            }),
+        }
+    }
+
+    pub fn max_in_flight(&self) -> usize {
+        self.max_in_flight
+    }
+
+    pub fn max_pause(&self) -> Duration {
+        self.max_pause
+    }
+
+    /// Wait for both a free slot and our turn in the sending schedule.
+    /// The request may go out as long as the returned permit is held.
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        let permit = self
+            .in_flight
+            .acquire()
+            .await
+            .expect("The throttle semaphore is never closed");
+        let (slot, pause_at_reservation) = {
+            let mut state = self.state.lock().unwrap();
+            let slot = state.next_slot.max(Instant::now());
+            state.next_slot = slot + self.interval;
+            (slot, state.total_pause)
+        };
+        loop {
+            // A 429 received while we wait pushes our turn back
+            let start = slot + (self.state.lock().unwrap().total_pause - pause_at_reservation);
+            if start <= Instant::now() {
+                return permit;
+            }
+            tokio::time::sleep_until(start).await;
+        }
+    }
+
+    /// Hold back every request, including those already waiting for their turn.
+    pub fn pause(&self, retry_after: Option<Duration>) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let until = now + retry_after.unwrap_or(DEFAULT_PAUSE).min(self.max_pause);
+        // Only the part that goes beyond the current pause, if any, delays the schedule
+        let paused_until = state.paused_until.unwrap_or(now).max(now);
+        if until > paused_until {
+            let delay = until - paused_until;
+            state.total_pause += delay;
+            state.next_slot = state.next_slot.max(now) + delay;
+            state.paused_until = Some(until);
+        }
+        state.rate_limited_responses += 1;
+    }
+
+    pub fn snapshot(&self) -> ThrottleSnapshot {
+        let state = self.state.lock().unwrap();
+        let paused_for = state.paused_until.map_or(Duration::ZERO, |paused_until| {
+            paused_until.saturating_duration_since(Instant::now())
+        });
+        ThrottleSnapshot {
+            messages_per_second: 1.0 / self.interval.as_secs_f64(),
+            max_in_flight: self.max_in_flight,
+            in_flight: self.max_in_flight - self.in_flight.available_permits(),
+            paused_for_milliseconds: paused_for.as_millis(),
+            rate_limited_responses: state.rate_limited_responses,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SendThrottle;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_are_spread_according_to_the_rate() {
+        let throttle = SendThrottle::new(10.0, 5, Duration::from_secs(60));
+        let start = Instant::now();
+        for _ in 0..5 {
+            drop(throttle.acquire().await);
+        }
+        // The first request goes out right away, the next four wait 100ms each
+        assert_eq!(start.elapsed(), Duration::from_millis(400));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_in_flight_are_capped() {
+        let throttle = SendThrottle::new(1000.0, 2, Duration::from_secs(60));
+        let _first = throttle.acquire().await;
+        let _second = throttle.acquire().await;
+        let third = tokio::time::timeout(Duration::from_secs(1), throttle.acquire()).await;
+        assert!(third.is_err());
+        assert_eq!(throttle.snapshot().in_flight, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_pause_holds_back_every_request() {
+        let throttle = SendThrottle::new(1000.0, 2, Duration::from_secs(60));
+        throttle.pause(Some(Duration::from_secs(30)));
+        let start = Instant::now();
+        drop(throttle.acquire().await);
+        assert!(start.elapsed() >= Duration::from_secs(30));
+        assert_eq!(throttle.snapshot().rate_limited_responses, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_pause_never_exceeds_the_maximum() {
+        let throttle = SendThrottle::new(1000.0, 2, Duration::from_secs(60));
+        throttle.pause(Some(Duration::from_secs(86_400)));
+        let start = Instant::now();
+        drop(throttle.acquire().await);
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_requests_keep_their_turn_through_a_pause() {
+        let throttle = Arc::new(SendThrottle::new(10.0, 5, Duration::from_secs(60)));
+        let start = Instant::now();
+        drop(throttle.acquire().await);
+        let waiting: Vec<_> = (0..3)
+            .map(|_| {
+                let throttle = throttle.clone();
+                tokio::spawn(async move {
+                    drop(throttle.acquire().await);
+                    start.elapsed()
+                })
+            })
+            .collect();
+        // Let them take the next three slots, 100ms apart
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        throttle.pause(Some(Duration::from_millis(100)));
+
+        let mut started = Vec::new();
+        for request in waiting {
+            started.push(request.await.unwrap());
+        }
+        assert_eq!(started, [200, 300, 400].map(Duration::from_millis).to_vec());
+        drop(throttle.acquire().await);
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+    }
+}
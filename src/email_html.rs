@@ -0,0 +1,190 @@
+use ammonia::{Builder, UrlRelative};
+use anyhow::Context;
+use lol_html::{text, ElementContentHandlers, RewriteStrSettings, Selector};
+use std::borrow::Cow;
+
+// Gmail hides anything past the first 102KB of HTML behind a "View entire message" link,
+// including our unsubscribe link
+const GMAIL_CLIPPING_THRESHOLD_BYTES: usize = 102 * 1024;
+
+/// Get HTML written by an editor ready for email clients: `<style>` rules are inlined
+/// into `style` attributes, tags and attributes outside of the allowlist are dropped
+/// and relative URLs are resolved against `base_url`.
+pub fn prepare(html: &str, base_url: &str) -> Result<String, anyhow::Error> {
+    let inlined = inline_styles(html)?;
+    let base_url = url::Url::parse(&format!("{}/", base_url.trim_end_matches('/')))
+        .context("The application base url is not a valid URL.")?;
+    Ok(sanitiser(base_url).clean(&inlined).to_string())
+}
+
+/// Returns a warning when Gmail will clip an email with this HTML body.
+pub fn clipping_warning(html: &str) -> Option<String> {
+    (html.len() > GMAIL_CLIPPING_THRESHOLD_BYTES).then(|| {
+        format!(
+            "The HTML body is {}KB: Gmail clips anything past the first {}KB.",
+            html.len() / 1024,
+            GMAIL_CLIPPING_THRESHOLD_BYTES / 1024
+        )
+    })
+}
+
+fn sanitiser(base_url: url::Url) -> Builder<'static> {
+    let mut builder = Builder::default();
+    builder
+        // Presentational attributes are still the most reliable way to lay out an email
+        .add_generic_attributes(&["style", "align", "valign", "width", "height", "bgcolor"])
+        .add_tag_attributes("table", &["border", "cellpadding", "cellspacing"])
+        // Inline images are referenced by the content ID of their attachment
+        .add_url_schemes(&["cid"])
+        .url_relative(UrlRelative::RewriteWithBase(base_url));
+    builder
+}
+
+// Many clients ignore `<style>` elements, so each rule is copied into the `style`
+// attribute of the elements it matches. Rules whose selector cannot be matched while
+// streaming (e.g. `a:hover` or sibling combinators) are dropped.
+fn inline_styles(html: &str) -> Result<String, anyhow::Error> {
+    let mut css = String::new();
+    lol_html::rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![text!("style", |chunk| {
+                css.push_str(chunk.as_str());
+                Ok(())
+            })],
+            ..RewriteStrSettings::default()
+        },
+    )
+    .context("Failed to parse the HTML content.")?;
+    if css.trim().is_empty() {
+        return Ok(html.to_string());
+    }
+
+    // Rules come sorted by ascending specificity, then source order. Walking them
+    // backwards and prepending their declarations leaves the most specific rules, and
+    // then the element's own `style`, last, where they win.
+    let stylesheet = simplecss::StyleSheet::parse(&css);
+    let mut handlers = Vec::new();
+    for rule in stylesheet.rules.iter().rev() {
+        let selector = rule.selector.to_string();
+        let Ok(parsed) = selector.parse::<Selector>() else {
+            tracing::warn!("Dropping the `{}` CSS rule, it cannot be inlined", selector);
+            continue;
+        };
+        let declarations = rule
+            .declarations
+            .iter()
+            .map(|declaration| {
+                let important = if declaration.important {
+                    " !important"
+                } else {
+                    ""
+                };
+                format!("{}: {}{}", declaration.name, declaration.value, important)
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        let handler = ElementContentHandlers::default().element(move |element| {
+            let style = match element.get_attribute("style") {
+                Some(existing) if !existing.trim().is_empty() => {
+                    format!("{}; {}", declarations, existing)
+                }
+                _ => declarations.clone(),
+            };
+            element.set_attribute("style", &style)?;
+            Ok(())
+        });
+        handlers.push((Cow::Owned(parsed), handler));
+    }
+    lol_html::rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: handlers,
+            ..RewriteStrSettings::default()
+        },
+    )
+    .context("Failed to inline the CSS rules of the HTML content.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clipping_warning, prepare};
+
+    const BASE_URL: &str = "https://example.com";
+
+    #[test]
+    fn scripts_forms_and_event_handlers_are_removed() {
+        let html = prepare(
+            r#"<p onclick="steal()">Hi</p><script>steal()</script>
+            <form action="/subscribe"><input name="email"></form>"#,
+            BASE_URL,
+        )
+        .unwrap();
+        assert!(!html.contains("onclick"));
+        assert!(!html.contains("script"));
+        assert!(!html.contains("form"));
+        assert!(!html.contains("input"));
+        assert!(html.contains("<p>Hi</p>"));
+    }
+
+    #[test]
+    fn style_rules_are_inlined_and_the_style_element_removed() {
+        let html = prepare(
+            r#"<style>p { color: red; } .note { color: blue; font-weight: bold }</style>
+            <p>Plain</p><p class="note">Note</p>"#,
+            BASE_URL,
+        )
+        .unwrap();
+        assert!(!html.contains("<style>"));
+        assert!(html.contains(r#"<p style="color: red">Plain</p>"#));
+        assert!(html.contains(r#"<p style="color: red; color: blue; font-weight: bold">Note</p>"#));
+    }
+
+    #[test]
+    fn inline_styles_win_over_style_rules() {
+        let html = prepare(
+            r#"<style>#title { color: red } h1 { color: blue }</style>
+            <h1 id="title" style="color: green">Title</h1>"#,
+            BASE_URL,
+        )
+        .unwrap();
+        assert!(html.contains(r#"style="color: blue; color: red; color: green""#));
+    }
+
+    #[test]
+    fn rules_that_cannot_be_inlined_are_dropped() {
+        let html = prepare(
+            "<style>a:hover { color: red } a { color: blue }</style><a href=\"/post\">Post</a>",
+            BASE_URL,
+        )
+        .unwrap();
+        assert!(html.contains(r#"style="color: blue""#));
+        assert!(!html.contains("red"));
+    }
+
+    #[test]
+    fn relative_urls_are_rewritten_against_the_base_url() {
+        let html = prepare(
+            r#"<a href="/posts/1">Post</a><img src="images/logo.png">
+            <a href="https://other.com/page">Other</a>"#,
+            BASE_URL,
+        )
+        .unwrap();
+        assert!(html.contains(r#"href="https://example.com/posts/1""#));
+        assert!(html.contains(r#"src="https://example.com/images/logo.png""#));
+        assert!(html.contains(r#"href="https://other.com/page""#));
+    }
+
+    #[test]
+    fn inline_image_references_are_kept() {
+        let html = prepare(r#"<img src="cid:logo.png">"#, BASE_URL).unwrap();
+        assert!(html.contains(r#"src="cid:logo.png""#));
+    }
+
+    #[test]
+    fn oversized_bodies_get_a_clipping_warning() {
+        assert!(clipping_warning("<p>Short</p>").is_none());
+        let long = format!("<p>{}</p>", "a".repeat(110 * 1024));
+        assert!(clipping_warning(&long).unwrap().contains("Gmail"));
+    }
+}
@@ -0,0 +1,134 @@
+use crate::domain::SubscriberEmail;
+use anyhow::Context;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+struct StoredSubscriber {
+    id: Uuid,
+    email: String,
+    email_normalised: String,
+}
+
+/// Bring `email_normalised` in line with `SubscriberEmail::normalised` for the subscriptions
+/// backfilled in SQL, which could only lowercase their IDN domains instead of converting them
+/// to A-labels. Subscriptions that now collapse onto the same address are merged, as the
+/// backfill did.
+/// Only addresses with non-ASCII characters can differ. Run through the `renormalise_emails`
+/// binary, once after the normalisation rules change.
+#[tracing::instrument(name = "Re-normalise subscriber emails", skip(pool))]
+pub async fn renormalise_subscriber_emails(pool: &PgPool) -> Result<(), anyhow::Error> {
+    let subscribers = sqlx::query_as!(
+        StoredSubscriber,
+        r#"
+        SELECT id, email, email_normalised
+        FROM subscriptions
+        WHERE email ~ '[^[:ascii:]]'
+        "#
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to fetch the subscribers with internationalised addresses.")?;
+    for subscriber in subscribers {
+        let email_normalised = match SubscriberEmail::parse(subscriber.email.clone()) {
+            Ok(email) => email.normalised(),
+            Err(e) => {
+                // Nothing new can collide with an address we would reject
+                tracing::warn!(subscriber_id = %subscriber.id, "Skipping an invalid address: {}", e);
+                continue;
+            }
+        };
+        if email_normalised == subscriber.email_normalised {
+            continue;
+        }
+        let mut transaction = pool
+            .begin()
+            .await
+            .context("Failed to acquire a Postgres connection from the pool.")?;
+        renormalise(&mut transaction, subscriber.id, &email_normalised)
+            .await
+            .context("Failed to re-normalise a subscriber email.")?;
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit the SQL transaction to re-normalise a subscriber email.")?;
+    }
+    Ok(())
+}
+
+async fn renormalise(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    email_normalised: &str,
+) -> Result<(), sqlx::Error> {
+    // Keep a confirmed subscription over a pending one and the oldest one otherwise
+    let ranked = sqlx::query!(
+        r#"
+        SELECT id FROM subscriptions
+        WHERE id = $1 OR email_normalised = $2
+        ORDER BY (status = 'confirmed') DESC, subscribed_at ASC, id ASC
+        FOR UPDATE
+        "#,
+        subscriber_id,
+        email_normalised
+    )
+    .fetch_all(&mut **transaction)
+    .await?;
+    let survivor_id = ranked[0].id;
+    if let Some(duplicate) = ranked.get(1) {
+        tracing::info!(%survivor_id, duplicate_id = %duplicate.id, "Merging duplicate subscriptions");
+        merge_subscriptions(transaction, duplicate.id, survivor_id).await?;
+    }
+    sqlx::query!(
+        "UPDATE subscriptions SET email_normalised = $2 WHERE id = $1",
+        survivor_id,
+        email_normalised
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+async fn merge_subscriptions(
+    transaction: &mut Transaction<'_, Postgres>,
+    duplicate_id: Uuid,
+    survivor_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    // The survivor's own record of an issue wins when both of them were sent it
+    sqlx::query!(
+        r#"
+        DELETE FROM newsletter_issue_deliveries
+        WHERE subscriber_id = $1 AND newsletter_issue_id IN (
+            SELECT newsletter_issue_id FROM newsletter_issue_deliveries WHERE subscriber_id = $2
+        )
+        "#,
+        duplicate_id,
+        survivor_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    sqlx::query!(
+        "UPDATE newsletter_issue_deliveries SET subscriber_id = $2 WHERE subscriber_id = $1",
+        duplicate_id,
+        survivor_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    sqlx::query!(
+        "UPDATE tracking_events SET subscriber_id = $2 WHERE subscriber_id = $1",
+        duplicate_id,
+        survivor_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    sqlx::query!(
+        "UPDATE subscription_tokens SET subscriber_id = $2 WHERE subscriber_id = $1",
+        duplicate_id,
+        survivor_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    sqlx::query!("DELETE FROM subscriptions WHERE id = $1", duplicate_id)
+        .execute(&mut **transaction)
+        .await?;
+    Ok(())
+}
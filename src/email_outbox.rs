@@ -0,0 +1,309 @@
+use crate::{
+    domain::SubscriberEmail,
+    email_client::{EmailClient, EmailKind, MessageOptions},
+    email_templates::RenderedEmail,
+    suppression::is_suppressed,
+};
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::{PgExecutor, PgPool};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+// Retries back off exponentially from this delay, up to `MAX_RETRY_DELAY`
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(10);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Store an email for the relay worker to send as soon as possible.
+/// Outbox emails are transactional. `kind` picks the sender identity, `tag` groups them
+/// in the provider's statistics.
+#[tracing::instrument(name = "Enqueue an email in the outbox", skip(executor, email))]
+pub async fn enqueue_email(
+    executor: impl PgExecutor<'_>,
+    recipient: &SubscriberEmail,
+    email: &RenderedEmail,
+    kind: EmailKind,
+    tag: Option<&str>,
+) -> Result<Uuid, sqlx::Error> {
+    let email_outbox_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO email_outbox (
+            email_outbox_id, recipient, subject, html_body, text_body, email_kind, tag,
+            status, next_attempt_at, created_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, 'pending', now(), now())
+        "#,
+        email_outbox_id,
+        recipient.as_ref(),
+        email.subject,
+        email.html,
+        email.text,
+        kind.as_str(),
+        tag
+    )
+    .execute(executor)
+    .await?;
+    Ok(email_outbox_id)
+}
+
+pub enum RelayOutcome {
+    EmailRelayed,
+    NothingDue,
+}
+
+/// Claim the oldest due email and try to send it. Failures are rescheduled with an
+/// exponential backoff until `max_attempts` is reached, or straight away for errors
+/// that retrying cannot fix. Emails to suppressed addresses are skipped.
+/// Emails left in 'sending' for longer than `lease` are claimed again: whoever was sending
+/// them is gone.
+pub async fn relay_next_email(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    max_attempts: i32,
+    lease: Duration,
+) -> Result<RelayOutcome, anyhow::Error> {
+    relay(pool, email_client, None, max_attempts, lease).await
+}
+
+/// Try to send a freshly queued email straight away, without waiting for the relay worker.
+/// `NothingDue` means that the worker already claimed it.
+#[tracing::instrument(name = "Relay an email from the outbox", skip(pool, email_client))]
+pub async fn relay_email(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    email_outbox_id: Uuid,
+    max_attempts: i32,
+    lease: Duration,
+) -> Result<RelayOutcome, anyhow::Error> {
+    relay(
+        pool,
+        email_client,
+        Some(email_outbox_id),
+        max_attempts,
+        lease,
+    )
+    .await
+}
+
+struct ClaimedEmail {
+    email_outbox_id: Uuid,
+    recipient: String,
+    subject: String,
+    html_body: String,
+    text_body: String,
+    email_kind: Option<String>,
+    tag: Option<String>,
+    attempts: i32,
+}
+
+enum Claim {
+    // Addresses were parsed before being queued, so this only fails if the rules changed since
+    Send(Box<ClaimedEmail>, Result<SubscriberEmail, String>),
+    Skipped,
+    NothingDue,
+}
+
+async fn relay(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    email_outbox_id: Option<Uuid>,
+    max_attempts: i32,
+    lease: Duration,
+) -> Result<RelayOutcome, anyhow::Error> {
+    let (row, recipient) = match claim(pool, email_outbox_id, lease).await? {
+        Claim::Send(row, recipient) => (row, recipient),
+        Claim::Skipped => return Ok(RelayOutcome::EmailRelayed),
+        Claim::NothingDue => return Ok(RelayOutcome::NothingDue),
+    };
+    // The row is not locked while the provider is called, the lease keeps it ours
+    let outcome = match recipient {
+        Ok(recipient) => email_client
+            .send_email_with_options(
+                &recipient,
+                &row.subject,
+                &row.html_body,
+                &row.text_body,
+                &MessageOptions {
+                    kind: row.email_kind.as_deref().and_then(EmailKind::parse),
+                    tag: row.tag.as_deref(),
+                    ..MessageOptions::default()
+                },
+            )
+            .await
+            .map_err(|e| (e.is_transient(), e.to_string())),
+        Err(error) => Err((false, error)),
+    };
+    match outcome {
+        Ok(sent) => {
+            tracing::info!(provider = %sent.provider, "Relayed an email from the outbox");
+            sqlx::query!(
+                "DELETE FROM email_outbox WHERE email_outbox_id = $1",
+                row.email_outbox_id
+            )
+            .execute(pool)
+            .await
+            .context("Failed to remove a sent email from the outbox.")?;
+        }
+        Err((transient, error)) => {
+            let attempts = row.attempts + 1;
+            let status = if transient && attempts < max_attempts {
+                tracing::warn!(
+                    error = %error,
+                    email_outbox_id = %row.email_outbox_id,
+                    "Failed to relay an email from the outbox, retrying later",
+                );
+                "pending"
+            } else {
+                tracing::error!(
+                    error = %error,
+                    email_outbox_id = %row.email_outbox_id,
+                    "Giving up on an email from the outbox",
+                );
+                "failed"
+            };
+            let next_attempt_at = Utc::now()
+                + chrono::Duration::from_std(retry_delay(attempts))
+                    .context("The retry delay is out of range.")?;
+            sqlx::query!(
+                r#"
+                UPDATE email_outbox
+                SET status = $2, attempts = $3, last_error = $4, next_attempt_at = $5
+                WHERE email_outbox_id = $1
+                "#,
+                row.email_outbox_id,
+                status,
+                attempts,
+                error,
+                next_attempt_at
+            )
+            .execute(pool)
+            .await
+            .context("Failed to reschedule an email from the outbox.")?;
+        }
+    }
+    Ok(RelayOutcome::EmailRelayed)
+}
+
+// Move the email to 'sending' in a short transaction, skipping it instead if its address was
+// suppressed while it waited in the outbox.
+async fn claim(
+    pool: &PgPool,
+    email_outbox_id: Option<Uuid>,
+    lease: Duration,
+) -> Result<Claim, anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    let Some(row) = sqlx::query_as!(
+        ClaimedEmail,
+        r#"
+        UPDATE email_outbox
+        SET status = 'sending', claimed_at = now()
+        WHERE email_outbox_id = (
+            SELECT email_outbox_id
+            FROM email_outbox
+            WHERE (
+                    status = 'pending'
+                    OR (status = 'sending' AND claimed_at < now() - make_interval(secs => $2))
+                )
+                AND CASE
+                    WHEN $1::uuid IS NULL THEN next_attempt_at <= now()
+                    ELSE email_outbox_id = $1
+                END
+            ORDER BY next_attempt_at
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING
+            email_outbox_id, recipient, subject, html_body, text_body, email_kind, tag,
+            attempts
+        "#,
+        email_outbox_id,
+        lease.as_secs_f64()
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to claim an email from the outbox.")?
+    else {
+        return Ok(Claim::NothingDue);
+    };
+    let recipient = SubscriberEmail::parse(row.recipient.clone());
+    let mut claim = Claim::Send(Box::new(row), recipient);
+    if let Claim::Send(row, Ok(recipient)) = &claim {
+        // The address may have been suppressed while the email waited in the outbox
+        if is_suppressed(&mut *transaction, recipient)
+            .await
+            .context("Failed to check the suppression list.")?
+        {
+            tracing::info!(
+                email_outbox_id = %row.email_outbox_id,
+                "Skipping an email to a suppressed address",
+            );
+            sqlx::query!(
+                "UPDATE email_outbox SET status = 'skipped' WHERE email_outbox_id = $1",
+                row.email_outbox_id
+            )
+            .execute(&mut *transaction)
+            .await
+            .context("Failed to skip an email from the outbox.")?;
+            claim = Claim::Skipped;
+        }
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the SQL transaction to claim an email.")?;
+    Ok(claim)
+}
+
+fn retry_delay(attempts: i32) -> Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 16) as u32;
+    BASE_RETRY_DELAY
+        .saturating_mul(2u32.pow(exponent))
+        .min(MAX_RETRY_DELAY)
+}
+
+// Drain the outbox back to back, sleeping once there is nothing left to send.
+pub async fn run_outbox_relay_until_stopped(
+    pool: PgPool,
+    email_client: Arc<EmailClient>,
+    poll_interval: Duration,
+    max_attempts: i32,
+    lease: Duration,
+) {
+    loop {
+        match relay_next_email(&pool, &email_client, max_attempts, lease).await {
+            Ok(RelayOutcome::EmailRelayed) => {}
+            Ok(RelayOutcome::NothingDue) => tokio::time::sleep(poll_interval).await,
+            Err(error) => {
+                tracing::error!(
+                    error.cause_chain = ?error,
+                    "Failed to relay an email from the outbox",
+                );
+                tokio::time::sleep(poll_interval).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{retry_delay, MAX_RETRY_DELAY};
+    use std::time::Duration;
+
+    #[test]
+    fn retries_back_off_exponentially() {
+        assert_eq!(retry_delay(1), Duration::from_secs(10));
+        assert_eq!(retry_delay(2), Duration::from_secs(20));
+        assert_eq!(retry_delay(4), Duration::from_secs(80));
+    }
+
+    #[test]
+    fn retry_delays_are_capped() {
+        assert_eq!(retry_delay(30), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(i32::MAX), MAX_RETRY_DELAY);
+    }
+}
@@ -0,0 +1,288 @@
+use anyhow::Context;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use tera::Tera;
+
+pub const CONFIRMATION: &str = "confirmation";
+pub const NEWSLETTER: &str = "newsletter";
+pub const PASSWORD_RESET: &str = "password_reset";
+// Wraps the HTML rendered from Markdown content; it only has an `.html` part since the
+// plain-text body is derived from its output.
+pub const MARKDOWN_LAYOUT: &str = "markdown_layout";
+
+// Every named template is made of these three parts, e.g. `confirmation.subject.txt`,
+// `confirmation.html` and `confirmation.txt`. Only `.html` parts are HTML-escaped.
+const SUBJECT_PART: &str = "subject.txt";
+const HTML_PART: &str = "html";
+const TEXT_PART: &str = "txt";
+
+#[derive(serde::Serialize, Debug)]
+pub struct RenderedEmail {
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct ConfirmationContext<'a> {
+    pub name: &'a str,
+    pub confirmation_link: &'a str,
+}
+
+#[derive(serde::Serialize)]
+pub struct NewsletterContext<'a> {
+    pub name: &'a str,
+    pub title: &'a str,
+    // Written by the author, inserted as is with `{{ html_content | safe }}`
+    pub html_content: &'a str,
+    pub text_content: &'a str,
+    pub unsubscribe_url: &'a str,
+    // Only set when the email is tracked
+    pub tracking_opt_out_url: Option<&'a str>,
+}
+
+#[derive(serde::Serialize)]
+pub struct PasswordResetContext<'a> {
+    pub name: &'a str,
+    pub reset_link: &'a str,
+}
+
+#[derive(serde::Serialize)]
+pub struct MarkdownLayoutContext<'a> {
+    pub title: &'a str,
+    // Already sanitised, inserted as is with `{{ content | safe }}`
+    pub content: &'a str,
+}
+
+pub struct EmailTemplates {
+    tera: Tera,
+}
+
+impl EmailTemplates {
+    /// Read the templates from `directory`, letting rows of the `email_templates` table
+    /// override them by name.
+    pub async fn load(directory: &str, pool: &PgPool) -> Result<Self, anyhow::Error> {
+        let mut sources = read_directory(directory)?;
+        let overrides = sqlx::query!(r#"SELECT name, source FROM email_templates"#)
+            .fetch_all(pool)
+            .await
+            .context("Failed to fetch the email templates stored in the database.")?;
+        for row in overrides {
+            tracing::info!(
+                "Using the {} email template stored in the database",
+                row.name
+            );
+            sources.insert(row.name, row.source);
+        }
+        Self::from_sources(sources)
+    }
+
+    /// Fails if a template cannot be parsed or rendered with the variables it is given.
+    pub fn from_sources(sources: BTreeMap<String, String>) -> Result<Self, anyhow::Error> {
+        let mut tera = Tera::default();
+        tera.add_raw_templates(sources)
+            .context("Failed to parse the email templates.")?;
+        tera.set_escape_fn(escape_html);
+        let templates = Self { tera };
+        templates.validate()?;
+        Ok(templates)
+    }
+
+    pub fn confirmation(
+        &self,
+        context: &ConfirmationContext,
+    ) -> Result<RenderedEmail, anyhow::Error> {
+        self.render(CONFIRMATION, context)
+    }
+
+    pub fn newsletter(&self, context: &NewsletterContext) -> Result<RenderedEmail, anyhow::Error> {
+        self.render(NEWSLETTER, context)
+    }
+
+    pub fn password_reset(
+        &self,
+        context: &PasswordResetContext,
+    ) -> Result<RenderedEmail, anyhow::Error> {
+        self.render(PASSWORD_RESET, context)
+    }
+
+    pub fn markdown_layout(
+        &self,
+        context: &MarkdownLayoutContext,
+    ) -> Result<String, anyhow::Error> {
+        let context = tera::Context::from_serialize(context)
+            .context("Failed to build the email template context.")?;
+        let template = format!("{}.{}", MARKDOWN_LAYOUT, HTML_PART);
+        self.tera
+            .render(&template, &context)
+            .with_context(|| format!("Failed to render the {} email template.", template))
+    }
+
+    fn render(
+        &self,
+        name: &str,
+        context: &impl serde::Serialize,
+    ) -> Result<RenderedEmail, anyhow::Error> {
+        let context = tera::Context::from_serialize(context)
+            .context("Failed to build the email template context.")?;
+        let render = |part: &str| {
+            let template = format!("{}.{}", name, part);
+            self.tera
+                .render(&template, &context)
+                .with_context(|| format!("Failed to render the {} email template.", template))
+        };
+        Ok(RenderedEmail {
+            subject: render(SUBJECT_PART)?.trim().to_string(),
+            html: render(HTML_PART)?,
+            text: render(TEXT_PART)?,
+        })
+    }
+
+    // Render every template with sample values, so that a missing part or an unknown
+    // variable stops the application at startup rather than when the first email goes out.
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        self.confirmation(&ConfirmationContext {
+            name: "Ursula",
+            confirmation_link: "https://example.com/subscriptions/confirm",
+        })?;
+        self.newsletter(&NewsletterContext {
+            name: "Ursula",
+            title: "Title",
+            html_content: "<p>Content</p>",
+            text_content: "Content",
+            unsubscribe_url: "https://example.com/subscriptions/unsubscribe",
+            tracking_opt_out_url: Some("https://example.com/subscriptions/tracking_opt_out"),
+        })?;
+        self.password_reset(&PasswordResetContext {
+            name: "Ursula",
+            reset_link: "https://example.com/password_reset",
+        })?;
+        self.markdown_layout(&MarkdownLayoutContext {
+            title: "Title",
+            content: "<p>Content</p>",
+        })?;
+        Ok(())
+    }
+}
+
+fn read_directory(directory: &str) -> Result<BTreeMap<String, String>, anyhow::Error> {
+    let mut sources = BTreeMap::new();
+    let entries = std::fs::read_dir(directory)
+        .with_context(|| format!("Failed to read the email templates in {}", directory))?;
+    for entry in entries {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .with_context(|| format!("{} is not a valid template name", path.display()))?
+            .to_string();
+        let source = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read the email template at {}", path.display()))?;
+        sources.insert(name, source);
+    }
+    Ok(sources)
+}
+
+// Tera's default escaping also encodes `/` as `&#x2F;`: valid HTML, but it turns every
+// link in our emails into noise for anyone reading the source.
+pub(crate) fn escape_html(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_directory, ConfirmationContext, EmailTemplates, NewsletterContext};
+
+    fn templates() -> EmailTemplates {
+        EmailTemplates::from_sources(read_directory("templates/email").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn the_bundled_templates_are_valid() {
+        let email = templates()
+            .confirmation(&ConfirmationContext {
+                name: "Ursula",
+                confirmation_link: "https://example.com/confirm?token=abc",
+            })
+            .unwrap();
+        assert_eq!(email.subject, "Welcome!");
+        assert!(email
+            .html
+            .contains(r#"href="https://example.com/confirm?token=abc""#));
+        assert!(email.text.contains("Hi Ursula,"));
+    }
+
+    #[test]
+    fn variables_are_escaped_in_html_but_not_in_text() {
+        let email = templates()
+            .newsletter(&NewsletterContext {
+                name: "<b>Ursula</b>",
+                title: "Title",
+                html_content: "<p>Content</p>",
+                text_content: "Content",
+                unsubscribe_url: "https://example.com/unsubscribe?a=1&b=2",
+                tracking_opt_out_url: None,
+            })
+            .unwrap();
+        assert!(email.html.contains("Hi &lt;b&gt;Ursula&lt;/b&gt;,"));
+        assert!(email.html.contains("<p>Content</p>"));
+        assert!(email.html.contains("unsubscribe?a=1&amp;b=2"));
+        assert!(email.text.contains("Hi <b>Ursula</b>,"));
+    }
+
+    #[test]
+    fn database_overrides_replace_files_by_name() {
+        let mut sources = read_directory("templates/email").unwrap();
+        sources.insert(
+            "newsletter.subject.txt".into(),
+            "[Newsletter] {{ title }}".into(),
+        );
+        let email = EmailTemplates::from_sources(sources)
+            .unwrap()
+            .newsletter(&NewsletterContext {
+                name: "Ursula",
+                title: "Title",
+                html_content: "",
+                text_content: "",
+                unsubscribe_url: "",
+                tracking_opt_out_url: None,
+            })
+            .unwrap();
+        assert_eq!(email.subject, "[Newsletter] Title");
+    }
+
+    #[test]
+    fn unknown_variables_fail_validation() {
+        let mut sources = read_directory("templates/email").unwrap();
+        sources.insert("confirmation.txt".into(), "Hi {{ nmae }}".into());
+        assert!(EmailTemplates::from_sources(sources).is_err());
+    }
+
+    #[test]
+    fn missing_parts_fail_validation() {
+        let mut sources = read_directory("templates/email").unwrap();
+        sources.remove("password_reset.html");
+        assert!(EmailTemplates::from_sources(sources).is_err());
+    }
+
+    #[test]
+    fn syntax_errors_fail_validation() {
+        let mut sources = read_directory("templates/email").unwrap();
+        sources.insert("newsletter.html".into(), "{{ name ".into());
+        assert!(EmailTemplates::from_sources(sources).is_err());
+    }
+}
@@ -0,0 +1,674 @@
+use crate::{
+    archive::assign_slug,
+    domain::SubscriberEmail,
+    email_client::{
+        Attachment, EmailClient, EmailKind, EncodedAttachment, MessageOptions, MessageStream,
+        SendEmailError, SentEmail,
+    },
+    email_templates::{EmailTemplates, NewsletterContext, RenderedEmail},
+    tracking::TrackingLinks,
+    unsubscribe::UnsubscribeLinks,
+};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use sqlx::PgPool;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+// Well below the scheduler's lease, so that a live delivery never looks stalled
+const LEASE_RENEWAL_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct NewsletterIssue {
+    pub newsletter_issue_id: Uuid,
+    pub title: String,
+    pub text_content: String,
+    pub html_content: String,
+    pub tracking_enabled: bool,
+    // The identity configured for newsletters is used when missing
+    pub sender_identity: Option<String>,
+}
+
+// Stand-in subscriber details for previews and test sends
+const PREVIEW_NAME: &str = "Jane Doe";
+const PREVIEW_UNSUBSCRIBE_URL: &str = "#unsubscribe";
+
+impl NewsletterIssue {
+    /// Build the email exactly as it is sent to `subscriber`. Links are tracked unless
+    /// either the issue or the subscriber turned tracking off.
+    fn render_for(
+        &self,
+        templates: &EmailTemplates,
+        unsubscribe_links: &UnsubscribeLinks,
+        tracking_links: &TrackingLinks,
+        subscriber: &ConfirmedSubscriber,
+    ) -> Result<RenderedEmail, anyhow::Error> {
+        let (html_content, tracking_opt_out_url) = if self.is_tracked_for(subscriber) {
+            let html_content = tracking_links.instrument(
+                &self.html_content,
+                self.newsletter_issue_id,
+                subscriber.id,
+            )?;
+            let opt_out_url = unsubscribe_links.tracking_opt_out_url_for(subscriber.id);
+            (html_content, Some(opt_out_url))
+        } else {
+            (self.html_content.clone(), None)
+        };
+        templates.newsletter(&NewsletterContext {
+            name: &subscriber.name,
+            title: &self.title,
+            html_content: &html_content,
+            text_content: &self.text_content,
+            unsubscribe_url: &unsubscribe_links.url_for(subscriber.id),
+            tracking_opt_out_url: tracking_opt_out_url.as_deref(),
+        })
+    }
+
+    fn is_tracked_for(&self, subscriber: &ConfirmedSubscriber) -> bool {
+        self.tracking_enabled && !subscriber.tracking_opt_out
+    }
+
+    /// Render the issue for a placeholder subscriber, as shown in previews and test sends.
+    /// Previews are never tracked.
+    pub fn render_preview(
+        &self,
+        templates: &EmailTemplates,
+    ) -> Result<RenderedEmail, anyhow::Error> {
+        templates.newsletter(&NewsletterContext {
+            name: PREVIEW_NAME,
+            title: &self.title,
+            html_content: &self.html_content,
+            text_content: &self.text_content,
+            unsubscribe_url: PREVIEW_UNSUBSCRIBE_URL,
+            tracking_opt_out_url: None,
+        })
+    }
+}
+
+/// Record a new issue, either ready to be sent right away (`send_at` is `None`) or
+/// waiting for the scheduler to release it.
+#[tracing::instrument(
+    name = "Insert newsletter issue",
+    skip(pool, title, text_content, html_content, attachments)
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn insert_newsletter_issue(
+    pool: &PgPool,
+    title: &str,
+    text_content: &str,
+    html_content: &str,
+    send_at: Option<DateTime<Utc>>,
+    tracking_enabled: bool,
+    sender_identity: Option<&str>,
+    archived: bool,
+    attachments: &[Attachment],
+) -> Result<NewsletterIssue, sqlx::Error> {
+    let newsletter_issue_id = Uuid::new_v4();
+    let mut transaction = pool.begin().await?;
+    let status = if send_at.is_some() {
+        "scheduled"
+    } else {
+        "sending"
+    };
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issues (
+            newsletter_issue_id, title, text_content, html_content, status, send_at,
+            tracking_enabled, sender_identity, archived, created_at, claimed_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, now(), CASE WHEN $5 = 'sending' THEN now() END)
+        "#,
+        newsletter_issue_id,
+        title,
+        text_content,
+        html_content,
+        status,
+        send_at,
+        tracking_enabled,
+        sender_identity,
+        archived
+    )
+    .execute(&mut *transaction)
+    .await?;
+    // Every published issue gets an address in the archive, even if it is not shown there
+    assign_slug(&mut transaction, newsletter_issue_id, title).await?;
+    for (position, attachment) in attachments.iter().enumerate() {
+        sqlx::query!(
+            r#"
+            INSERT INTO newsletter_issue_attachments (
+                newsletter_issue_id, position, name, content_type, content, content_id
+            )
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+            newsletter_issue_id,
+            position as i32,
+            attachment.name,
+            attachment.content_type,
+            attachment.content,
+            attachment.content_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await?;
+    Ok(NewsletterIssue {
+        newsletter_issue_id,
+        title: title.into(),
+        text_content: text_content.into(),
+        html_content: html_content.into(),
+        tracking_enabled,
+        sender_identity: sender_identity.map(Into::into),
+    })
+}
+
+/// Send an issue that has been moved to 'sending' to every confirmed subscriber,
+/// then record whether delivery went through.
+/// Each recipient gets a row in `newsletter_issue_deliveries`: delivering the same issue
+/// again only retries the recipients that have not received it yet, without adding new ones.
+#[tracing::instrument(
+    name = "Deliver newsletter issue",
+    skip(pool, email_client, templates, unsubscribe_links, tracking_links, issue),
+    fields(newsletter_issue_id = %issue.newsletter_issue_id)
+)]
+pub async fn deliver_issue(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    templates: &EmailTemplates,
+    unsubscribe_links: &UnsubscribeLinks,
+    tracking_links: &TrackingLinks,
+    issue: &NewsletterIssue,
+) -> Result<(), anyhow::Error> {
+    let outcome = send_to_confirmed_subscribers(
+        pool,
+        email_client,
+        templates,
+        unsubscribe_links,
+        tracking_links,
+        issue,
+    )
+    .await;
+    let (status, tracked_recipients) = match &outcome {
+        Ok(tally) if tally.failed_recipients == 0 => ("sent", tally.tracked_recipients),
+        Ok(tally) => ("failed", tally.tracked_recipients),
+        Err(_) => ("failed", 0),
+    };
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET status = $2, published_at = now(), tracked_recipients = tracked_recipients + $3
+        WHERE newsletter_issue_id = $1
+        "#,
+        issue.newsletter_issue_id,
+        status,
+        tracked_recipients
+    )
+    .execute(pool)
+    .await
+    .context("Failed to record the outcome of a newsletter issue delivery.")?;
+    // A failed recipient does not stop the others from getting the issue,
+    // but fails the delivery as a whole once everyone else has been tried.
+    let tally = outcome?;
+    if tally.failed_recipients > 0 {
+        anyhow::bail!(
+            "The issue could not be delivered to {} subscribers.",
+            tally.failed_recipients
+        );
+    }
+    Ok(())
+}
+
+struct DeliveryTally {
+    // Recipients who received a tracked email, the denominator of the engagement rates
+    tracked_recipients: i32,
+    failed_recipients: i32,
+}
+
+async fn send_to_confirmed_subscribers(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    templates: &EmailTemplates,
+    unsubscribe_links: &UnsubscribeLinks,
+    tracking_links: &TrackingLinks,
+    issue: &NewsletterIssue,
+) -> Result<DeliveryTally, anyhow::Error> {
+    let mut tally = DeliveryTally {
+        tracked_recipients: 0,
+        failed_recipients: 0,
+    };
+    queue_deliveries(pool, issue.newsletter_issue_id)
+        .await
+        .context("Failed to queue the deliveries of the issue.")?;
+    // Encoded once for all the recipients
+    let attachments: Vec<_> = get_issue_attachments(pool, issue.newsletter_issue_id)
+        .await
+        .context("Failed to retrieve the attachments of the issue.")?
+        .iter()
+        .map(Attachment::encode)
+        .collect();
+    let recipients = get_pending_recipients(pool, issue.newsletter_issue_id)
+        .await
+        .context("Failed to retrieve the recipients of the issue.")?;
+    let delivery = Delivery {
+        pool,
+        email_client,
+        templates,
+        unsubscribe_links,
+        tracking_links,
+        issue,
+        attachments: &attachments,
+        stopped: AtomicBool::new(false),
+    };
+    // The email client spaces the requests out, we only need to keep enough of them waiting
+    let mut outcomes = futures_util::stream::iter(recipients)
+        .map(|recipient| delivery.deliver_to(recipient))
+        .buffer_unordered(email_client.max_in_flight());
+    // Every send in flight is waited for, even after an error: dropping one could lose the
+    // record of an email that already went out, which the next attempt would send again.
+    let mut lease_renewed_at = Instant::now();
+    while let Some(outcome) = outcomes.next().await {
+        if lease_renewed_at.elapsed() >= LEASE_RENEWAL_INTERVAL {
+            if let Err(error) = renew_lease(pool, issue.newsletter_issue_id).await {
+                tracing::error!(
+                    error.cause_chain = ?error,
+                    "Failed to renew the lease on the issue, trying again later",
+                );
+            }
+            lease_renewed_at = Instant::now();
+        }
+        match outcome {
+            Ok(RecipientOutcome::Sent { tracked: true }) => tally.tracked_recipients += 1,
+            Ok(RecipientOutcome::Failed) => tally.failed_recipients += 1,
+            Ok(
+                RecipientOutcome::Sent { tracked: false }
+                | RecipientOutcome::Skipped
+                | RecipientOutcome::NotAttempted,
+            ) => {}
+            Err(error) => {
+                tracing::error!(
+                    error.cause_chain = ?error,
+                    "Failed to deliver the issue to a subscriber",
+                );
+                tally.failed_recipients += 1;
+            }
+        }
+    }
+    Ok(tally)
+}
+
+// Keeps the scheduler from taking over the issue, see `release_due_issue`
+#[tracing::instrument(name = "Renew newsletter issue lease", skip(pool))]
+async fn renew_lease(pool: &PgPool, newsletter_issue_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issues SET claimed_at = now()
+        WHERE newsletter_issue_id = $1 AND status = 'sending'
+        "#,
+        newsletter_issue_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// Everything needed to deliver an issue, shared by the concurrent sends
+struct Delivery<'a> {
+    pool: &'a PgPool,
+    email_client: &'a EmailClient,
+    templates: &'a EmailTemplates,
+    unsubscribe_links: &'a UnsubscribeLinks,
+    tracking_links: &'a TrackingLinks,
+    issue: &'a NewsletterIssue,
+    attachments: &'a [EncodedAttachment],
+    // Set once no other recipient could get the issue either
+    stopped: AtomicBool,
+}
+
+enum RecipientOutcome {
+    Sent { tracked: bool },
+    // Recipients we cannot deliver to are reported, but do not fail the issue as a whole
+    Skipped,
+    Failed,
+    // Left queued for the next attempt at delivering the issue
+    NotAttempted,
+}
+
+impl Delivery<'_> {
+    async fn deliver_to(
+        &self,
+        recipient: PendingRecipient,
+    ) -> Result<RecipientOutcome, anyhow::Error> {
+        if self.stopped.load(Ordering::Relaxed) {
+            return Ok(RecipientOutcome::NotAttempted);
+        }
+        let (outcome, skipped) = match &recipient.subscriber {
+            Ok(subscriber) if !self.email_client.can_deliver_to(&subscriber.email) => {
+                tracing::warn!(
+                    "Skipping a confirmed subscriber. \
+                    Their address requires SMTPUTF8, which the email backend does not support",
+                );
+                let error = anyhow::anyhow!(
+                    "The address requires SMTPUTF8, which the email backend does not support."
+                );
+                (Err(error), true)
+            }
+            Ok(subscriber) => {
+                let outcome = self.send_to(subscriber).await;
+                // Postmark refuses addresses it deactivated after a bounce or a complaint
+                let skipped = matches!(
+                    &outcome,
+                    Err(error) if send_email_error(error).is_some_and(SendEmailError::suppresses_recipient)
+                );
+                (outcome, skipped)
+            }
+            Err(error) => {
+                tracing::warn!(
+                    // The error chain is recorded as a structured field on the log record.
+                    error.cause_chain = ?error,
+                    "Skipping a confirmed subscriber. \
+                    Their stored contact details are invalid",
+                );
+                let error = anyhow::anyhow!("The stored contact details are invalid.");
+                (Err(error), true)
+            }
+        };
+        record_delivery_attempt(
+            self.pool,
+            self.issue.newsletter_issue_id,
+            recipient.subscriber_id,
+            &outcome,
+        )
+        .await
+        .context("Failed to record the delivery of the issue to a subscriber.")?;
+        Ok(match (outcome, &recipient.subscriber) {
+            (Ok(_), subscriber) => RecipientOutcome::Sent {
+                tracked: subscriber
+                    .as_ref()
+                    .is_ok_and(|subscriber| self.issue.is_tracked_for(subscriber)),
+            },
+            (Err(_), _) if skipped => RecipientOutcome::Skipped,
+            (Err(error), _) => {
+                if send_email_error(&error).is_some_and(SendEmailError::affects_every_recipient) {
+                    tracing::error!(
+                        error.cause_chain = ?error,
+                        "Stopped delivering the issue, no other recipient would get it either",
+                    );
+                    self.stopped.store(true, Ordering::Relaxed);
+                }
+                RecipientOutcome::Failed
+            }
+        })
+    }
+
+    async fn send_to(&self, subscriber: &ConfirmedSubscriber) -> Result<SentEmail, anyhow::Error> {
+        let rendered = self.issue.render_for(
+            self.templates,
+            self.unsubscribe_links,
+            self.tracking_links,
+            subscriber,
+        )?;
+        let unsubscribe_url = self.unsubscribe_links.url_for(subscriber.id);
+        // Lets the provider's webhooks be traced back to the delivery
+        let metadata = [
+            (
+                "newsletter_issue_id",
+                self.issue.newsletter_issue_id.to_string(),
+            ),
+            ("subscriber_id", subscriber.id.to_string()),
+        ];
+        self.email_client
+            .send_email_with_options(
+                &subscriber.email,
+                &rendered.subject,
+                &rendered.html,
+                &rendered.text,
+                &MessageOptions {
+                    kind: Some(EmailKind::Newsletter),
+                    sender: self.issue.sender_identity.as_deref(),
+                    stream: MessageStream::Broadcast,
+                    tag: Some("newsletter"),
+                    metadata: &metadata,
+                    attachments: self.attachments,
+                    unsubscribe_url: Some(&unsubscribe_url),
+                    ..MessageOptions::default()
+                },
+            )
+            .await
+            .with_context(|| format!("Failed to send newsletter issue to {}", subscriber.email))
+    }
+}
+
+fn send_email_error(error: &anyhow::Error) -> Option<&SendEmailError> {
+    error.downcast_ref::<SendEmailError>()
+}
+
+struct ConfirmedSubscriber {
+    id: Uuid,
+    email: SubscriberEmail,
+    name: String,
+    tracking_opt_out: bool,
+}
+
+// Suppressed addresses are left out here rather than checked one by one,
+// see `suppression::is_suppressed` for the rule.
+// Recipients are only queued the first time the issue is delivered: delivering it again
+// retries them, it does not reach the subscribers who confirmed in the meantime.
+#[tracing::instrument(name = "Queue newsletter issue deliveries", skip(pool))]
+async fn queue_deliveries(pool: &PgPool, newsletter_issue_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        WITH issue AS (
+            UPDATE newsletter_issues SET recipients_queued_at = now()
+            WHERE newsletter_issue_id = $1 AND recipients_queued_at IS NULL
+            RETURNING newsletter_issue_id
+        )
+        INSERT INTO newsletter_issue_deliveries (
+            newsletter_issue_id, subscriber_id, status, updated_at
+        )
+        SELECT issue.newsletter_issue_id, subscriptions.id, 'queued', now()
+        FROM issue, subscriptions
+        WHERE subscriptions.status = 'confirmed'
+        AND NOT EXISTS (
+            SELECT 1 FROM suppressions
+            WHERE suppressions.email = subscriptions.email_normalised
+            OR suppressions.domain = split_part(subscriptions.email_normalised, '@', 2)
+        )
+        ON CONFLICT DO NOTHING
+        "#,
+        newsletter_issue_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Get newsletter issue attachments", skip(pool))]
+async fn get_issue_attachments(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+) -> Result<Vec<Attachment>, sqlx::Error> {
+    sqlx::query_as!(
+        Attachment,
+        r#"
+        SELECT name, content_type, content, content_id
+        FROM newsletter_issue_attachments
+        WHERE newsletter_issue_id = $1
+        ORDER BY position
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+// A recipient whose stored address no longer parses is kept, so that its delivery is marked failed
+struct PendingRecipient {
+    subscriber_id: Uuid,
+    subscriber: Result<ConfirmedSubscriber, anyhow::Error>,
+}
+
+#[tracing::instrument(name = "Get pending recipients", skip(pool))]
+async fn get_pending_recipients(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+) -> Result<Vec<PendingRecipient>, anyhow::Error> {
+    let recipients = sqlx::query!(
+        r#"
+        SELECT s.id, s.email, s.name, s.tracking_opt_out
+        FROM newsletter_issue_deliveries d
+        JOIN subscriptions s ON s.id = d.subscriber_id
+        WHERE d.newsletter_issue_id = $1 AND d.status IN ('queued', 'failed')
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| {
+        let subscriber = match SubscriberEmail::parse(r.email) {
+            Ok(email) => Ok(ConfirmedSubscriber {
+                id: r.id,
+                email,
+                name: r.name,
+                tracking_opt_out: r.tracking_opt_out,
+            }),
+            Err(error) => Err(anyhow::anyhow!(error)),
+        };
+        PendingRecipient {
+            subscriber_id: r.id,
+            subscriber,
+        }
+    })
+    .collect();
+    Ok(recipients)
+}
+
+async fn record_delivery_attempt(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+    subscriber_id: Uuid,
+    outcome: &Result<SentEmail, anyhow::Error>,
+) -> Result<(), sqlx::Error> {
+    let (status, provider_message_id, provider, last_error) = match outcome {
+        Ok(sent) => (
+            "sent",
+            sent.message_id.clone(),
+            Some(sent.provider.clone()),
+            None,
+        ),
+        Err(error) => ("failed", None, None, Some(format!("{:#}", error))),
+    };
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issue_deliveries
+        SET status = $3, provider_message_id = $4, provider = $5, last_error = $6,
+            attempts = attempts + 1, updated_at = now()
+        WHERE newsletter_issue_id = $1 AND subscriber_id = $2
+        "#,
+        newsletter_issue_id,
+        subscriber_id,
+        status,
+        provider_message_id,
+        provider,
+        last_error
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub enum ReleaseOutcome {
+    IssueReleased,
+    NothingDue,
+}
+
+/// Claim the earliest scheduled issue that is due and deliver it.
+/// Issues left in 'sending' whose lease has not been renewed for `lease` are claimed as
+/// well: whoever was delivering them is gone, and only the recipients still queued get them.
+/// `SKIP LOCKED` lets several instances of the application poll the same table.
+pub async fn release_due_issue(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    templates: &EmailTemplates,
+    unsubscribe_links: &UnsubscribeLinks,
+    tracking_links: &TrackingLinks,
+    lease: Duration,
+) -> Result<ReleaseOutcome, anyhow::Error> {
+    let issue = sqlx::query_as!(
+        NewsletterIssue,
+        r#"
+        UPDATE newsletter_issues
+        SET status = 'sending', claimed_at = now()
+        WHERE newsletter_issue_id = (
+            SELECT newsletter_issue_id
+            FROM newsletter_issues
+            WHERE (status = 'scheduled' AND send_at <= now())
+                OR (status = 'sending' AND claimed_at < now() - make_interval(secs => $1))
+            ORDER BY COALESCE(send_at, created_at)
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING
+            newsletter_issue_id, title, text_content, html_content, tracking_enabled,
+            sender_identity
+        "#,
+        lease.as_secs_f64()
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to claim a due newsletter issue.")?;
+    match issue {
+        Some(issue) => {
+            tracing::info!(
+                newsletter_issue_id = %issue.newsletter_issue_id,
+                "Releasing a scheduled or stalled newsletter issue",
+            );
+            deliver_issue(
+                pool,
+                email_client,
+                templates,
+                unsubscribe_links,
+                tracking_links,
+                &issue,
+            )
+            .await?;
+            Ok(ReleaseOutcome::IssueReleased)
+        }
+        None => Ok(ReleaseOutcome::NothingDue),
+    }
+}
+
+// Poll for due issues, draining them back to back and sleeping once there is nothing left to send.
+pub async fn run_scheduler_until_stopped(
+    pool: PgPool,
+    email_client: Arc<EmailClient>,
+    templates: Arc<EmailTemplates>,
+    unsubscribe_links: Arc<UnsubscribeLinks>,
+    tracking_links: Arc<TrackingLinks>,
+    poll_interval: Duration,
+    lease: Duration,
+) {
+    loop {
+        let outcome = release_due_issue(
+            &pool,
+            &email_client,
+            &templates,
+            &unsubscribe_links,
+            &tracking_links,
+            lease,
+        )
+        .await;
+        match outcome {
+            Ok(ReleaseOutcome::IssueReleased) => {}
+            Ok(ReleaseOutcome::NothingDue) => tokio::time::sleep(poll_interval).await,
+            Err(error) => {
+                tracing::error!(
+                    error.cause_chain = ?error,
+                    "Failed to release a scheduled newsletter issue",
+                );
+                tokio::time::sleep(poll_interval).await;
+            }
+        }
+    }
+}
@@ -1,6 +1,21 @@
+pub mod archive;
+pub mod authentication;
+pub mod bot_protection;
 pub mod configuration;
 pub mod domain;
 pub mod email_client;
+pub mod email_html;
+pub mod email_normalisation;
+pub mod email_outbox;
+pub mod email_templates;
+pub mod issue_delivery;
+pub mod markdown;
+pub mod plain_text;
+pub mod rate_limiting;
 pub mod routes;
+pub mod signup_policy;
 pub mod startup;
+pub mod suppression;
 pub mod telemetry;
+pub mod tracking;
+pub mod unsubscribe;
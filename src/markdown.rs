@@ -0,0 +1,49 @@
+use pulldown_cmark::{html, Options, Parser};
+
+/// Render Markdown to HTML that is safe to embed in an email.
+/// Markdown lets writers inline raw HTML, so the output goes through `ammonia`
+/// to drop scripts, event handlers and any other tag or attribute it does not allow.
+pub fn to_html(markdown: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH;
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, Parser::new_ext(markdown, options));
+    ammonia::clean(&unsafe_html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_html;
+
+    #[test]
+    fn markdown_is_rendered_to_html() {
+        let html = to_html("# News\n\nRead **this** and ~~that~~.\n\n* One\n* Two\n");
+        assert_eq!(
+            html,
+            "<h1>News</h1>\n<p>Read <strong>this</strong> and <del>that</del>.</p>\n\
+             <ul>\n<li>One</li>\n<li>Two</li>\n</ul>\n"
+        );
+    }
+
+    #[test]
+    fn tables_are_supported() {
+        let html = to_html("| Day | Talk |\n| --- | --- |\n| Monday | Rust |\n");
+        assert!(html.contains("<th>Day</th>"));
+        assert!(html.contains("<td>Monday</td>"));
+    }
+
+    #[test]
+    fn inline_html_is_sanitised() {
+        let html = to_html(
+            "Hello <script>alert('hi')</script><img src=\"x.png\" onerror=\"alert('hi')\">",
+        );
+        assert!(!html.contains("script"));
+        assert!(!html.contains("onerror"));
+        assert!(html.contains(r#"<img src="x.png">"#));
+    }
+
+    #[test]
+    fn links_are_kept() {
+        let html = to_html("[Our post](https://example.com/post)");
+        assert!(html.contains(r#"href="https://example.com/post""#));
+    }
+}
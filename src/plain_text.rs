@@ -0,0 +1,112 @@
+use anyhow::Context;
+
+// Most email clients show the plain-text part at its natural width, so we wrap early
+const WRAP_WIDTH: usize = 78;
+
+/// Derive a readable plain-text alternative from an HTML body.
+/// Links become numbered footnotes, headings and lists keep a light Markdown-like
+/// decoration and tables are flattened to one cell per line.
+pub fn from_html(html: &str) -> Result<String, anyhow::Error> {
+    let text = html2text::config::plain()
+        // Bordered tables are unreadable once a client re-wraps them
+        .raw_mode(true)
+        .allow_width_overflow()
+        .string_from_read(html.as_bytes(), WRAP_WIDTH)
+        .context("Failed to convert the HTML content to plain text.")?;
+    Ok(text.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::from_html;
+
+    #[test]
+    fn links_become_footnotes() {
+        let text = from_html(
+            r#"<p>Read <a href="https://example.com/post">our post</a> and
+            <a href="https://example.com/talk">the talk</a>.</p>"#,
+        )
+        .unwrap();
+        assert!(text.contains("Read [our post][1] and [the talk][2]."));
+        assert!(text.ends_with("[1]: https://example.com/post\n[2]: https://example.com/talk"));
+    }
+
+    #[test]
+    fn headings_and_lists_keep_their_structure() {
+        let text = from_html(
+            "<h1>Agenda</h1><ul><li>Coffee</li><li>Talks</li></ul>\
+             <h2>Talks</h2><ol><li>Rust</li><li>Postgres</li></ol>",
+        )
+        .unwrap();
+        assert_eq!(
+            text,
+            "# Agenda\n\n* Coffee\n* Talks\n\n## Talks\n\n1. Rust\n2. Postgres"
+        );
+    }
+
+    #[test]
+    fn tables_are_flattened() {
+        let text = from_html(
+            "<table><tr><th>Day</th><th>Talk</th></tr>\
+             <tr><td>Monday</td><td>Rust</td></tr></table>",
+        )
+        .unwrap();
+        assert_eq!(text, "Day\nTalk\nMonday\nRust");
+    }
+
+    #[test]
+    fn styles_and_scripts_are_dropped() {
+        let text =
+            from_html("<style>p { color: red; }</style><script>alert('hi')</script><p>Hello</p>")
+                .unwrap();
+        assert_eq!(text, "Hello");
+    }
+
+    #[test]
+    fn a_representative_newsletter_reads_well() {
+        let html = r#"
+            <h1>October news</h1>
+            <p>Hello <strong>friends</strong>, this month we shipped
+            <a href="https://example.com/changelog">a lot of changes</a>.</p>
+            <h2>Highlights</h2>
+            <ul>
+                <li>Scheduled sends</li>
+                <li>Drafts with <em>previews</em></li>
+            </ul>
+            <table>
+                <tr><th>Date</th><th>Meetup</th></tr>
+                <tr><td>Oct 12</td><td>Berlin</td></tr>
+            </table>
+            <p>See you soon,<br>The team</p>
+        "#;
+        let text = from_html(html).unwrap();
+        assert_eq!(
+            text,
+            "# October news\n\
+             \n\
+             Hello **friends**, this month we shipped [a lot of changes][1].\n\
+             \n\
+             ## Highlights\n\
+             \n\
+             * Scheduled sends\n\
+             * Drafts with *previews*\n\
+             \n\
+             Date\n\
+             Meetup\n\
+             Oct 12\n\
+             Berlin\n\
+             \n\
+             See you soon,\n\
+             The team\n\
+             \n\
+             [1]: https://example.com/changelog"
+        );
+    }
+
+    #[test]
+    fn long_paragraphs_are_wrapped() {
+        let text = from_html(&format!("<p>{}</p>", "word ".repeat(40))).unwrap();
+        assert!(text.lines().count() > 1);
+        assert!(text.lines().all(|line| line.len() <= 78));
+    }
+}
@@ -0,0 +1,95 @@
+use super::RateLimiter;
+use crate::configuration::RouteLimitSettings;
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::web::Data;
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use std::rc::Rc;
+
+/// Limits requests to the wrapped resource per client IP address.
+/// `route` namespaces the buckets, so that each resource has its own budget.
+pub struct RateLimit {
+    limiter: Data<RateLimiter>,
+    route: &'static str,
+    limits: RouteLimitSettings,
+}
+
+impl RateLimit {
+    pub fn new(
+        limiter: Data<RateLimiter>,
+        route: &'static str,
+        limits: RouteLimitSettings,
+    ) -> Self {
+        Self {
+            limiter,
+            route,
+            limits,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            limiter: self.limiter.clone(),
+            route: self.route,
+            limits: self.limits.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    limiter: Data<RateLimiter>,
+    route: &'static str,
+    limits: RouteLimitSettings,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let limiter = self.limiter.clone();
+        let route = self.route;
+        let limits = self.limits.clone();
+        Box::pin(async move {
+            let client_ip = limiter
+                .client_ip(request.request())
+                .map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+            let key = format!("{}:ip:{}", route, client_ip);
+            let decision = limiter.check(&key, &limits).await;
+            if let Some(decision) = decision.as_ref().filter(|decision| !decision.allowed) {
+                tracing::warn!(route, %client_ip, "Rejecting a rate limited request");
+                let response = decision.too_many_requests();
+                return Ok(request.into_response(response).map_into_right_body());
+            }
+            let mut response = service.call(request).await?;
+            if let Some(decision) = decision {
+                decision.insert_headers(response.headers_mut());
+            }
+            Ok(response.map_into_left_body())
+        })
+    }
+}
@@ -0,0 +1,276 @@
+mod middleware;
+mod store;
+
+pub use middleware::RateLimit;
+pub use store::{
+    delete_full_buckets, delete_full_buckets_periodically,
+    delete_full_in_memory_buckets_periodically, InMemoryBuckets, RateLimitStore,
+};
+
+use crate::configuration::{RateLimitSettings, RouteLimitSettings};
+use crate::domain::SubscriberEmail;
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use actix_web::{HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use std::net::IpAddr;
+
+// The outcome of taking a token from a bucket, rendered as `RateLimit-*` headers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    limit: u32,
+    remaining: u32,
+    // Seconds until the bucket is full again
+    reset_seconds: u64,
+    // Seconds until the next token is available, only set when the request was denied
+    retry_after_seconds: Option<u64>,
+    // Time it takes to refill an empty bucket, advertised in `RateLimit-Policy`
+    window_seconds: u64,
+}
+
+impl RateLimitDecision {
+    pub fn insert_headers(&self, headers: &mut HeaderMap) {
+        let mut insert = |name: &'static str, value: String| {
+            headers.insert(
+                HeaderName::from_static(name),
+                HeaderValue::from_str(&value).unwrap(),
+            );
+        };
+        insert("ratelimit-limit", self.limit.to_string());
+        insert("ratelimit-remaining", self.remaining.to_string());
+        insert("ratelimit-reset", self.reset_seconds.to_string());
+        insert(
+            "ratelimit-policy",
+            format!("{};w={}", self.limit, self.window_seconds),
+        );
+        if let Some(retry_after_seconds) = self.retry_after_seconds {
+            headers.insert(RETRY_AFTER, HeaderValue::from(retry_after_seconds));
+        }
+    }
+
+    pub fn too_many_requests(&self) -> HttpResponse {
+        let mut response = HttpResponse::TooManyRequests().body("Too many requests, slow down.");
+        self.insert_headers(response.headers_mut());
+        response
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BucketState {
+    tokens: f64,
+    updated_at: DateTime<Utc>,
+}
+
+impl RouteLimitSettings {
+    // Tokens added back to the bucket every second
+    fn refill_rate(&self) -> f64 {
+        self.requests_per_minute.max(1) as f64 / 60.0
+    }
+
+    // When a bucket left with `tokens` will be full again
+    fn full_at(&self, state: &BucketState) -> DateTime<Utc> {
+        let seconds = (self.burst as f64 - state.tokens).max(0.0) / self.refill_rate();
+        state.updated_at + chrono::Duration::milliseconds((seconds * 1000.0).ceil() as i64)
+    }
+}
+
+// Refill the bucket for the time elapsed since it was last updated, then try to take a token.
+// A missing bucket starts out full.
+fn take_token(
+    state: Option<BucketState>,
+    limits: &RouteLimitSettings,
+    now: DateTime<Utc>,
+) -> (BucketState, RateLimitDecision) {
+    let capacity = limits.burst as f64;
+    let rate = limits.refill_rate();
+    let tokens = match state {
+        Some(state) => {
+            let elapsed = (now - state.updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+            (state.tokens + elapsed * rate).min(capacity)
+        }
+        None => capacity,
+    };
+    let allowed = tokens >= 1.0;
+    let tokens = if allowed { tokens - 1.0 } else { tokens };
+    let decision = RateLimitDecision {
+        allowed,
+        limit: limits.burst,
+        remaining: tokens.floor() as u32,
+        reset_seconds: ((capacity - tokens) / rate).ceil() as u64,
+        retry_after_seconds: (!allowed).then(|| ((1.0 - tokens) / rate).ceil() as u64),
+        window_seconds: (capacity / rate).ceil() as u64,
+    };
+    (
+        BucketState {
+            tokens,
+            updated_at: now,
+        },
+        decision,
+    )
+}
+
+pub struct RateLimiter {
+    store: RateLimitStore,
+    settings: RateLimitSettings,
+}
+
+impl RateLimiter {
+    pub fn new(store: RateLimitStore, settings: RateLimitSettings) -> Self {
+        Self { store, settings }
+    }
+
+    pub fn settings(&self) -> &RateLimitSettings {
+        &self.settings
+    }
+
+    /// Take a token from the bucket identified by `key`.
+    /// Returns `None` if the store could not be reached, in which case the request is let through.
+    pub async fn check(&self, key: &str, limits: &RouteLimitSettings) -> Option<RateLimitDecision> {
+        match self.store.take(key, limits).await {
+            Ok(decision) => Some(decision),
+            Err(error) => {
+                tracing::error!(
+                    error.cause_chain = ?error,
+                    "Failed to check the rate limit, letting the request through",
+                );
+                None
+            }
+        }
+    }
+
+    /// Limit how often the same address can be submitted, whichever IP it comes from.
+    pub async fn check_email(&self, email: &SubscriberEmail) -> Option<RateLimitDecision> {
+        let limits = self.settings.subscriptions_per_email.as_ref()?;
+        self.check(
+            &format!("subscriptions:email:{}", email.normalised()),
+            limits,
+        )
+        .await
+    }
+
+    /// The client address, looking through `X-Forwarded-For` when the peer is a trusted proxy.
+    pub fn client_ip(&self, request: &HttpRequest) -> Option<IpAddr> {
+        let trusted_proxies = &self.settings.trusted_proxies;
+        let mut client = request.peer_addr()?.ip();
+        if !trusted_proxies.contains(&client) {
+            return Some(client);
+        }
+        let forwarded_for: Vec<&str> = request
+            .headers()
+            .get_all("X-Forwarded-For")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        // Walk the chain from the closest hop, skipping our own proxies
+        for hop in forwarded_for.iter().rev() {
+            match hop.parse::<IpAddr>() {
+                Ok(ip) => {
+                    client = ip;
+                    if !trusted_proxies.contains(&ip) {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        Some(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{take_token, RateLimitStore, RateLimiter};
+    use crate::configuration::{RateLimitSettings, RateLimitStoreKind, RouteLimitSettings};
+    use actix_web::test::TestRequest;
+    use chrono::{Duration, Utc};
+    use std::net::IpAddr;
+
+    fn limits() -> RouteLimitSettings {
+        RouteLimitSettings {
+            burst: 2,
+            requests_per_minute: 60,
+        }
+    }
+
+    fn limiter(trusted_proxies: &[&str]) -> RateLimiter {
+        RateLimiter::new(
+            RateLimitStore::in_memory(),
+            RateLimitSettings {
+                store: RateLimitStoreKind::InMemory,
+                cleanup_interval_seconds: 3600,
+                trusted_proxies: trusted_proxies
+                    .iter()
+                    .map(|ip| ip.parse().unwrap())
+                    .collect(),
+                subscriptions: limits(),
+                subscriptions_confirm: limits(),
+                subscriptions_form_token: limits(),
+                newsletter: limits(),
+                subscriptions_per_email: None,
+            },
+        )
+    }
+
+    #[test]
+    fn a_new_bucket_allows_a_burst_then_denies() {
+        let now = Utc::now();
+        let (state, first) = take_token(None, &limits(), now);
+        let (state, second) = take_token(Some(state), &limits(), now);
+        let (_, third) = take_token(Some(state), &limits(), now);
+
+        assert!(first.allowed && second.allowed);
+        assert_eq!(first.remaining, 1);
+        assert!(!third.allowed);
+        assert_eq!(third.retry_after_seconds, Some(1));
+        assert_eq!(third.reset_seconds, 2);
+    }
+
+    #[test]
+    fn tokens_are_refilled_over_time_up_to_the_burst() {
+        let now = Utc::now();
+        let (state, _) = take_token(None, &limits(), now);
+        let (state, _) = take_token(Some(state), &limits(), now);
+
+        let (_, later) = take_token(Some(state), &limits(), now + Duration::hours(1));
+
+        assert!(later.allowed);
+        assert_eq!(later.remaining, 1);
+    }
+
+    #[tokio::test]
+    async fn the_in_memory_store_keeps_buckets_apart() {
+        let limiter = limiter(&[]);
+        let limits = RouteLimitSettings {
+            burst: 1,
+            requests_per_minute: 1,
+        };
+        assert!(limiter.check("a", &limits).await.unwrap().allowed);
+        assert!(!limiter.check("a", &limits).await.unwrap().allowed);
+        assert!(limiter.check("b", &limits).await.unwrap().allowed);
+    }
+
+    #[test]
+    fn forwarded_headers_from_untrusted_peers_are_ignored() {
+        let request = TestRequest::default()
+            .peer_addr("203.0.113.7:4000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "198.51.100.1"))
+            .to_http_request();
+
+        let client_ip = limiter(&[]).client_ip(&request);
+
+        assert_eq!(client_ip, Some("203.0.113.7".parse::<IpAddr>().unwrap()));
+    }
+
+    #[test]
+    fn the_first_untrusted_hop_is_the_client() {
+        let request = TestRequest::default()
+            .peer_addr("10.0.0.1:4000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "198.51.100.1, 192.0.2.9, 10.0.0.2"))
+            .to_http_request();
+
+        let client_ip = limiter(&["10.0.0.1", "10.0.0.2"]).client_ip(&request);
+
+        assert_eq!(client_ip, Some("192.0.2.9".parse::<IpAddr>().unwrap()));
+    }
+}
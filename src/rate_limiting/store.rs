@@ -0,0 +1,206 @@
+use super::{take_token, BucketState, RateLimitDecision};
+use crate::configuration::RouteLimitSettings;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+struct InMemoryBucket {
+    state: BucketState,
+    // A full bucket is equivalent to a missing one and can be forgotten
+    full_at: DateTime<Utc>,
+}
+
+// Buckets private to a single instance of the application
+#[derive(Default)]
+pub struct InMemoryBuckets(Mutex<HashMap<String, InMemoryBucket>>);
+
+impl InMemoryBuckets {
+    fn take(
+        &self,
+        key: &str,
+        limits: &RouteLimitSettings,
+        now: DateTime<Utc>,
+    ) -> RateLimitDecision {
+        let mut buckets = self.0.lock().unwrap();
+        let (state, decision) =
+            take_token(buckets.get(key).map(|bucket| bucket.state), limits, now);
+        let full_at = limits.full_at(&state);
+        buckets.insert(key.to_string(), InMemoryBucket { state, full_at });
+        decision
+    }
+
+    /// Forget the buckets that have refilled completely by `now`, as `delete_full_buckets`
+    /// does for the Postgres store.
+    pub fn delete_full(&self, now: DateTime<Utc>) -> usize {
+        let mut buckets = self.0.lock().unwrap();
+        let before = buckets.len();
+        buckets.retain(|_, bucket| bucket.full_at > now);
+        before - buckets.len()
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
+
+pub enum RateLimitStore {
+    InMemory(Arc<InMemoryBuckets>),
+    // Buckets are shared by every instance using the same database
+    Postgres(PgPool),
+}
+
+impl RateLimitStore {
+    pub fn in_memory() -> Self {
+        Self::InMemory(Arc::default())
+    }
+
+    pub async fn take(
+        &self,
+        key: &str,
+        limits: &RouteLimitSettings,
+    ) -> Result<RateLimitDecision, anyhow::Error> {
+        let now = Utc::now();
+        match self {
+            Self::InMemory(buckets) => Ok(buckets.take(key, limits, now)),
+            Self::Postgres(pool) => take_from_postgres(pool, key, limits, now).await,
+        }
+    }
+}
+
+#[tracing::instrument(name = "Take a rate limit token from Postgres", skip(pool, limits))]
+async fn take_from_postgres(
+    pool: &PgPool,
+    key: &str,
+    limits: &RouteLimitSettings,
+    now: DateTime<Utc>,
+) -> Result<RateLimitDecision, anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    // Make sure the row exists so that concurrent requests queue up on its lock
+    sqlx::query!(
+        r#"
+        INSERT INTO rate_limit_buckets (key, tokens, updated_at, full_at)
+        VALUES ($1, $2, $3, $3)
+        ON CONFLICT (key) DO NOTHING
+        "#,
+        key,
+        limits.burst as f64,
+        now
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to create the rate limit bucket.")?;
+    // The cleanup may have deleted the bucket in between, it was full then
+    let state = sqlx::query_as!(
+        BucketState,
+        r#"SELECT tokens, updated_at FROM rate_limit_buckets WHERE key = $1 FOR UPDATE"#,
+        key
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to lock the rate limit bucket.")?;
+    let (state, decision) = take_token(state, limits, now);
+    sqlx::query!(
+        r#"
+        INSERT INTO rate_limit_buckets (key, tokens, updated_at, full_at)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (key) DO UPDATE
+        SET tokens = EXCLUDED.tokens, updated_at = EXCLUDED.updated_at, full_at = EXCLUDED.full_at
+        "#,
+        key,
+        state.tokens,
+        state.updated_at,
+        limits.full_at(&state)
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to update the rate limit bucket.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the rate limit bucket.")?;
+    Ok(decision)
+}
+
+/// Delete the buckets that have refilled completely, which the next request would
+/// recreate full anyway. Buckets in use are skipped.
+#[tracing::instrument(name = "Delete full rate limit buckets", skip(pool))]
+pub async fn delete_full_buckets(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let deleted = sqlx::query!(
+        r#"
+        DELETE FROM rate_limit_buckets
+        WHERE key IN (
+            SELECT key FROM rate_limit_buckets
+            WHERE full_at <= now()
+            FOR UPDATE SKIP LOCKED
+        )
+        "#
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    Ok(deleted)
+}
+
+// Without it, every key ever seen would keep a row in the Postgres store.
+pub async fn delete_full_buckets_periodically(pool: PgPool, cleanup_interval: Duration) {
+    let mut interval = tokio::time::interval(cleanup_interval);
+    loop {
+        interval.tick().await;
+        if let Err(error) = delete_full_buckets(&pool).await {
+            tracing::error!(
+                error.cause_chain = ?error,
+                "Failed to delete the full rate limit buckets",
+            );
+        }
+    }
+}
+
+// Same for the in-memory store, which would otherwise grow with every client address seen.
+pub async fn delete_full_in_memory_buckets_periodically(
+    buckets: Arc<InMemoryBuckets>,
+    cleanup_interval: Duration,
+) {
+    let mut interval = tokio::time::interval(cleanup_interval);
+    loop {
+        interval.tick().await;
+        let deleted = buckets.delete_full(Utc::now());
+        tracing::debug!(deleted, "Deleted the full in-memory rate limit buckets");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InMemoryBuckets;
+    use crate::configuration::RouteLimitSettings;
+    use chrono::{Duration, Utc};
+
+    #[test]
+    fn the_in_memory_store_only_keeps_buckets_that_are_still_refilling() {
+        let buckets = InMemoryBuckets::default();
+        let limits = RouteLimitSettings {
+            burst: 2,
+            requests_per_minute: 60,
+        };
+        let now = Utc::now();
+        for i in 0..1000 {
+            buckets.take(&format!("client-{}", i), &limits, now);
+        }
+        // One token takes a second to come back
+        let later = now + Duration::milliseconds(500);
+        buckets.take("busy-client", &limits, later);
+        assert_eq!(buckets.len(), 1001);
+
+        assert_eq!(buckets.delete_full(now + Duration::seconds(1)), 1000);
+
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(buckets.delete_full(later + Duration::seconds(1)), 1);
+        assert_eq!(buckets.len(), 0);
+    }
+}
@@ -0,0 +1,396 @@
+use super::{authenticate_admin, AdminError};
+use crate::{
+    archive::assign_slug,
+    domain::SubscriberEmail,
+    email_client::{EmailClient, EmailKind, MessageOptions},
+    email_html,
+    email_templates::{EmailTemplates, RenderedEmail},
+    issue_delivery::{deliver_issue, NewsletterIssue},
+    routes::{Content, PublishError},
+    startup::ApplicationBaseUrl,
+    suppression::is_suppressed,
+    tracking::TrackingLinks,
+    unsubscribe::UnsubscribeLinks,
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// Test sends go to a handful of colleagues, not to a mailing list
+const MAX_TEST_RECIPIENTS: usize = 10;
+
+#[derive(serde::Deserialize)]
+pub struct DraftData {
+    title: String,
+    // Same as when publishing: `markdown`, or `html` with an optional `text`
+    content: Content,
+}
+
+// The bodies as they will be sent, whichever way the content was given
+#[derive(serde::Serialize)]
+pub struct DraftContent {
+    html: String,
+    text: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct DraftRecord {
+    newsletter_issue_id: Uuid,
+    title: String,
+    content: DraftContent,
+    created_at: DateTime<Utc>,
+    updated_at: Option<DateTime<Utc>>,
+}
+
+#[derive(serde::Serialize)]
+pub struct DraftPreview {
+    #[serde(flatten)]
+    email: RenderedEmail,
+    // Problems the editor should fix before publishing, e.g. Gmail clipping the email
+    warnings: Vec<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct TestSendData {
+    recipients: Vec<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct PublishDraftData {
+    // Publish right away when missing
+    send_at: Option<DateTime<Utc>>,
+    // Open and click tracking is on unless turned off here
+    tracking: Option<bool>,
+    // Name of the sender identity, the one configured for newsletters when missing
+    sender: Option<String>,
+    // The issue appears in the public archive and its feeds unless turned off here
+    archive: Option<bool>,
+}
+
+#[tracing::instrument(name = "Create draft", skip(request, pool, templates, base_url, body))]
+pub async fn create_draft(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    body: web::Json<DraftData>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate_admin(&request, &pool).await?;
+    let (html_content, text_content) = draft_bodies(&body, &templates, &base_url.0)?;
+    let newsletter_issue_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issues (
+            newsletter_issue_id, title, text_content, html_content, status, created_at
+        )
+        VALUES ($1, $2, $3, $4, 'draft', now())
+        "#,
+        newsletter_issue_id,
+        body.title,
+        text_content,
+        html_content
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to store the draft.")?;
+    let draft = get_draft_record(&pool, newsletter_issue_id).await?;
+    Ok(HttpResponse::Created().json(draft))
+}
+
+#[tracing::instrument(name = "Update draft", skip(request, pool, templates, base_url, body))]
+pub async fn update_draft(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    newsletter_issue_id: web::Path<Uuid>,
+    body: web::Json<DraftData>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate_admin(&request, &pool).await?;
+    let (html_content, text_content) = draft_bodies(&body, &templates, &base_url.0)?;
+    let updated = sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET title = $2, text_content = $3, html_content = $4, updated_at = now()
+        WHERE newsletter_issue_id = $1 AND status = 'draft'
+        "#,
+        *newsletter_issue_id,
+        body.title,
+        text_content,
+        html_content
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to update the draft.")?
+    .rows_affected();
+    if updated == 0 {
+        return Err(draft_not_found(&newsletter_issue_id));
+    }
+    let draft = get_draft_record(&pool, *newsletter_issue_id).await?;
+    Ok(HttpResponse::Ok().json(draft))
+}
+
+#[tracing::instrument(name = "Get draft", skip(request, pool))]
+pub async fn get_draft(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    newsletter_issue_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate_admin(&request, &pool).await?;
+    let draft = get_draft_record(&pool, *newsletter_issue_id).await?;
+    Ok(HttpResponse::Ok().json(draft))
+}
+
+#[tracing::instrument(name = "Preview draft", skip(request, pool, templates))]
+pub async fn preview_draft(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    templates: web::Data<EmailTemplates>,
+    newsletter_issue_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate_admin(&request, &pool).await?;
+    let draft = get_draft_issue(&pool, *newsletter_issue_id).await?;
+    let email = draft.render_preview(&templates)?;
+    let warnings = email_html::clipping_warning(&email.html)
+        .into_iter()
+        .collect();
+    Ok(HttpResponse::Ok().json(DraftPreview { email, warnings }))
+}
+
+#[tracing::instrument(
+    name = "Send test draft",
+    skip(request, pool, email_client, templates, body)
+)]
+pub async fn send_test_draft(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    templates: web::Data<EmailTemplates>,
+    newsletter_issue_id: web::Path<Uuid>,
+    body: web::Json<TestSendData>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate_admin(&request, &pool).await?;
+    let recipients = parse_test_recipients(&body.recipients, &email_client)?;
+    for recipient in &recipients {
+        if is_suppressed(pool.get_ref(), recipient)
+            .await
+            .context("Failed to check the suppression list.")?
+        {
+            return Err(AdminError::ValidationError(format!(
+                "{} is on the suppression list.",
+                recipient
+            )));
+        }
+    }
+    let draft = get_draft_issue(&pool, *newsletter_issue_id).await?;
+    let rendered = draft.render_preview(&templates)?;
+    let subject = format!("[Test] {}", rendered.subject);
+    for recipient in &recipients {
+        email_client
+            .send_email_with_options(
+                recipient,
+                &subject,
+                &rendered.html,
+                &rendered.text,
+                &MessageOptions {
+                    kind: Some(EmailKind::TestSend),
+                    tag: Some("test-send"),
+                    ..MessageOptions::default()
+                },
+            )
+            .await
+            .with_context(|| format!("Failed to send a test of the draft to {}", recipient))?;
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[tracing::instrument(
+    name = "Publish draft",
+    skip(
+        request,
+        pool,
+        email_client,
+        templates,
+        unsubscribe_links,
+        tracking_links,
+        body
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn publish_draft(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    templates: web::Data<EmailTemplates>,
+    unsubscribe_links: web::Data<UnsubscribeLinks>,
+    tracking_links: web::Data<TrackingLinks>,
+    newsletter_issue_id: web::Path<Uuid>,
+    body: web::Json<PublishDraftData>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate_admin(&request, &pool).await?;
+    if let Some(send_at) = body.send_at {
+        if send_at <= Utc::now() {
+            return Err(AdminError::ValidationError(
+                "send_at must be in the future.".into(),
+            ));
+        }
+    }
+    if let Some(sender) = &body.sender {
+        if !email_client.senders().contains(sender) {
+            return Err(AdminError::ValidationError(format!(
+                "There is no sender identity named {}.",
+                sender
+            )));
+        }
+    }
+    let status = if body.send_at.is_some() {
+        "scheduled"
+    } else {
+        "sending"
+    };
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    // Moving the draft out of 'draft' first guarantees it is only published once
+    let issue = sqlx::query_as!(
+        NewsletterIssue,
+        r#"
+        UPDATE newsletter_issues
+        SET status = $2, send_at = $3, tracking_enabled = $4, sender_identity = $5,
+            archived = $6, claimed_at = CASE WHEN $2 = 'sending' THEN now() END
+        WHERE newsletter_issue_id = $1 AND status = 'draft'
+        RETURNING
+            newsletter_issue_id, title, text_content, html_content, tracking_enabled,
+            sender_identity
+        "#,
+        *newsletter_issue_id,
+        status,
+        body.send_at,
+        body.tracking.unwrap_or(true),
+        body.sender,
+        body.archive.unwrap_or(true)
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to publish the draft.")?
+    .ok_or_else(|| draft_not_found(&newsletter_issue_id))?;
+    assign_slug(&mut transaction, issue.newsletter_issue_id, &issue.title)
+        .await
+        .context("Failed to assign a slug to the draft.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the SQL transaction to publish the draft.")?;
+    if body.send_at.is_some() {
+        return Ok(HttpResponse::Accepted().finish());
+    }
+    deliver_issue(
+        &pool,
+        &email_client,
+        &templates,
+        &unsubscribe_links,
+        &tracking_links,
+        &issue,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+fn draft_bodies(
+    draft: &DraftData,
+    templates: &EmailTemplates,
+    base_url: &str,
+) -> Result<(String, String), AdminError> {
+    draft
+        .content
+        .bodies(templates, base_url, &draft.title)
+        .map_err(|e| match e {
+            PublishError::ValidationError(message) => AdminError::ValidationError(message),
+            e => AdminError::UnexpectedError(e.into()),
+        })
+}
+
+fn parse_test_recipients(
+    recipients: &[String],
+    email_client: &EmailClient,
+) -> Result<Vec<SubscriberEmail>, AdminError> {
+    if recipients.is_empty() || recipients.len() > MAX_TEST_RECIPIENTS {
+        return Err(AdminError::ValidationError(format!(
+            "Test sends need between 1 and {} recipients.",
+            MAX_TEST_RECIPIENTS
+        )));
+    }
+    recipients
+        .iter()
+        .map(|recipient| {
+            let email =
+                SubscriberEmail::parse(recipient.clone()).map_err(AdminError::ValidationError)?;
+            if !email_client.can_deliver_to(&email) {
+                return Err(AdminError::ValidationError(format!(
+                    "We cannot deliver emails to {} yet.",
+                    email
+                )));
+            }
+            Ok(email)
+        })
+        .collect()
+}
+
+async fn get_draft_issue(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+) -> Result<NewsletterIssue, AdminError> {
+    sqlx::query_as!(
+        NewsletterIssue,
+        r#"
+        SELECT
+            newsletter_issue_id, title, text_content, html_content, tracking_enabled,
+            sender_identity
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1 AND status = 'draft'
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to fetch the draft.")?
+    .ok_or_else(|| draft_not_found(&newsletter_issue_id))
+}
+
+async fn get_draft_record(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+) -> Result<DraftRecord, AdminError> {
+    let row = sqlx::query!(
+        r#"
+        SELECT newsletter_issue_id, title, text_content, html_content, created_at, updated_at
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1 AND status = 'draft'
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to fetch the draft.")?
+    .ok_or_else(|| draft_not_found(&newsletter_issue_id))?;
+    Ok(DraftRecord {
+        newsletter_issue_id: row.newsletter_issue_id,
+        title: row.title,
+        content: DraftContent {
+            html: row.html_content,
+            text: row.text_content,
+        },
+        created_at: row.created_at,
+        updated_at: row.updated_at,
+    })
+}
+
+fn draft_not_found(newsletter_issue_id: &Uuid) -> AdminError {
+    AdminError::NotFound(format!(
+        "There is no draft with id {}.",
+        newsletter_issue_id
+    ))
+}
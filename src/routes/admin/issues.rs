@@ -0,0 +1,405 @@
+use super::{authenticate_admin, AdminError};
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+#[derive(serde::Serialize)]
+pub struct ScheduledIssueRecord {
+    newsletter_issue_id: Uuid,
+    title: String,
+    send_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+pub struct ScheduledIssues {
+    issues: Vec<ScheduledIssueRecord>,
+}
+
+#[derive(serde::Serialize)]
+pub struct IssueEngagement {
+    newsletter_issue_id: Uuid,
+    tracking_enabled: bool,
+    tracked_recipients: i32,
+    // A click implies the email was opened, even if its images were blocked
+    unique_opens: i64,
+    unique_clicks: i64,
+    open_rate: f64,
+    click_rate: f64,
+    links: Vec<LinkClicks>,
+}
+
+#[derive(serde::Serialize)]
+pub struct LinkClicks {
+    url: String,
+    clicks: i64,
+    unique_clicks: i64,
+}
+
+#[derive(serde::Serialize)]
+pub struct IssueDeliveries {
+    newsletter_issue_id: Uuid,
+    summary: DeliverySummary,
+    deliveries: Vec<DeliveryRecord>,
+    // Pass it as `after` to get the next page, missing on the last one
+    next_cursor: Option<String>,
+}
+
+// Number of recipients in each delivery state
+#[derive(serde::Serialize)]
+pub struct DeliverySummary {
+    queued: i64,
+    sent: i64,
+    failed: i64,
+    bounced: i64,
+}
+
+#[derive(serde::Serialize)]
+pub struct DeliveryRecord {
+    subscriber_id: Uuid,
+    email: String,
+    status: String,
+    provider_message_id: Option<String>,
+    // Email backend that accepted the delivery
+    provider: Option<String>,
+    attempts: i32,
+    last_error: Option<String>,
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Queued,
+    Sent,
+    Failed,
+    Bounced,
+}
+
+impl DeliveryStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Queued => "queued",
+            DeliveryStatus::Sent => "sent",
+            DeliveryStatus::Failed => "failed",
+            DeliveryStatus::Bounced => "bounced",
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct DeliveryFilters {
+    status: Option<DeliveryStatus>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct DeliveryPagination {
+    limit: Option<i64>,
+    after: Option<String>,
+}
+
+// Position of the last delivery of a page in the (email, subscriber id) keyset.
+#[derive(Debug, PartialEq)]
+struct DeliveryCursor {
+    email: String,
+    subscriber_id: Uuid,
+}
+
+impl DeliveryCursor {
+    fn encode(&self) -> String {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(format!("{}|{}", self.email, self.subscriber_id))
+    }
+
+    fn decode(s: &str) -> Result<Self, anyhow::Error> {
+        let decoded_bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(s)
+            .context("Failed to base64-decode the cursor.")?;
+        let decoded = String::from_utf8(decoded_bytes).context("The cursor is not valid UTF8.")?;
+        let (email, subscriber_id) = decoded
+            .rsplit_once('|')
+            .context("The cursor is missing its separator.")?;
+        let subscriber_id =
+            Uuid::parse_str(subscriber_id).context("The cursor does not end with a valid id.")?;
+        Ok(Self {
+            email: email.to_string(),
+            subscriber_id,
+        })
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct RescheduleData {
+    send_at: DateTime<Utc>,
+}
+
+#[tracing::instrument(name = "List scheduled issues", skip(request, pool))]
+pub async fn list_scheduled_issues(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate_admin(&request, &pool).await?;
+    let issues = sqlx::query_as!(
+        ScheduledIssueRecord,
+        r#"
+        SELECT newsletter_issue_id, title, send_at, created_at
+        FROM newsletter_issues
+        WHERE status = 'scheduled'
+        ORDER BY send_at
+        "#
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .context("Failed to fetch the scheduled issues.")?;
+    Ok(HttpResponse::Ok().json(ScheduledIssues { issues }))
+}
+
+#[tracing::instrument(name = "Reschedule issue", skip(request, pool, body))]
+pub async fn reschedule_issue(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    newsletter_issue_id: web::Path<Uuid>,
+    body: web::Json<RescheduleData>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate_admin(&request, &pool).await?;
+    if body.send_at <= Utc::now() {
+        return Err(AdminError::ValidationError(
+            "send_at must be in the future.".into(),
+        ));
+    }
+    // Only issues the scheduler has not picked up yet can be moved
+    let issue = sqlx::query_as!(
+        ScheduledIssueRecord,
+        r#"
+        UPDATE newsletter_issues
+        SET send_at = $2
+        WHERE newsletter_issue_id = $1 AND status = 'scheduled'
+        RETURNING newsletter_issue_id, title, send_at, created_at
+        "#,
+        *newsletter_issue_id,
+        body.send_at
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to reschedule the issue.")?
+    .ok_or_else(|| not_scheduled(&newsletter_issue_id))?;
+    Ok(HttpResponse::Ok().json(issue))
+}
+
+#[tracing::instrument(name = "Cancel scheduled issue", skip(request, pool))]
+pub async fn cancel_scheduled_issue(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    newsletter_issue_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate_admin(&request, &pool).await?;
+    let cancelled = sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET status = 'cancelled'
+        WHERE newsletter_issue_id = $1 AND status = 'scheduled'
+        "#,
+        *newsletter_issue_id
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to cancel the issue.")?
+    .rows_affected();
+    if cancelled == 0 {
+        return Err(not_scheduled(&newsletter_issue_id));
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[tracing::instrument(name = "Get issue engagement", skip(request, pool))]
+pub async fn issue_engagement(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    newsletter_issue_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate_admin(&request, &pool).await?;
+    let issue = sqlx::query!(
+        r#"
+        SELECT
+            tracking_enabled,
+            tracked_recipients,
+            (
+                SELECT COUNT(DISTINCT subscriber_id) FROM tracking_events
+                WHERE newsletter_issue_id = $1
+            ) AS "unique_opens!",
+            (
+                SELECT COUNT(DISTINCT subscriber_id) FROM tracking_events
+                WHERE newsletter_issue_id = $1 AND kind = 'click'
+            ) AS "unique_clicks!"
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        "#,
+        *newsletter_issue_id
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to fetch the engagement of the issue.")?
+    .ok_or_else(|| {
+        AdminError::NotFound(format!(
+            "There is no issue with id {}.",
+            newsletter_issue_id
+        ))
+    })?;
+    let links = sqlx::query_as!(
+        LinkClicks,
+        r#"
+        SELECT
+            url AS "url!",
+            COUNT(*) AS "clicks!",
+            COUNT(DISTINCT subscriber_id) AS "unique_clicks!"
+        FROM tracking_events
+        WHERE newsletter_issue_id = $1 AND kind = 'click'
+        GROUP BY url
+        ORDER BY 2 DESC, url
+        "#,
+        *newsletter_issue_id
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .context("Failed to fetch the clicks of the issue.")?;
+    let rate = |count: i64| {
+        if issue.tracked_recipients == 0 {
+            0.0
+        } else {
+            count as f64 / issue.tracked_recipients as f64
+        }
+    };
+    Ok(HttpResponse::Ok().json(IssueEngagement {
+        newsletter_issue_id: *newsletter_issue_id,
+        tracking_enabled: issue.tracking_enabled,
+        tracked_recipients: issue.tracked_recipients,
+        unique_opens: issue.unique_opens,
+        unique_clicks: issue.unique_clicks,
+        open_rate: rate(issue.unique_opens),
+        click_rate: rate(issue.unique_clicks),
+        links,
+    }))
+}
+
+#[tracing::instrument(name = "List issue deliveries", skip(request, pool))]
+pub async fn list_issue_deliveries(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    newsletter_issue_id: web::Path<Uuid>,
+    filters: web::Query<DeliveryFilters>,
+    pagination: web::Query<DeliveryPagination>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate_admin(&request, &pool).await?;
+    let limit = pagination.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(AdminError::ValidationError(format!(
+            "`limit` must be between 1 and {}.",
+            MAX_PAGE_SIZE
+        )));
+    }
+    let after = pagination
+        .after
+        .as_deref()
+        .map(DeliveryCursor::decode)
+        .transpose()
+        .map_err(|e| AdminError::ValidationError(format!("Invalid cursor: {}", e)))?;
+    let summary = sqlx::query_as!(
+        DeliverySummary,
+        r#"
+        SELECT
+            COUNT(*) FILTER (WHERE d.status = 'queued') AS "queued!",
+            COUNT(*) FILTER (WHERE d.status = 'sent') AS "sent!",
+            COUNT(*) FILTER (WHERE d.status = 'failed') AS "failed!",
+            COUNT(*) FILTER (WHERE d.status = 'bounced') AS "bounced!"
+        FROM newsletter_issues i
+        LEFT JOIN newsletter_issue_deliveries d USING (newsletter_issue_id)
+        WHERE i.newsletter_issue_id = $1
+        GROUP BY i.newsletter_issue_id
+        "#,
+        *newsletter_issue_id
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to summarise the deliveries of the issue.")?
+    .ok_or_else(|| {
+        AdminError::NotFound(format!(
+            "There is no issue with id {}.",
+            newsletter_issue_id
+        ))
+    })?;
+    // Fetch one extra row to find out whether there is a next page.
+    let mut deliveries = sqlx::query_as!(
+        DeliveryRecord,
+        r#"
+        SELECT
+            d.subscriber_id, s.email, d.status, d.provider_message_id, d.provider,
+            d.attempts, d.last_error, d.updated_at
+        FROM newsletter_issue_deliveries d
+        JOIN subscriptions s ON s.id = d.subscriber_id
+        WHERE d.newsletter_issue_id = $1 AND ($2::TEXT IS NULL OR d.status = $2)
+            AND ($3::TEXT IS NULL OR (s.email, d.subscriber_id) > ($3, $4::UUID))
+        ORDER BY s.email, d.subscriber_id
+        LIMIT $5
+        "#,
+        *newsletter_issue_id,
+        filters.status.as_ref().map(DeliveryStatus::as_str),
+        after.as_ref().map(|cursor| cursor.email.as_str()),
+        after.as_ref().map(|cursor| cursor.subscriber_id),
+        limit + 1
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .context("Failed to fetch the deliveries of the issue.")?;
+    let next_cursor = if deliveries.len() as i64 > limit {
+        deliveries.truncate(limit as usize);
+        deliveries.last().map(|last| {
+            DeliveryCursor {
+                email: last.email.clone(),
+                subscriber_id: last.subscriber_id,
+            }
+            .encode()
+        })
+    } else {
+        None
+    };
+    Ok(HttpResponse::Ok().json(IssueDeliveries {
+        newsletter_issue_id: *newsletter_issue_id,
+        summary,
+        deliveries,
+        next_cursor,
+    }))
+}
+
+fn not_scheduled(newsletter_issue_id: &Uuid) -> AdminError {
+    AdminError::NotFound(format!(
+        "There is no scheduled issue with id {}.",
+        newsletter_issue_id
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeliveryCursor;
+    use claims::{assert_err, assert_ok_eq};
+    use uuid::Uuid;
+
+    #[test]
+    fn a_delivery_cursor_containing_the_separator_round_trips() {
+        let cursor = DeliveryCursor {
+            email: "odd|name@example.com".into(),
+            subscriber_id: Uuid::new_v4(),
+        };
+        assert_ok_eq!(DeliveryCursor::decode(&cursor.encode()), cursor);
+    }
+
+    #[test]
+    fn garbage_delivery_cursors_are_rejected() {
+        assert_err!(DeliveryCursor::decode("not a cursor"));
+    }
+}
@@ -0,0 +1,31 @@
+use super::{authenticate_admin, AdminError};
+use crate::email_client::{BackendSnapshot, EmailClient, ThrottleSnapshot};
+use actix_web::{web, HttpRequest, HttpResponse};
+use sqlx::PgPool;
+
+#[derive(serde::Serialize)]
+pub struct Metrics {
+    email_client: EmailClientMetrics,
+}
+
+#[derive(serde::Serialize)]
+pub struct EmailClientMetrics {
+    throttle: ThrottleSnapshot,
+    // The primary backend first
+    backends: Vec<BackendSnapshot>,
+}
+
+#[tracing::instrument(name = "Get metrics", skip(request, pool, email_client))]
+pub async fn get_metrics(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate_admin(&request, &pool).await?;
+    Ok(HttpResponse::Ok().json(Metrics {
+        email_client: EmailClientMetrics {
+            throttle: email_client.throttle_snapshot(),
+            backends: email_client.backend_snapshots(),
+        },
+    }))
+}
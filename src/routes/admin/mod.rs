@@ -0,0 +1,95 @@
+mod drafts;
+mod issues;
+mod metrics;
+mod subscribers;
+mod suppressions;
+
+pub use drafts::*;
+pub use issues::*;
+pub use metrics::*;
+pub use subscribers::*;
+pub use suppressions::*;
+
+use crate::{
+    authentication::{basic_authentification, validate_credentials, AuthError},
+    routes::error_chain_fmt,
+};
+use actix_web::{http::header, HttpRequest, HttpResponse, ResponseError};
+use reqwest::{header::HeaderValue, StatusCode};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(thiserror::Error)]
+pub enum AdminError {
+    #[error("Authentification failed")]
+    AuthError(#[source] anyhow::Error),
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for AdminError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for AdminError {
+    fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
+        match self {
+            AdminError::UnexpectedError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+            AdminError::ValidationError(message) => {
+                HttpResponse::BadRequest().body(message.clone())
+            }
+            AdminError::NotFound(message) => HttpResponse::NotFound().body(message.clone()),
+            AdminError::AuthError(_) => {
+                let mut response = HttpResponse::new(StatusCode::UNAUTHORIZED);
+                let header_value = HeaderValue::from_str(r#"Basic realm="admin""#).unwrap();
+                response
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, header_value);
+                response
+            }
+        }
+    }
+}
+
+// Every admin endpoint is gated behind the same 'Basic' credentials used to publish newsletters.
+#[tracing::instrument(
+    name = "Authenticate admin",
+    skip(request, pool),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn authenticate_admin(request: &HttpRequest, pool: &PgPool) -> Result<Uuid, AdminError> {
+    let credentials = basic_authentification(request.headers()).map_err(AdminError::AuthError)?;
+    tracing::Span::current().record("username", tracing::field::display(&credentials.username));
+    let user_id = validate_credentials(credentials, pool)
+        .await
+        .map_err(|e| match e {
+            AuthError::InvalidCredentials(_) => AdminError::AuthError(e.into()),
+            AuthError::UnexpectedError(_) => AdminError::UnexpectedError(e.into()),
+        })?;
+    tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+    Ok(user_id)
+}
+
+// Escape the LIKE wildcards, and the escape character first, so that a search
+// is a plain substring match.
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::escape_like;
+
+    #[test]
+    fn like_wildcards_are_escaped() {
+        assert_eq!(escape_like("a_b%c\\"), "a\\_b\\%c\\\\");
+    }
+}
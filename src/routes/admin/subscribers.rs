@@ -0,0 +1,350 @@
+use super::{authenticate_admin, escape_like, AdminError};
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use tracing::Instrument;
+use uuid::Uuid;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+const CSV_HEADER: &str = "id,email,name,status,subscribed_at\n";
+
+#[derive(serde::Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    #[default]
+    SubscribedAt,
+    Email,
+    Name,
+}
+
+impl SortField {
+    fn column(&self) -> &'static str {
+        match self {
+            SortField::SubscribedAt => "subscribed_at",
+            SortField::Email => "email",
+            SortField::Name => "name",
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+// Filters shared by the paginated listing and the CSV export.
+#[derive(serde::Deserialize, Debug)]
+pub struct SubscriberFilters {
+    status: Option<String>,
+    // Case-insensitive substring match on the email address
+    email: Option<String>,
+    subscribed_after: Option<DateTime<Utc>>,
+    subscribed_before: Option<DateTime<Utc>>,
+    #[serde(default)]
+    sort: SortField,
+    #[serde(default)]
+    order: SortOrder,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct Pagination {
+    limit: Option<i64>,
+    cursor: Option<String>,
+}
+
+#[derive(serde::Serialize, sqlx::FromRow)]
+pub struct SubscriberRecord {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    subscribed_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+pub struct SubscriberPage {
+    subscribers: Vec<SubscriberRecord>,
+    next_cursor: Option<String>,
+}
+
+// Position of the last row of a page in the (sort column, id) keyset.
+#[derive(Debug, PartialEq)]
+struct Cursor {
+    value: CursorValue,
+    id: Uuid,
+}
+
+#[derive(Debug, PartialEq)]
+enum CursorValue {
+    Timestamp(DateTime<Utc>),
+    Text(String),
+}
+
+impl Cursor {
+    fn after(record: &SubscriberRecord, sort: SortField) -> Self {
+        let value = match sort {
+            SortField::SubscribedAt => CursorValue::Timestamp(record.subscribed_at),
+            SortField::Email => CursorValue::Text(record.email.clone()),
+            SortField::Name => CursorValue::Text(record.name.clone()),
+        };
+        Self {
+            value,
+            id: record.id,
+        }
+    }
+
+    fn encode(&self) -> String {
+        let value = match &self.value {
+            CursorValue::Timestamp(t) => t.to_rfc3339(),
+            CursorValue::Text(s) => s.clone(),
+        };
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(format!("{}|{}", value, self.id))
+    }
+
+    fn decode(s: &str, sort: SortField) -> Result<Self, anyhow::Error> {
+        let decoded_bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(s)
+            .context("Failed to base64-decode the cursor.")?;
+        let decoded = String::from_utf8(decoded_bytes).context("The cursor is not valid UTF8.")?;
+        // The id is always last and never contains the separator, unlike the sort value.
+        let (value, id) = decoded
+            .rsplit_once('|')
+            .context("The cursor is missing its separator.")?;
+        let id = Uuid::parse_str(id).context("The cursor does not end with a valid id.")?;
+        let value = match sort {
+            SortField::SubscribedAt => CursorValue::Timestamp(
+                DateTime::parse_from_rfc3339(value)
+                    .context("The cursor does not match the requested sort field.")?
+                    .with_timezone(&Utc),
+            ),
+            SortField::Email | SortField::Name => CursorValue::Text(value.to_string()),
+        };
+        Ok(Self { value, id })
+    }
+}
+
+impl SubscriberRecord {
+    fn to_csv_record(&self) -> String {
+        format!(
+            "{},{},{},{},{}\n",
+            self.id,
+            csv_field(&self.email),
+            csv_field(&self.name),
+            csv_field(&self.status),
+            self.subscribed_at.to_rfc3339(),
+        )
+    }
+}
+
+// Quote a CSV field when needed and defuse values that spreadsheets would evaluate as formulas.
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+fn select_subscribers<'a>(
+    filters: &'a SubscriberFilters,
+    cursor: Option<Cursor>,
+) -> QueryBuilder<'a, Postgres> {
+    let mut query = QueryBuilder::new(
+        "SELECT id, email, name, status, subscribed_at FROM subscriptions WHERE TRUE",
+    );
+    if let Some(status) = &filters.status {
+        query.push(" AND status = ").push_bind(status);
+    }
+    if let Some(email) = &filters.email {
+        query
+            .push(" AND email ILIKE ")
+            .push_bind(format!("%{}%", escape_like(email)));
+    }
+    if let Some(subscribed_after) = filters.subscribed_after {
+        query
+            .push(" AND subscribed_at >= ")
+            .push_bind(subscribed_after);
+    }
+    if let Some(subscribed_before) = filters.subscribed_before {
+        query
+            .push(" AND subscribed_at < ")
+            .push_bind(subscribed_before);
+    }
+
+    let column = filters.sort.column();
+    let (comparison, direction) = match filters.order {
+        SortOrder::Asc => (">", "ASC"),
+        SortOrder::Desc => ("<", "DESC"),
+    };
+    if let Some(cursor) = cursor {
+        query.push(format!(" AND ({}, id) {} (", column, comparison));
+        match cursor.value {
+            CursorValue::Timestamp(t) => query.push_bind(t),
+            CursorValue::Text(s) => query.push_bind(s),
+        };
+        query.push(", ").push_bind(cursor.id).push(")");
+    }
+    query.push(format!(
+        " ORDER BY {} {}, id {}",
+        column, direction, direction
+    ));
+    query
+}
+
+#[tracing::instrument(name = "List subscribers", skip(request, pool))]
+pub async fn list_subscribers(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    filters: web::Query<SubscriberFilters>,
+    pagination: web::Query<Pagination>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate_admin(&request, &pool).await?;
+
+    let limit = pagination.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(AdminError::ValidationError(format!(
+            "`limit` must be between 1 and {}.",
+            MAX_PAGE_SIZE
+        )));
+    }
+    let cursor = pagination
+        .cursor
+        .as_deref()
+        .map(|c| Cursor::decode(c, filters.sort))
+        .transpose()
+        .map_err(|e| AdminError::ValidationError(format!("Invalid cursor: {}", e)))?;
+
+    let mut query = select_subscribers(&filters, cursor);
+    // Fetch one extra row to find out whether there is a next page.
+    query.push(" LIMIT ").push_bind(limit + 1);
+    let mut subscribers = query
+        .build_query_as::<SubscriberRecord>()
+        .fetch_all(pool.get_ref())
+        .await
+        .context("Failed to fetch subscribers.")?;
+
+    let next_cursor = if subscribers.len() as i64 > limit {
+        subscribers.truncate(limit as usize);
+        subscribers
+            .last()
+            .map(|last| Cursor::after(last, filters.sort).encode())
+    } else {
+        None
+    };
+    Ok(HttpResponse::Ok().json(SubscriberPage {
+        subscribers,
+        next_cursor,
+    }))
+}
+
+#[tracing::instrument(name = "Export subscribers as CSV", skip(request, pool))]
+pub async fn export_subscribers(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    filters: web::Query<SubscriberFilters>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate_admin(&request, &pool).await?;
+
+    // Rows are streamed from Postgres into the response body through a bounded channel,
+    // so a slow client applies backpressure instead of buffering the whole table in memory.
+    let (sender, receiver) = tokio::sync::mpsc::channel::<Result<web::Bytes, sqlx::Error>>(64);
+    let pool = pool.get_ref().clone();
+    let filters = filters.into_inner();
+    tokio::spawn(
+        async move {
+            if sender.send(Ok(web::Bytes::from(CSV_HEADER))).await.is_err() {
+                return;
+            }
+            let mut query = select_subscribers(&filters, None);
+            let mut rows = query.build_query_as::<SubscriberRecord>().fetch(&pool);
+            while let Some(row) = rows.next().await {
+                let failed = row.is_err();
+                if let Err(error) = &row {
+                    tracing::error!(error.cause_chain = ?error, "Failed to stream subscribers.");
+                }
+                let chunk = row.map(|record| web::Bytes::from(record.to_csv_record()));
+                if sender.send(chunk).await.is_err() || failed {
+                    break;
+                }
+            }
+        }
+        .instrument(tracing::Span::current()),
+    );
+    let body = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            r#"attachment; filename="subscribers.csv""#,
+        ))
+        .streaming(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{csv_field, Cursor, CursorValue, SortField};
+    use chrono::{TimeZone, Utc};
+    use claims::{assert_err, assert_ok_eq};
+    use uuid::Uuid;
+
+    #[test]
+    fn a_timestamp_cursor_round_trips() {
+        let cursor = Cursor {
+            value: CursorValue::Timestamp(Utc.with_ymd_and_hms(2023, 9, 1, 12, 30, 0).unwrap()),
+            id: Uuid::new_v4(),
+        };
+        let encoded = cursor.encode();
+        assert_ok_eq!(Cursor::decode(&encoded, SortField::SubscribedAt), cursor);
+    }
+
+    #[test]
+    fn a_text_cursor_containing_the_separator_round_trips() {
+        let cursor = Cursor {
+            value: CursorValue::Text("odd|name@example.com".into()),
+            id: Uuid::new_v4(),
+        };
+        let encoded = cursor.encode();
+        assert_ok_eq!(Cursor::decode(&encoded, SortField::Email), cursor);
+    }
+
+    #[test]
+    fn a_text_cursor_is_rejected_for_a_timestamp_sort() {
+        let cursor = Cursor {
+            value: CursorValue::Text("ursula@domain.com".into()),
+            id: Uuid::new_v4(),
+        };
+        assert_err!(Cursor::decode(&cursor.encode(), SortField::SubscribedAt));
+    }
+
+    #[test]
+    fn garbage_cursors_are_rejected() {
+        assert_err!(Cursor::decode("not a cursor", SortField::Email));
+    }
+
+    #[test]
+    fn csv_fields_with_separators_are_quoted() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("Kuzoi, Sara"), "\"Kuzoi, Sara\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn csv_fields_starting_with_a_formula_are_defused() {
+        assert_eq!(csv_field("=HYPERLINK(1)"), "'=HYPERLINK(1)");
+    }
+}
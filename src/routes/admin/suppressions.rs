@@ -0,0 +1,205 @@
+use super::{authenticate_admin, escape_like, AdminError};
+use crate::domain::SubscriberEmail;
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const MAX_SEARCH_RESULTS: i64 = 500;
+
+#[derive(serde::Deserialize)]
+pub struct SuppressionData {
+    // Exactly one of `email` and `domain` must be given
+    email: Option<String>,
+    domain: Option<String>,
+    reason: String,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct SuppressionSearch {
+    // Case-insensitive substring match on the address, the domain or the reason
+    q: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct SuppressionRecord {
+    suppression_id: Uuid,
+    email: Option<String>,
+    domain: Option<String>,
+    reason: String,
+    // Username of the admin who added the entry
+    added_by: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+pub struct Suppressions {
+    suppressions: Vec<SuppressionRecord>,
+}
+
+enum SuppressionTarget {
+    Email(String),
+    Domain(String),
+}
+
+impl TryFrom<&SuppressionData> for SuppressionTarget {
+    type Error = String;
+
+    // Entries are stored in the form `suppression::is_suppressed` compares against
+    fn try_from(value: &SuppressionData) -> Result<Self, Self::Error> {
+        match (&value.email, &value.domain) {
+            (Some(email), None) => {
+                let email = SubscriberEmail::parse(email.clone())?;
+                Ok(Self::Email(email.normalised()))
+            }
+            (None, Some(domain)) => {
+                // Reuse the address parser to validate the domain and convert it to A-labels
+                let email = SubscriberEmail::parse(format!("postmaster@{}", domain.trim()))
+                    .map_err(|_| format!("{} is not a valid domain.", domain))?;
+                Ok(Self::Domain(email.domain().to_owned()))
+            }
+            _ => Err("Either an email or a domain must be given, but not both.".into()),
+        }
+    }
+}
+
+#[tracing::instrument(name = "Search the suppression list", skip(request, pool))]
+pub async fn list_suppressions(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    query: web::Query<SuppressionSearch>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate_admin(&request, &pool).await?;
+    let pattern = query.q.as_deref().map(|q| format!("%{}%", escape_like(q)));
+    let suppressions = sqlx::query_as!(
+        SuppressionRecord,
+        r#"
+        SELECT
+            s.suppression_id, s.email, s.domain, s.reason,
+            u.username AS added_by, s.created_at
+        FROM suppressions s
+        JOIN users u ON u.user_id = s.added_by
+        WHERE $1::TEXT IS NULL
+            OR s.email ILIKE $1 OR s.domain ILIKE $1 OR s.reason ILIKE $1
+        ORDER BY s.created_at DESC
+        LIMIT $2
+        "#,
+        pattern,
+        MAX_SEARCH_RESULTS
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .context("Failed to search the suppression list.")?;
+    Ok(HttpResponse::Ok().json(Suppressions { suppressions }))
+}
+
+#[tracing::instrument(name = "Add to the suppression list", skip(request, pool, body))]
+pub async fn add_suppression(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    body: web::Json<SuppressionData>,
+) -> Result<HttpResponse, AdminError> {
+    let user_id = authenticate_admin(&request, &pool).await?;
+    let target = SuppressionTarget::try_from(&*body).map_err(AdminError::ValidationError)?;
+    if body.reason.trim().is_empty() {
+        return Err(AdminError::ValidationError(
+            "A reason must be given.".into(),
+        ));
+    }
+    let (email, domain) = match target {
+        SuppressionTarget::Email(email) => (Some(email), None),
+        SuppressionTarget::Domain(domain) => (None, Some(domain)),
+    };
+    let suppression = sqlx::query_as!(
+        SuppressionRecord,
+        r#"
+        WITH inserted AS (
+            INSERT INTO suppressions (suppression_id, email, domain, reason, added_by, created_at)
+            VALUES ($1, $2, $3, $4, $5, now())
+            ON CONFLICT DO NOTHING
+            RETURNING suppression_id, email, domain, reason, added_by, created_at
+        )
+        SELECT
+            i.suppression_id, i.email, i.domain, i.reason,
+            u.username AS added_by, i.created_at
+        FROM inserted i
+        JOIN users u ON u.user_id = i.added_by
+        "#,
+        Uuid::new_v4(),
+        email,
+        domain,
+        body.reason.trim(),
+        user_id
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to add to the suppression list.")?
+    .ok_or_else(|| {
+        AdminError::ValidationError(format!(
+            "{} is already on the suppression list.",
+            email.or(domain).unwrap_or_default()
+        ))
+    })?;
+    Ok(HttpResponse::Created().json(suppression))
+}
+
+#[tracing::instrument(name = "Remove from the suppression list", skip(request, pool))]
+pub async fn remove_suppression(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    suppression_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AdminError> {
+    authenticate_admin(&request, &pool).await?;
+    let removed = sqlx::query!(
+        r#"DELETE FROM suppressions WHERE suppression_id = $1"#,
+        *suppression_id
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to remove from the suppression list.")?
+    .rows_affected();
+    if removed == 0 {
+        return Err(AdminError::NotFound(format!(
+            "There is no suppression with id {}.",
+            suppression_id
+        )));
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SuppressionData, SuppressionTarget};
+
+    fn target(email: Option<&str>, domain: Option<&str>) -> Result<SuppressionTarget, String> {
+        SuppressionTarget::try_from(&SuppressionData {
+            email: email.map(Into::into),
+            domain: domain.map(Into::into),
+            reason: "Legal request".into(),
+        })
+    }
+
+    #[test]
+    fn emails_are_normalised() {
+        let Ok(SuppressionTarget::Email(email)) = target(Some("Ursula@Example.com"), None) else {
+            panic!("The email was rejected");
+        };
+        assert_eq!(email, "ursula@example.com");
+    }
+
+    #[test]
+    fn domains_are_stored_as_lowercase_a_labels() {
+        let Ok(SuppressionTarget::Domain(domain)) = target(None, Some("Bücher.Example")) else {
+            panic!("The domain was rejected");
+        };
+        assert_eq!(domain, "xn--bcher-kva.example");
+    }
+
+    #[test]
+    fn exactly_one_target_is_required() {
+        assert!(target(None, None).is_err());
+        assert!(target(Some("ursula@example.com"), Some("example.com")).is_err());
+        assert!(target(None, Some("not a domain")).is_err());
+    }
+}
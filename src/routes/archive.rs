@@ -0,0 +1,133 @@
+use crate::archive::{ArchivePages, ArchivedIssue};
+use crate::routes::error_chain_fmt;
+use actix_web::{http::header::ContentType, web, HttpResponse, ResponseError};
+use anyhow::Context;
+use reqwest::StatusCode;
+use sqlx::PgPool;
+
+#[derive(thiserror::Error)]
+pub enum ArchiveError {
+    #[error("There is no archived issue at this address")]
+    NotFound,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for ArchiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ArchiveError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ArchiveError::NotFound => StatusCode::NOT_FOUND,
+            ArchiveError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[tracing::instrument(name = "Show the archive", skip(pool, archive))]
+pub async fn archive_index(
+    pool: web::Data<PgPool>,
+    archive: web::Data<ArchivePages>,
+) -> Result<HttpResponse, ArchiveError> {
+    let issues = get_archived_issues(&pool, None).await?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(archive.index(&issues)?))
+}
+
+#[tracing::instrument(name = "Show an archived issue", skip(pool, archive))]
+pub async fn archived_issue(
+    pool: web::Data<PgPool>,
+    archive: web::Data<ArchivePages>,
+    slug: web::Path<String>,
+) -> Result<HttpResponse, ArchiveError> {
+    let issue = sqlx::query_as!(
+        ArchivedIssue,
+        r#"
+        SELECT slug AS "slug!", title, html_content, published_at AS "published_at!"
+        FROM newsletter_issues
+        WHERE slug = $1 AND archived AND status = 'sent'
+        "#,
+        *slug
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to fetch the archived issue.")?
+    .ok_or(ArchiveError::NotFound)?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(archive.issue(&issue)?))
+}
+
+#[tracing::instrument(name = "Serve an inline image of an archived issue", skip(pool))]
+pub async fn archived_inline_image(
+    pool: web::Data<PgPool>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, ArchiveError> {
+    let (slug, content_id) = path.into_inner();
+    let image = sqlx::query!(
+        r#"
+        SELECT a.content_type, a.content
+        FROM newsletter_issue_attachments a
+        JOIN newsletter_issues i ON i.newsletter_issue_id = a.newsletter_issue_id
+        WHERE i.slug = $1 AND i.archived AND i.status = 'sent' AND a.content_id = $2
+        "#,
+        slug,
+        content_id
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to fetch the inline image.")?
+    .ok_or(ArchiveError::NotFound)?;
+    Ok(HttpResponse::Ok()
+        .content_type(image.content_type)
+        .body(image.content))
+}
+
+#[tracing::instrument(name = "Serve the Atom feed", skip(pool, archive))]
+pub async fn archive_atom_feed(
+    pool: web::Data<PgPool>,
+    archive: web::Data<ArchivePages>,
+) -> Result<HttpResponse, ArchiveError> {
+    let issues = get_archived_issues(&pool, Some(archive.feed_length.into())).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/atom+xml; charset=utf-8")
+        .body(archive.atom_feed(&issues)?))
+}
+
+#[tracing::instrument(name = "Serve the RSS feed", skip(pool, archive))]
+pub async fn archive_rss_feed(
+    pool: web::Data<PgPool>,
+    archive: web::Data<ArchivePages>,
+) -> Result<HttpResponse, ArchiveError> {
+    let issues = get_archived_issues(&pool, Some(archive.feed_length.into())).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/rss+xml; charset=utf-8")
+        .body(archive.rss_feed(&issues)?))
+}
+
+// The most recent first, every one of them when `limit` is `None`.
+// Issues that did not reach every subscriber stay out of the archive.
+async fn get_archived_issues(
+    pool: &PgPool,
+    limit: Option<i64>,
+) -> Result<Vec<ArchivedIssue>, anyhow::Error> {
+    sqlx::query_as!(
+        ArchivedIssue,
+        r#"
+        SELECT slug AS "slug!", title, html_content, published_at AS "published_at!"
+        FROM newsletter_issues
+        WHERE archived AND slug IS NOT NULL AND status = 'sent'
+        ORDER BY published_at DESC
+        LIMIT $1
+        "#,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to fetch the archived issues.")
+}
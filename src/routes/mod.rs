@@ -1,9 +1,21 @@
+mod admin;
+mod archive;
 mod health_check;
 mod newsletter;
+mod postmark_webhook;
 mod subscriptions;
 mod subscriptions_confirm;
+mod subscriptions_form_token;
+mod subscriptions_unsubscribe;
+mod tracking;
 
+pub use admin::*;
+pub use archive::*;
 pub use health_check::*;
 pub use newsletter::*;
+pub use postmark_webhook::*;
 pub use subscriptions::*;
 pub use subscriptions_confirm::*;
+pub use subscriptions_form_token::*;
+pub use subscriptions_unsubscribe::*;
+pub use tracking::*;
@@ -1,23 +1,39 @@
 use crate::{
-    domain::SubscriberEmail, email_client::EmailClient, routes::error_chain_fmt,
-    telemetry::spawn_blocking_with_tracing,
-};
-use actix_web::{
-    http::header::{self, HeaderMap},
-    web, HttpRequest, HttpResponse, ResponseError,
+    authentication::{basic_authentification, validate_credentials, AuthError},
+    email_client::{base64_len, is_valid_content_id, Attachment, EmailClient},
+    email_html,
+    email_templates::{EmailTemplates, MarkdownLayoutContext},
+    issue_delivery::{deliver_issue, insert_newsletter_issue},
+    markdown, plain_text,
+    routes::error_chain_fmt,
+    startup::ApplicationBaseUrl,
+    tracking::TrackingLinks,
+    unsubscribe::UnsubscribeLinks,
 };
+use actix_multipart::Multipart;
+use actix_web::{http::header, web, HttpRequest, HttpResponse, ResponseError};
 use anyhow::Context;
-use argon2::{Argon2, PasswordHash, PasswordVerifier};
-use base64::Engine;
+use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
 use reqwest::{header::HeaderValue, StatusCode};
-use secrecy::{ExposeSecret, Secret};
 use sqlx::PgPool;
 use uuid::Uuid;
 
+// Same as the default limit of the JSON extractor
+const MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+// Postmark accepts up to 10 MB per email, attachments included once encoded in base64.
+// What the body may take is kept for them.
+const MAX_ENCODED_ATTACHMENT_BYTES: usize = 10 * 1000 * 1000 - MAX_BODY_BYTES;
+const MAX_ATTACHMENTS: usize = 20;
+
 #[derive(thiserror::Error)]
 pub enum PublishError {
     #[error("Authentification failed")]
     AuthError(#[source] anyhow::Error),
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("{0}")]
+    PayloadTooLarge(String),
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
 }
@@ -34,6 +50,12 @@ impl ResponseError for PublishError {
             PublishError::UnexpectedError(_) => {
                 HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
             }
+            PublishError::ValidationError(message) => {
+                HttpResponse::BadRequest().body(message.clone())
+            }
+            PublishError::PayloadTooLarge(message) => {
+                HttpResponse::PayloadTooLarge().body(message.clone())
+            }
             PublishError::AuthError(_) => {
                 let mut response = HttpResponse::new(StatusCode::UNAUTHORIZED);
                 let header_value = HeaderValue::from_str(r#"Basic realm="publish""#).unwrap();
@@ -50,173 +72,330 @@ impl ResponseError for PublishError {
 pub struct BodyData {
     title: String,
     content: Content,
+    // Publish right away when missing; the offset is required, e.g. `2023-10-01T09:00:00+02:00`
+    send_at: Option<DateTime<Utc>>,
+    // Open and click tracking is on unless turned off here
+    tracking: Option<bool>,
+    // Name of the sender identity, the one configured for newsletters when missing
+    sender: Option<String>,
+    // The issue appears in the public archive and its feeds unless turned off here
+    archive: Option<bool>,
 }
 
+// Either `markdown`, or `html` with an optional `text`
 #[derive(serde::Deserialize)]
 pub struct Content {
-    html: String,
-    text: String,
+    markdown: Option<String>,
+    html: Option<String>,
+    // Derived from `html` when missing or blank
+    text: Option<String>,
+}
+
+impl Content {
+    /// Returns the HTML and plain-text bodies of the issue, with the HTML ready for
+    /// email clients. Drafts go through here as well, so that they preview as published.
+    pub fn bodies(
+        &self,
+        templates: &EmailTemplates,
+        base_url: &str,
+        title: &str,
+    ) -> Result<(String, String), PublishError> {
+        let (html, text) = match (&self.markdown, &self.html, &self.text) {
+            (Some(source), None, None) => {
+                let html = templates.markdown_layout(&MarkdownLayoutContext {
+                    title,
+                    content: &markdown::to_html(source),
+                })?;
+                (html, None)
+            }
+            (None, Some(html), text) => {
+                let text = text.as_ref().filter(|text| !text.trim().is_empty());
+                (html.clone(), text.cloned())
+            }
+            _ => {
+                return Err(PublishError::ValidationError(
+                    "content must have either `markdown`, or `html` and an optional `text`.".into(),
+                ))
+            }
+        };
+        let html = email_html::prepare(&html, base_url)?;
+        let text = match text {
+            Some(text) => text,
+            None => plain_text::from_html(&html)?,
+        };
+        Ok((html, text))
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct ScheduledIssue {
+    newsletter_issue_id: Uuid,
+    send_at: DateTime<Utc>,
+    warnings: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct PublishedIssue {
+    newsletter_issue_id: Uuid,
+    // Problems the editor may want to fix in the next issue, e.g. Gmail clipping the email
+    warnings: Vec<String>,
 }
 
 #[tracing::instrument(
     name = "Publish a newsletter issue",
-    skip(body, pool, email_client, request),
+    skip(
+        body,
+        pool,
+        email_client,
+        templates,
+        unsubscribe_links,
+        tracking_links,
+        base_url,
+        request
+    ),
     fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
 )]
+#[allow(clippy::too_many_arguments)]
 pub async fn publish_newsletter(
     body: web::Json<BodyData>,
     pool: web::Data<PgPool>,
     email_client: web::Data<EmailClient>,
+    templates: web::Data<EmailTemplates>,
+    unsubscribe_links: web::Data<UnsubscribeLinks>,
+    tracking_links: web::Data<TrackingLinks>,
+    base_url: web::Data<ApplicationBaseUrl>,
     request: HttpRequest,
 ) -> Result<HttpResponse, PublishError> {
-    let credentials = basic_authentification(request.headers()).map_err(PublishError::AuthError)?;
-    tracing::Span::current().record("username", &tracing::field::display(&credentials.username));
-    let user_id = validate_credentials(credentials, &pool).await?;
-    tracing::Span::current().record("user_id", &tracing::field::display(&user_id));
-    let subscribers = get_confirmed_subscribers(&pool).await?;
-    for subscriber in subscribers {
-        match subscriber {
-            Ok(subscriber) => {
-                email_client
-                    .send_email(
-                        &subscriber.email,
-                        &body.title,
-                        &body.content.html,
-                        &body.content.text,
-                    )
-                    .await
-                    .with_context(|| {
-                        format!("Failed to send newsletter issue to {}", subscriber.email)
-                    })?;
-            }
-            Err(error) => {
-                tracing::warn!(
-                    // The error chain is recorded as a structured field on the log record.
-                    error.cause_chain = ?error,
-                    "Skipping a confirmed subscriber. \
-                    Their stored contact details are invalid",
-                )
-            }
-        }
-    }
-    Ok(HttpResponse::Ok().finish())
-}
-
-#[allow(dead_code)]
-struct Credentials {
-    username: String,
-    password: Secret<String>,
-}
-
-fn basic_authentification(headers: &HeaderMap) -> Result<Credentials, anyhow::Error> {
-    // Header Value must be valid UTF8 string
-    let header_value = headers
-        .get("Authorization")
-        .context("The 'Authorization' header was missing")?
-        .to_str()
-        .context("The 'Authorization' header was not a valid UTF8 string.")?;
-    let base64encoded_segment = header_value
-        .strip_prefix("Basic ")
-        .context("The authorization scheme was not 'Basic'.")?;
-    let decoded_bytes = base64::engine::general_purpose::STANDARD
-        .decode(base64encoded_segment)
-        .context("Failed to base64-decode 'Basic' credentials.")?;
-    let decoded_credentials = String::from_utf8(decoded_bytes)
-        .context("The decoded credential string is not valid UTF8.")?;
-    let mut credentials = decoded_credentials.splitn(2, ':');
-    let username = credentials
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("A username must be provided in 'Basic' auth."))?
-        .to_string();
-    let password = credentials
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("A password must be provided in 'Basic' auth."))?
-        .to_string();
-
-    Ok(Credentials {
-        username,
-        password: Secret::new(password),
-    })
-}
-
-#[tracing::instrument(name = "Validate user credentials", skip(creds, pool))]
-async fn validate_credentials(
-    creds: Credentials,
-    pool: &PgPool,
-) -> Result<uuid::Uuid, PublishError> {
-    let (user_id, expected_password_hash) = get_stored_credentials(&creds.username, pool)
-        .await
-        .map_err(PublishError::UnexpectedError)?
-        .ok_or_else(|| PublishError::AuthError(anyhow::anyhow!("Unknown username")))?;
-
-    spawn_blocking_with_tracing(move || {
-        validate_password_hash(expected_password_hash, creds.password)
-    })
+    authenticate_publisher(&request, &pool).await?;
+    publish(
+        body.into_inner(),
+        &[],
+        &pool,
+        &email_client,
+        &templates,
+        &unsubscribe_links,
+        &tracking_links,
+        &base_url.0,
+    )
     .await
-    .context("Failed to spawn blocking task.")
-    .map_err(PublishError::UnexpectedError)??;
-
-    Ok(user_id)
 }
 
-#[tracing::instrument(name = "Get stored credentials", skip(pool, username))]
-async fn get_stored_credentials(
-    username: &str,
-    pool: &PgPool,
-) -> Result<Option<(Uuid, Secret<String>)>, anyhow::Error> {
-    let row = sqlx::query!(
-        r#"SELECT user_id, password_hash FROM users WHERE username = $1"#,
-        username
+/// Same as `publish_newsletter`, for `multipart/form-data` requests carrying files.
+/// The issue itself is sent as JSON in the `body` part. Files in `attachments` parts are
+/// attached to the email, files in `inline` parts are embedded and referenced from the
+/// HTML as `cid:<file name>`.
+#[tracing::instrument(
+    name = "Publish a newsletter issue with attachments",
+    skip(
+        payload,
+        pool,
+        email_client,
+        templates,
+        unsubscribe_links,
+        tracking_links,
+        base_url,
+        request
+    ),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn publish_newsletter_with_attachments(
+    payload: Multipart,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    templates: web::Data<EmailTemplates>,
+    unsubscribe_links: web::Data<UnsubscribeLinks>,
+    tracking_links: web::Data<TrackingLinks>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    request: HttpRequest,
+) -> Result<HttpResponse, PublishError> {
+    authenticate_publisher(&request, &pool).await?;
+    let (body, attachments) = read_multipart(payload).await?;
+    publish(
+        body,
+        &attachments,
+        &pool,
+        &email_client,
+        &templates,
+        &unsubscribe_links,
+        &tracking_links,
+        &base_url.0,
     )
-    .fetch_optional(pool)
     .await
-    .context("Failed to perform query to retrieve stored username and password")?
-    .map(|row| (row.user_id, Secret::new(row.password_hash)));
-
-    Ok(row)
 }
 
-#[tracing::instrument(
-    name = "Verify password hash",
-    skip(expected_password_hash, password_candidate)
-)]
-fn validate_password_hash(
-    expected_password_hash: Secret<String>,
-    password_candidate: Secret<String>,
-) -> Result<(), PublishError> {
-    let expected_password_hash = PasswordHash::new(expected_password_hash.expose_secret())
-        .context("Failed to parse hash in PHC string format.")
-        .map_err(PublishError::UnexpectedError)?;
+// Records the publisher on the span of the calling handler
+async fn authenticate_publisher(request: &HttpRequest, pool: &PgPool) -> Result<(), PublishError> {
+    let credentials = basic_authentification(request.headers()).map_err(PublishError::AuthError)?;
+    tracing::Span::current().record("username", tracing::field::display(&credentials.username));
+    let user_id = validate_credentials(credentials, pool)
+        .await
+        .map_err(|e| match e {
+            AuthError::InvalidCredentials(_) => PublishError::AuthError(e.into()),
+            AuthError::UnexpectedError(_) => PublishError::UnexpectedError(e.into()),
+        })?;
+    tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+    Ok(())
+}
 
-    Argon2::default()
-        .verify_password(
-            password_candidate.expose_secret().as_bytes(),
-            &expected_password_hash,
-        )
-        .context("Invalid password")
-        .map_err(PublishError::AuthError)
+async fn read_multipart(
+    mut payload: Multipart,
+) -> Result<(BodyData, Vec<Attachment>), PublishError> {
+    let mut body = None;
+    let mut attachments = Vec::new();
+    let mut encoded_attachment_bytes = 0;
+    let mut parts = 0;
+    while let Some(mut field) = payload.try_next().await.map_err(invalid_multipart)? {
+        parts += 1;
+        // The body, then the files
+        if parts > MAX_ATTACHMENTS + 1 {
+            return Err(PublishError::ValidationError(format!(
+                "At most {} files can be attached.",
+                MAX_ATTACHMENTS
+            )));
+        }
+        let name = field.name().unwrap_or_default().to_owned();
+        let file_name = field
+            .content_disposition()
+            .and_then(|disposition| disposition.get_filename())
+            .map(ToOwned::to_owned);
+        let content_type = field
+            .content_type()
+            .map(|mime| mime.essence_str().to_owned())
+            .unwrap_or_else(|| "application/octet-stream".into());
+        let mut content = Vec::new();
+        while let Some(chunk) = field.try_next().await.map_err(invalid_multipart)? {
+            let too_large = match name.as_str() {
+                "body" => content.len() + chunk.len() > MAX_BODY_BYTES,
+                _ => {
+                    encoded_attachment_bytes + base64_len(content.len() + chunk.len())
+                        > MAX_ENCODED_ATTACHMENT_BYTES
+                }
+            };
+            if too_large {
+                return Err(PublishError::PayloadTooLarge(format!(
+                    "The `body` must not exceed {} MiB, and attachments {} MB in total \
+                    once encoded in base64.",
+                    MAX_BODY_BYTES / 1024 / 1024,
+                    MAX_ENCODED_ATTACHMENT_BYTES / 1000 / 1000
+                )));
+            }
+            content.extend_from_slice(&chunk);
+        }
+        match (name.as_str(), file_name) {
+            ("body", _) => {
+                let data = serde_json::from_slice(&content).map_err(|e| {
+                    PublishError::ValidationError(format!("The `body` part is invalid: {}", e))
+                })?;
+                body = Some(data);
+            }
+            // Inline file names are their content id, referenced from the HTML
+            ("inline", Some(file_name)) if !is_valid_content_id(&file_name) => {
+                return Err(PublishError::ValidationError(format!(
+                    "Files in `inline` parts must be named with ASCII letters, digits, \
+                    `.`, `-` and `_` only, `{}` is not.",
+                    file_name
+                )))
+            }
+            ("attachments" | "inline", Some(file_name)) if !file_name.trim().is_empty() => {
+                encoded_attachment_bytes += base64_len(content.len());
+                attachments.push(Attachment {
+                    content_id: (name == "inline").then(|| file_name.clone()),
+                    name: file_name,
+                    content_type,
+                    content,
+                });
+            }
+            ("attachments" | "inline", _) => {
+                return Err(PublishError::ValidationError(format!(
+                    "Files in `{}` parts must have a file name.",
+                    name
+                )))
+            }
+            _ => {
+                return Err(PublishError::ValidationError(format!(
+                    "Unexpected part `{}`, expected `body`, `attachments` or `inline`.",
+                    name
+                )))
+            }
+        }
+    }
+    let body =
+        body.ok_or_else(|| PublishError::ValidationError("The `body` part is missing.".into()))?;
+    Ok((body, attachments))
 }
 
-struct ConfirmedSubscriber {
-    email: SubscriberEmail,
+fn invalid_multipart(e: actix_multipart::MultipartError) -> PublishError {
+    PublishError::ValidationError(format!("Invalid multipart payload: {}", e))
 }
 
-#[tracing::instrument(name = "Get confirmed subscribers", skip(pool))]
-async fn get_confirmed_subscribers(
+#[allow(clippy::too_many_arguments)]
+async fn publish(
+    body: BodyData,
+    attachments: &[Attachment],
     pool: &PgPool,
-) -> Result<Vec<Result<ConfirmedSubscriber, anyhow::Error>>, anyhow::Error> {
-    let confirmed_subscribers = sqlx::query!(
-        r#"SELECT email 
-        FROM subscriptions 
-        WHERE status = 'confirmed'"#,
+    email_client: &EmailClient,
+    templates: &EmailTemplates,
+    unsubscribe_links: &UnsubscribeLinks,
+    tracking_links: &TrackingLinks,
+    base_url: &str,
+) -> Result<HttpResponse, PublishError> {
+    if let Some(send_at) = body.send_at {
+        if send_at <= Utc::now() {
+            return Err(PublishError::ValidationError(
+                "send_at must be in the future.".into(),
+            ));
+        }
+    }
+    if let Some(sender) = &body.sender {
+        if !email_client.senders().contains(sender) {
+            return Err(PublishError::ValidationError(format!(
+                "There is no sender identity named {}.",
+                sender
+            )));
+        }
+    }
+    let (html_content, text_content) = body.content.bodies(templates, base_url, &body.title)?;
+    let warnings: Vec<String> = email_html::clipping_warning(&html_content)
+        .into_iter()
+        .collect();
+    for warning in &warnings {
+        tracing::warn!("{}", warning);
+    }
+    let issue = insert_newsletter_issue(
+        pool,
+        &body.title,
+        &text_content,
+        &html_content,
+        body.send_at,
+        body.tracking.unwrap_or(true),
+        body.sender.as_deref(),
+        body.archive.unwrap_or(true),
+        attachments,
     )
-    .fetch_all(pool)
-    .await?
-    .into_iter()
-    .map(|r| match SubscriberEmail::parse(r.email) {
-        Ok(email) => Ok(ConfirmedSubscriber { email }),
-        Err(error) => Err(anyhow::anyhow!(error)),
-    })
-    .collect();
-    Ok(confirmed_subscribers)
+    .await
+    .context("Failed to store the newsletter issue.")?;
+    match body.send_at {
+        // The scheduler takes it from here
+        Some(send_at) => Ok(HttpResponse::Accepted().json(ScheduledIssue {
+            newsletter_issue_id: issue.newsletter_issue_id,
+            send_at,
+            warnings,
+        })),
+        None => {
+            deliver_issue(
+                pool,
+                email_client,
+                templates,
+                unsubscribe_links,
+                tracking_links,
+                &issue,
+            )
+            .await?;
+            Ok(HttpResponse::Ok().json(PublishedIssue {
+                newsletter_issue_id: issue.newsletter_issue_id,
+                warnings,
+            }))
+        }
+    }
 }
@@ -0,0 +1,328 @@
+use crate::{
+    authentication::basic_authentification, configuration::PostmarkWebhookSettings,
+    domain::SubscriberEmail, routes::error_chain_fmt,
+};
+use actix_web::{http::header, web, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use reqwest::{header::HeaderValue, StatusCode};
+use secrecy::ExposeSecret;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(thiserror::Error)]
+pub enum WebhookError {
+    #[error("Authentification failed")]
+    AuthError(#[source] anyhow::Error),
+    #[error("{0}")]
+    ValidationError(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for WebhookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for WebhookError {
+    fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
+        match self {
+            WebhookError::UnexpectedError(_) => {
+                HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            WebhookError::ValidationError(message) => {
+                HttpResponse::BadRequest().body(message.clone())
+            }
+            WebhookError::AuthError(_) => {
+                let mut response = HttpResponse::new(StatusCode::UNAUTHORIZED);
+                let header_value = HeaderValue::from_str(r#"Basic realm="webhooks""#).unwrap();
+                response
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, header_value);
+                response
+            }
+        }
+    }
+}
+
+// The fields we act upon, see https://postmarkapp.com/developer/webhooks/webhooks-overview
+#[derive(serde::Deserialize)]
+#[serde(tag = "RecordType")]
+enum PostmarkEvent {
+    #[serde(rename_all = "PascalCase")]
+    Bounce {
+        // e.g. 'HardBounce', 'SoftBounce' or 'Transient'
+        #[serde(rename = "Type")]
+        bounce_type: String,
+        email: String,
+        // Postmark stops sending to addresses it deactivated
+        #[serde(default)]
+        inactive: bool,
+        // Matches the ID returned when the email was sent
+        #[serde(rename = "MessageID")]
+        message_id: Option<String>,
+    },
+    #[serde(rename_all = "PascalCase")]
+    SpamComplaint { email: String },
+    #[serde(rename_all = "PascalCase")]
+    SubscriptionChange {
+        recipient: String,
+        suppress_sending: bool,
+        // 'HardBounce', 'SpamComplaint' or 'ManualSuppression'
+        suppression_reason: Option<String>,
+    },
+    // Deliveries, opens, clicks... are only recorded
+    #[serde(other)]
+    Other,
+}
+
+impl PostmarkEvent {
+    // Auto-responders, transient failures and the like do not mean the address is unusable
+    fn is_permanent_bounce(&self) -> bool {
+        matches!(
+            self,
+            PostmarkEvent::Bounce { bounce_type, inactive, .. }
+                if bounce_type == "HardBounce" || *inactive
+        )
+    }
+
+    fn email(&self) -> Option<&str> {
+        match self {
+            PostmarkEvent::Bounce { email, .. } | PostmarkEvent::SpamComplaint { email } => {
+                Some(email)
+            }
+            PostmarkEvent::SubscriptionChange { recipient, .. } => Some(recipient),
+            PostmarkEvent::Other => None,
+        }
+    }
+
+    /// The status the subscriber should move to, if any.
+    fn subscriber_status(&self) -> Option<&'static str> {
+        match self {
+            PostmarkEvent::Bounce { .. } if self.is_permanent_bounce() => Some("bounced"),
+            PostmarkEvent::SpamComplaint { .. } => Some("complained"),
+            PostmarkEvent::SubscriptionChange {
+                suppress_sending: true,
+                suppression_reason,
+                ..
+            } => match suppression_reason.as_deref() {
+                Some("HardBounce") => Some("bounced"),
+                Some("SpamComplaint") => Some("complained"),
+                _ => Some("unsubscribed"),
+            },
+            // Reactivations in Postmark do not resubscribe anyone: they have to sign up again
+            _ => None,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct RecordType {
+    #[serde(rename = "RecordType")]
+    record_type: String,
+}
+
+// Postmark retries any call that does not get a 2xx, so events we do not act upon
+// are still acknowledged.
+#[tracing::instrument(
+    name = "Handle a Postmark webhook",
+    skip(request, body, pool, settings),
+    fields(record_type = tracing::field::Empty)
+)]
+pub async fn handle_postmark_webhook(
+    request: HttpRequest,
+    body: web::Bytes,
+    pool: web::Data<PgPool>,
+    settings: web::Data<PostmarkWebhookSettings>,
+) -> Result<HttpResponse, WebhookError> {
+    authenticate_webhook(&request, &settings)?;
+    let payload = std::str::from_utf8(&body)
+        .map_err(|_| WebhookError::ValidationError("The payload is not valid UTF-8.".into()))?;
+    let RecordType { record_type } = serde_json::from_str(payload)
+        .map_err(|e| WebhookError::ValidationError(format!("Invalid payload: {}", e)))?;
+    tracing::Span::current().record("record_type", tracing::field::display(&record_type));
+    let event: PostmarkEvent = serde_json::from_str(payload)
+        .map_err(|e| WebhookError::ValidationError(format!("Invalid payload: {}", e)))?;
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    sqlx::query!(
+        r#"
+        INSERT INTO email_provider_events (
+            email_provider_event_id, record_type, email, payload, received_at
+        )
+        VALUES ($1, $2, $3, $4, now())
+        "#,
+        Uuid::new_v4(),
+        record_type,
+        event.email(),
+        payload
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to store the webhook event.")?;
+    if let (Some(email), Some(status)) = (event.email(), event.subscriber_status()) {
+        update_subscriber_status(&mut transaction, email, status).await?;
+    }
+    if let PostmarkEvent::Bounce {
+        bounce_type,
+        message_id: Some(message_id),
+        ..
+    } = &event
+    {
+        record_delivery_bounce(
+            &mut transaction,
+            message_id,
+            bounce_type,
+            event.is_permanent_bounce(),
+        )
+        .await?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the SQL transaction to store a webhook event.")?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn update_subscriber_status(
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    email: &str,
+    status: &str,
+) -> Result<(), WebhookError> {
+    let email_normalised = match SubscriberEmail::parse(email.into()) {
+        Ok(email) => email.normalised(),
+        Err(_) => {
+            tracing::warn!("Ignoring a webhook event for an invalid email address");
+            return Ok(());
+        }
+    };
+    let updated = sqlx::query!(
+        r#"UPDATE subscriptions SET status = $2 WHERE email_normalised = $1"#,
+        email_normalised,
+        status
+    )
+    .execute(&mut **transaction)
+    .await
+    .context("Failed to update the subscriber's status.")?
+    .rows_affected();
+    if updated == 0 {
+        tracing::info!("The webhook event is not about one of our subscribers");
+    } else {
+        tracing::info!("Marked a subscriber as {}", status);
+    }
+    Ok(())
+}
+
+// Only permanent bounces count as undelivered, the other types are kept as the last error
+async fn record_delivery_bounce(
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    message_id: &str,
+    bounce_type: &str,
+    permanent: bool,
+) -> Result<(), WebhookError> {
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issue_deliveries
+        SET status = CASE WHEN $3 THEN 'bounced' ELSE status END,
+            last_error = $2,
+            updated_at = now()
+        WHERE provider_message_id = $1
+        "#,
+        message_id,
+        bounce_type,
+        permanent
+    )
+    .execute(&mut **transaction)
+    .await
+    .context("Failed to record a bounce of a newsletter delivery.")?;
+    Ok(())
+}
+
+fn authenticate_webhook(
+    request: &HttpRequest,
+    settings: &PostmarkWebhookSettings,
+) -> Result<(), WebhookError> {
+    let credentials = basic_authentification(request.headers()).map_err(WebhookError::AuthError)?;
+    let username_matches = constant_time_eq(&credentials.username, &settings.username);
+    let password_matches = constant_time_eq(
+        credentials.password.expose_secret(),
+        settings.password.expose_secret(),
+    );
+    if !(username_matches && password_matches) {
+        return Err(WebhookError::AuthError(anyhow::anyhow!(
+            "Invalid webhook credentials."
+        )));
+    }
+    Ok(())
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |difference, (x, y)| difference | (x ^ y))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PostmarkEvent;
+
+    fn parse(payload: serde_json::Value) -> PostmarkEvent {
+        serde_json::from_value(payload).unwrap()
+    }
+
+    #[test]
+    fn hard_bounces_and_deactivated_addresses_are_bounced() {
+        let hard = parse(serde_json::json!({
+            "RecordType": "Bounce", "Type": "HardBounce", "Email": "a@example.com", "Inactive": true
+        }));
+        let deactivated = parse(serde_json::json!({
+            "RecordType": "Bounce", "Type": "SpamNotification", "Email": "a@example.com", "Inactive": true
+        }));
+        let soft = parse(serde_json::json!({
+            "RecordType": "Bounce", "Type": "SoftBounce", "Email": "a@example.com", "Inactive": false
+        }));
+        assert_eq!(hard.subscriber_status(), Some("bounced"));
+        assert_eq!(deactivated.subscriber_status(), Some("bounced"));
+        assert_eq!(soft.subscriber_status(), None);
+    }
+
+    #[test]
+    fn subscription_changes_follow_the_suppression_reason() {
+        let cases = [
+            ("HardBounce", Some("bounced")),
+            ("SpamComplaint", Some("complained")),
+            ("ManualSuppression", Some("unsubscribed")),
+        ];
+        for (reason, status) in cases {
+            let event = parse(serde_json::json!({
+                "RecordType": "SubscriptionChange",
+                "Recipient": "a@example.com",
+                "SuppressSending": true,
+                "SuppressionReason": reason
+            }));
+            assert_eq!(event.subscriber_status(), status);
+        }
+        let reactivation = parse(serde_json::json!({
+            "RecordType": "SubscriptionChange",
+            "Recipient": "a@example.com",
+            "SuppressSending": false,
+            "SuppressionReason": null
+        }));
+        assert_eq!(reactivation.subscriber_status(), None);
+    }
+
+    #[test]
+    fn other_record_types_are_accepted() {
+        let event = parse(serde_json::json!({
+            "RecordType": "Delivery", "Recipient": "a@example.com"
+        }));
+        assert!(event.email().is_none());
+        assert!(event.subscriber_status().is_none());
+    }
+}
@@ -1,19 +1,29 @@
 use crate::{
+    bot_protection::{burn_form_token, BotProtection},
+    configuration::EmailOutboxSettings,
     domain::{NewSubscriber, SubscriberEmail, SubscriberName},
-    email_client::EmailClient,
+    email_client::{EmailClient, EmailKind},
+    email_outbox::{enqueue_email, relay_email},
+    email_templates::{ConfirmationContext, EmailTemplates},
+    rate_limiting::{RateLimitDecision, RateLimiter},
+    signup_policy::{SignupPolicy, SignupPolicyStore},
     startup::ApplicationBaseUrl,
+    suppression::is_suppressed,
 };
-use actix_web::{web, HttpResponse, ResponseError};
+use actix_web::{http::header::ContentType, web, HttpRequest, HttpResponse, ResponseError};
 use anyhow::Context;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use reqwest::StatusCode;
 use sqlx::{postgres, PgPool, Postgres, Transaction};
+use tracing::Instrument;
 use uuid::Uuid;
 
 #[derive(thiserror::Error)]
 pub enum SubscribeError {
     #[error("{0}")]
     ValidationError(String),
+    #[error("Too many subscription attempts for this address.")]
+    RateLimited(RateLimitDecision),
     // Transparent delegates both `Display`'s and `source`'s implementation to the type wrapped by `UnexpectedError`.
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
@@ -29,32 +39,111 @@ impl ResponseError for SubscribeError {
     fn status_code(&self) -> reqwest::StatusCode {
         match self {
             SubscribeError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            SubscribeError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
             SubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            SubscribeError::RateLimited(decision) => decision.too_many_requests(),
+            _ => HttpResponse::build(self.status_code())
+                .insert_header(ContentType::plaintext())
+                .body(self.to_string()),
+        }
+    }
 }
 
 #[derive(serde::Deserialize)]
 pub struct FormData {
     email: String,
     name: String,
+    // Honeypot: hidden from humans by the form's CSS, so only bots fill it in
+    #[serde(default)]
+    website: String,
+    // Single-use signed timestamp issued by `/subscriptions/form_token` when the form was rendered
+    form_token: Option<String>,
+    // Accept the field names used by the hCaptcha and Turnstile widgets
+    #[serde(alias = "h-captcha-response", alias = "cf-turnstile-response")]
+    challenge_response: Option<String>,
 }
 
 #[tracing::instrument(
     name = "Adding a new subscriber",
-    skip(form, pool, email_client, base_url),
+    skip(form, pool, email_client, templates, base_url, signup_policy, bot_protection, rate_limiter, email_outbox, request),
     fields(
         subscriber_email = %form.email,
         subscriber_name = %form.name,
     )
 )]
+#[allow(clippy::too_many_arguments)]
 pub async fn subscribe(
     form: web::Form<FormData>,
     pool: web::Data<PgPool>,
     email_client: web::Data<EmailClient>,
+    templates: web::Data<EmailTemplates>,
     base_url: web::Data<ApplicationBaseUrl>,
+    signup_policy: web::Data<SignupPolicyStore>,
+    bot_protection: web::Data<BotProtection>,
+    rate_limiter: web::Data<RateLimiter>,
+    email_outbox: web::Data<EmailOutboxSettings>,
+    request: HttpRequest,
 ) -> Result<HttpResponse, SubscribeError> {
-    let new_subscriber = form.0.try_into().map_err(SubscribeError::ValidationError)?;
+    let mut form = form.into_inner();
+    if !form.website.is_empty() {
+        // Pretend everything went fine, so that bots do not learn about the trap.
+        tracing::warn!("Ignoring a subscription that filled in the honeypot field.");
+        return Ok(HttpResponse::Ok().finish());
+    }
+    let form_token = bot_protection
+        .form_tokens
+        .verify(form.form_token.as_deref().unwrap_or_default())
+        .map_err(|e| SubscribeError::ValidationError(e.to_string()))?;
+    let challenge_response = form.challenge_response.take();
+
+    let new_subscriber: NewSubscriber = (form, signup_policy.current().as_ref())
+        .try_into()
+        .map_err(SubscribeError::ValidationError)?;
+    if !email_client.can_deliver_to(&new_subscriber.email) {
+        return Err(SubscribeError::ValidationError(format!(
+            "We cannot deliver emails to {} yet.",
+            new_subscriber.email
+        )));
+    }
+    if let Some(challenge) = &bot_protection.challenge {
+        let response = challenge_response.ok_or_else(|| {
+            SubscribeError::ValidationError("Please complete the challenge.".into())
+        })?;
+        let remote_ip = request.peer_addr().map(|address| address.ip().to_string());
+        let passed = challenge
+            .verify(&response, remote_ip.as_deref())
+            .await
+            .context("Failed to verify the challenge response.")?;
+        if !passed {
+            return Err(SubscribeError::ValidationError(
+                "The challenge was not passed, please try again.".into(),
+            ));
+        }
+    }
+    // Burnt once the submission is valid, so that fixing a typo does not require a new form
+    burn_form_token(&pool, &form_token)
+        .await
+        .context("Failed to record the form token as used.")?
+        .map_err(|e| SubscribeError::ValidationError(e.to_string()))?;
+    // Counted once the submission looks legitimate, so rejected attempts do not use up the budget
+    if let Some(decision) = rate_limiter.check_email(&new_subscriber.email).await {
+        if !decision.allowed {
+            return Err(SubscribeError::RateLimited(decision));
+        }
+    }
+    if is_suppressed(pool.get_ref(), &new_subscriber.email)
+        .await
+        .context("Failed to check the suppression list.")?
+    {
+        // Answer as usual, so that the form does not reveal which addresses are suppressed.
+        tracing::warn!("Ignoring a subscription for a suppressed address.");
+        return Ok(HttpResponse::Ok().finish());
+    }
     let mut transaction = pool
         .begin()
         .await
@@ -68,7 +157,7 @@ pub async fn subscribe(
             transaction
                 .rollback()
                 .await.context("Failed to rollback SQL transaction if a subscriber tries subscribing more than once.")?;
-            let subscriber_id = get_subscriber_id_from_email(&pool, new_subscriber.email.as_ref())
+            let subscriber_id = get_subscriber_id_from_email(&pool, &new_subscriber.email)
                 .await
                 .unwrap();
             let transaction = pool
@@ -82,18 +171,37 @@ pub async fn subscribe(
     store_token(&mut transaction, subscriber_id, &subscription_token)
         .await
         .context("Failed to store the confirmation token for a new subscriber.")?;
-    transaction
-        .commit()
-        .await
-        .context("Failed to commit SQL transaction to store a new subscriber.")?;
-    send_confirmation_email(
-        &email_client,
+    // Queued in the same transaction, so that a stored subscriber always gets their email
+    let email_outbox_id = queue_confirmation_email(
+        &mut transaction,
+        &templates,
         new_subscriber,
         &base_url.0,
         &subscription_token,
     )
     .await
-    .context("Failed to send a confirmation email.")?;
+    .context("Failed to queue a confirmation email.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to store a new subscriber.")?;
+    // Send it right away, in the background so that the response does not wait for the
+    // provider. Whatever goes wrong is left to the relay worker to retry.
+    let pool = pool.get_ref().clone();
+    let (max_attempts, lease) = (email_outbox.max_attempts, email_outbox.lease());
+    tokio::spawn(
+        async move {
+            if let Err(e) =
+                relay_email(&pool, &email_client, email_outbox_id, max_attempts, lease).await
+            {
+                tracing::error!(
+                    error.cause_chain = ?e,
+                    "Failed to relay the confirmation email, leaving it in the outbox",
+                );
+            }
+        }
+        .instrument(tracing::Span::current()),
+    );
     Ok(HttpResponse::Ok().finish())
 }
 
@@ -109,11 +217,12 @@ pub async fn insert_subscriber(
     let subscriber_id = Uuid::new_v4();
     let query: Result<postgres::PgQueryResult, sqlx::Error> = sqlx::query!(
         r#"
-    INSERT INTO subscriptions (id, email, name, subscribed_at, status) 
-    VALUES ($1, $2, $3, $4, 'pending_confirmation')
+    INSERT INTO subscriptions (id, email, email_normalised, name, subscribed_at, status) 
+    VALUES ($1, $2, $3, $4, $5, 'pending_confirmation')
         "#,
         subscriber_id,
-        new_subscriber.email.as_ref(),
+        new_subscriber.email.original(),
+        new_subscriber.email.normalised(),
         new_subscriber.name.as_ref(),
         chrono::Utc::now()
     )
@@ -138,12 +247,12 @@ pub async fn insert_subscriber(
 
 pub async fn get_subscriber_id_from_email(
     pool: &PgPool,
-    subscriber_email: &str,
+    subscriber_email: &SubscriberEmail,
 ) -> Result<Uuid, sqlx::Error> {
     let result = sqlx::query!(
         "SELECT id FROM subscriptions \
-    WHERE email = $1",
-        subscriber_email
+    WHERE email_normalised = $1",
+        subscriber_email.normalised()
     )
     .fetch_one(pool)
     .await
@@ -209,39 +318,43 @@ fn generate_subscription_token() -> String {
 }
 
 #[tracing::instrument(
-    name = "Send a confirmation email to a new subscriber"
-    skip(email_client, new_subscriber, base_url, subscription_token)
+    name = "Queue a confirmation email for a new subscriber"
+    skip(transaction, templates, new_subscriber, base_url, subscription_token)
 )]
-pub async fn send_confirmation_email(
-    email_client: &EmailClient,
+pub async fn queue_confirmation_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    templates: &EmailTemplates,
     new_subscriber: NewSubscriber,
     base_url: &str,
     subscription_token: &str,
-) -> Result<(), reqwest::Error> {
+) -> Result<Uuid, anyhow::Error> {
     let confirmation_link = format!(
         "{}/subscriptions/confirm?subscription_token={}",
         base_url, subscription_token,
     );
-    let plain_body = format!(
-        "Welcome to our newsletter!\nVisit {} to confirm your subscription",
-        confirmation_link
-    );
-    let html_body = format!(
-        "Welcome to our newsletter!<br />\
-        Click <a href=\"{}\">here</a> to confirm your subscription.",
-        confirmation_link
-    );
-    email_client
-        .send_email(&new_subscriber.email, "Welcome!", &html_body, &plain_body)
-        .await
+    let email = templates.confirmation(&ConfirmationContext {
+        name: new_subscriber.name.as_ref(),
+        confirmation_link: &confirmation_link,
+    })?;
+    let email_outbox_id = enqueue_email(
+        &mut **transaction,
+        &new_subscriber.email,
+        &email,
+        EmailKind::Confirmation,
+        Some("confirmation"),
+    )
+    .await?;
+    Ok(email_outbox_id)
 }
 
-impl TryFrom<FormData> for NewSubscriber {
+// The signup policy is part of what makes an address acceptable, so it is checked while parsing.
+impl TryFrom<(FormData, &SignupPolicy)> for NewSubscriber {
     type Error = String;
 
-    fn try_from(value: FormData) -> Result<Self, Self::Error> {
+    fn try_from((value, signup_policy): (FormData, &SignupPolicy)) -> Result<Self, Self::Error> {
         let email = SubscriberEmail::parse(value.email)?;
         let name = SubscriberName::parse(value.name)?;
+        signup_policy.check(&email)?;
         Ok(Self { email, name })
     }
 }
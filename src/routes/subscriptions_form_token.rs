@@ -0,0 +1,14 @@
+use crate::bot_protection::BotProtection;
+use actix_web::{web, HttpResponse};
+
+#[derive(serde::Serialize)]
+struct FormToken {
+    form_token: String,
+}
+
+// Issue the single-use signed timestamp that the subscription form must send back as `form_token`.
+pub async fn issue_form_token(bot_protection: web::Data<BotProtection>) -> HttpResponse {
+    HttpResponse::Ok().json(FormToken {
+        form_token: bot_protection.form_tokens.issue(),
+    })
+}
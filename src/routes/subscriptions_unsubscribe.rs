@@ -0,0 +1,144 @@
+use crate::routes::error_chain_fmt;
+use crate::unsubscribe::{LinkPurpose, UnsubscribeLinks};
+use actix_web::{http::header::ContentType, web, HttpResponse, ResponseError};
+use anyhow::Context;
+use reqwest::StatusCode;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(thiserror::Error)]
+pub enum UnsubscribeError {
+    #[error("The unsubscribe link is invalid")]
+    InvalidLink,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for UnsubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for UnsubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            UnsubscribeError::InvalidLink => StatusCode::UNAUTHORIZED,
+            UnsubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct UnsubscribeParameters {
+    subscriber_id: Uuid,
+    token: String,
+}
+
+impl UnsubscribeParameters {
+    fn verify(
+        &self,
+        unsubscribe_links: &UnsubscribeLinks,
+        purpose: LinkPurpose,
+    ) -> Result<(), UnsubscribeError> {
+        if !unsubscribe_links.verify(purpose, self.subscriber_id, &self.token) {
+            return Err(UnsubscribeError::InvalidLink);
+        }
+        Ok(())
+    }
+}
+
+// Posts back to the link itself, which carries the subscriber id and the token
+fn confirmation_page(question: &str, action: &str) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>{action}</title>
+</head>
+<body>
+    <form method="post">
+        <p>{question}</p>
+        <button type="submit">{action}</button>
+    </form>
+</body>
+</html>"#
+        ))
+}
+
+/// Link checkers and prefetching mail clients follow links on their own,
+/// so opening the link only asks for a confirmation.
+#[tracing::instrument(
+    name = "Show the unsubscribe confirmation",
+    skip(parameters, unsubscribe_links)
+)]
+pub async fn unsubscribe_form(
+    parameters: web::Query<UnsubscribeParameters>,
+    unsubscribe_links: web::Data<UnsubscribeLinks>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    parameters.verify(&unsubscribe_links, LinkPurpose::Unsubscribe)?;
+    Ok(confirmation_page(
+        "Do you want to stop receiving our newsletter?",
+        "Unsubscribe",
+    ))
+}
+
+/// Submitted by the confirmation page, or directly by mail clients that support
+/// one-click unsubscription (RFC 8058), whatever the body.
+#[tracing::instrument(
+    name = "Unsubscribe a subscriber",
+    skip(parameters, pool, unsubscribe_links)
+)]
+pub async fn unsubscribe(
+    parameters: web::Query<UnsubscribeParameters>,
+    pool: web::Data<PgPool>,
+    unsubscribe_links: web::Data<UnsubscribeLinks>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    parameters.verify(&unsubscribe_links, LinkPurpose::Unsubscribe)?;
+    sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'unsubscribed' WHERE id = $1"#,
+        parameters.subscriber_id
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to update the subscriber's status to `unsubscribed`.")?;
+    Ok(HttpResponse::Ok().body("You have been unsubscribed."))
+}
+
+#[tracing::instrument(
+    name = "Show the tracking opt-out confirmation",
+    skip(parameters, unsubscribe_links)
+)]
+pub async fn tracking_opt_out_form(
+    parameters: web::Query<UnsubscribeParameters>,
+    unsubscribe_links: web::Data<UnsubscribeLinks>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    parameters.verify(&unsubscribe_links, LinkPurpose::TrackingOptOut)?;
+    Ok(confirmation_page(
+        "Do you want us to stop tracking when you open our emails and follow their links?",
+        "Opt out",
+    ))
+}
+
+#[tracing::instrument(
+    name = "Opt a subscriber out of tracking",
+    skip(parameters, pool, unsubscribe_links)
+)]
+pub async fn opt_out_of_tracking(
+    parameters: web::Query<UnsubscribeParameters>,
+    pool: web::Data<PgPool>,
+    unsubscribe_links: web::Data<UnsubscribeLinks>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    parameters.verify(&unsubscribe_links, LinkPurpose::TrackingOptOut)?;
+    sqlx::query!(
+        r#"UPDATE subscriptions SET tracking_opt_out = true WHERE id = $1"#,
+        parameters.subscriber_id
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to opt the subscriber out of tracking.")?;
+    Ok(HttpResponse::Ok().body("We will no longer track your opens and clicks."))
+}
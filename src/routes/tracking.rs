@@ -0,0 +1,92 @@
+use crate::tracking::{TrackingEvent, TrackingLinks};
+use actix_web::{http::header, web, HttpResponse};
+use anyhow::Context;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// The smallest transparent GIF: 1x1 pixel, one colour
+const PIXEL: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+// The pixel is served whatever happens: a broken image at the bottom of a newsletter
+// would be worse than a missing open.
+#[tracing::instrument(name = "Track an open", skip(token, pool, tracking_links))]
+pub async fn track_open(
+    token: web::Path<String>,
+    pool: web::Data<PgPool>,
+    tracking_links: web::Data<TrackingLinks>,
+) -> HttpResponse {
+    match tracking_links.decode(&token) {
+        Some(event @ TrackingEvent::Open { .. }) => record_event(&pool, &event).await,
+        _ => tracing::warn!("Received an invalid open tracking token"),
+    }
+    HttpResponse::Ok()
+        .content_type("image/gif")
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body(PIXEL)
+}
+
+#[tracing::instrument(name = "Track a click", skip(token, pool, tracking_links))]
+pub async fn track_click(
+    token: web::Path<String>,
+    pool: web::Data<PgPool>,
+    tracking_links: web::Data<TrackingLinks>,
+) -> HttpResponse {
+    let Some(event) = tracking_links.decode(&token) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let TrackingEvent::Click { url, .. } = &event else {
+        return HttpResponse::NotFound().finish();
+    };
+    record_event(&pool, &event).await;
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, url.as_str()))
+        .finish()
+}
+
+// Failing to record an event must not keep the reader from their link
+async fn record_event(pool: &PgPool, event: &TrackingEvent) {
+    if let Err(error) = insert_event(pool, event).await {
+        tracing::error!(
+            error.cause_chain = ?error,
+            "Failed to record a tracking event",
+        );
+    }
+}
+
+async fn insert_event(pool: &PgPool, event: &TrackingEvent) -> Result<(), anyhow::Error> {
+    let (newsletter_issue_id, subscriber_id, url) = match event {
+        TrackingEvent::Open {
+            newsletter_issue_id,
+            subscriber_id,
+        } => (newsletter_issue_id, subscriber_id, None),
+        TrackingEvent::Click {
+            newsletter_issue_id,
+            subscriber_id,
+            url,
+        } => (newsletter_issue_id, subscriber_id, Some(url)),
+    };
+    // Subscribers who opted out after receiving the issue are not recorded either
+    sqlx::query!(
+        r#"
+        INSERT INTO tracking_events (
+            tracking_event_id, newsletter_issue_id, subscriber_id, kind, url, occurred_at
+        )
+        SELECT $1, $2, id, $4, $5, now()
+        FROM subscriptions
+        WHERE id = $3 AND NOT tracking_opt_out
+        "#,
+        Uuid::new_v4(),
+        newsletter_issue_id,
+        subscriber_id,
+        event.kind(),
+        url
+    )
+    .execute(pool)
+    .await
+    .context("Failed to store a tracking event.")?;
+    Ok(())
+}
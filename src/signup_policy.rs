@@ -0,0 +1,233 @@
+use crate::{configuration::SignupPolicySettings, domain::SubscriberEmail};
+use anyhow::Context;
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+// Which addresses we accept on signup, based on their domain and local part.
+#[derive(Debug, Default, PartialEq)]
+pub struct SignupPolicy {
+    blocked_domains: HashSet<String>,
+    allowed_domains: HashSet<String>,
+    role_accounts: HashSet<String>,
+}
+
+impl SignupPolicy {
+    pub fn new(blocked_domains: &str, allowed_domains: &str, role_accounts: &str) -> Self {
+        Self {
+            blocked_domains: parse_list(blocked_domains, normalise_domain),
+            allowed_domains: parse_list(allowed_domains, normalise_domain),
+            role_accounts: parse_list(role_accounts, |s| Some(s.to_lowercase())),
+        }
+    }
+
+    /// Returns the message to show the subscriber if their address is not accepted.
+    pub fn check(&self, email: &SubscriberEmail) -> Result<(), String> {
+        // Ignore sub-addressing, so that `noreply+news@` is treated like `noreply@`
+        let local_part = email.local_part().to_lowercase();
+        let local_part = local_part.split('+').next().unwrap_or_default();
+        if self.role_accounts.contains(local_part) {
+            return Err(format!(
+                "Role addresses such as {}@ are not accepted, please subscribe with a personal address.",
+                local_part
+            ));
+        }
+        let domain = email.domain();
+        // Allowed domains take precedence over broader blocklist entries
+        if matches_domain(&self.allowed_domains, domain) {
+            return Ok(());
+        }
+        if matches_domain(&self.blocked_domains, domain) {
+            return Err(format!(
+                "Addresses at {} are not accepted, please subscribe with a permanent address.",
+                domain
+            ));
+        }
+        Ok(())
+    }
+}
+
+// One entry per line; blank lines and `#` comments are ignored.
+fn parse_list(contents: &str, normalise: impl Fn(&str) -> Option<String>) -> HashSet<String> {
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| {
+            let entry = normalise(line);
+            if entry.is_none() {
+                tracing::warn!("Ignoring invalid signup policy entry: {}", line);
+            }
+            entry
+        })
+        .collect()
+}
+
+fn normalise_domain(domain: &str) -> Option<String> {
+    idna::domain_to_ascii(domain.trim_end_matches('.')).ok()
+}
+
+// A domain matches an entry for itself or for any of its parent domains.
+fn matches_domain(domains: &HashSet<String>, domain: &str) -> bool {
+    let mut candidate = domain;
+    loop {
+        if domains.contains(candidate) {
+            return true;
+        }
+        match candidate.split_once('.') {
+            Some((_, parent)) => candidate = parent,
+            None => return false,
+        }
+    }
+}
+
+// Holds the current policy and swaps it for a fresh one when the list files change on disk.
+pub struct SignupPolicyStore {
+    settings: SignupPolicySettings,
+    current: RwLock<Arc<SignupPolicy>>,
+}
+
+impl SignupPolicyStore {
+    pub fn load(settings: SignupPolicySettings) -> Result<Self, anyhow::Error> {
+        let policy = read_policy(&settings)?;
+        Ok(Self {
+            settings,
+            current: RwLock::new(Arc::new(policy)),
+        })
+    }
+
+    pub fn current(&self) -> Arc<SignupPolicy> {
+        self.current.read().unwrap().clone()
+    }
+
+    #[tracing::instrument(name = "Reload signup policy", skip(self))]
+    pub fn reload(&self) -> Result<(), anyhow::Error> {
+        let policy = read_policy(&self.settings)?;
+        if *self.current() != policy {
+            tracing::info!("The signup policy lists have changed, applying them.");
+            *self.current.write().unwrap() = Arc::new(policy);
+        }
+        Ok(())
+    }
+
+    // Re-read the lists on a fixed interval, keeping the previous policy if they cannot be read.
+    pub async fn reload_periodically(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.settings.reload_interval());
+        // The first tick completes immediately, and the lists have just been loaded.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(error) = self.reload() {
+                tracing::error!(
+                    error.cause_chain = ?error,
+                    "Failed to reload the signup policy, keeping the previous one",
+                );
+            }
+        }
+    }
+}
+
+fn read_policy(settings: &SignupPolicySettings) -> Result<SignupPolicy, anyhow::Error> {
+    let read = |path: &str| {
+        std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read signup policy list at {}", path))
+    };
+    Ok(SignupPolicy::new(
+        &read(&settings.blocked_domains_path)?,
+        &read(&settings.allowed_domains_path)?,
+        &read(&settings.role_accounts_path)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SignupPolicy, SignupPolicyStore};
+    use crate::configuration::SignupPolicySettings;
+    use crate::domain::SubscriberEmail;
+    use claims::{assert_err, assert_ok};
+
+    fn email(s: &str) -> SubscriberEmail {
+        SubscriberEmail::parse(s.to_string()).unwrap()
+    }
+
+    fn policy() -> SignupPolicy {
+        SignupPolicy::new(
+            "# Disposable providers\nmailinator.com\nyopmail.com # trailing comment\n\n",
+            "staff.mailinator.com",
+            "noreply\npostmaster",
+        )
+    }
+
+    #[test]
+    fn regular_addresses_are_accepted() {
+        assert_ok!(policy().check(&email("sara_kuzoi@tuta.io")));
+    }
+
+    #[test]
+    fn blocked_domains_and_their_subdomains_are_rejected() {
+        assert_err!(policy().check(&email("ursula@mailinator.com")));
+        assert_err!(policy().check(&email("ursula@eu.Mailinator.com")));
+    }
+
+    #[test]
+    fn allowed_domains_override_the_blocklist() {
+        assert_ok!(policy().check(&email("ursula@staff.mailinator.com")));
+    }
+
+    #[test]
+    fn role_accounts_are_rejected_regardless_of_case_and_tags() {
+        assert_err!(policy().check(&email("PostMaster@tuta.io")));
+        assert_err!(policy().check(&email("noreply+news@tuta.io")));
+    }
+
+    #[test]
+    fn role_accounts_are_rejected_even_on_allowed_domains() {
+        assert_err!(policy().check(&email("noreply@staff.mailinator.com")));
+    }
+
+    #[test]
+    fn the_store_picks_up_changes_on_reload() {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = |name: &str| directory.join(name).to_str().unwrap().to_string();
+        std::fs::write(path("blocked"), "").unwrap();
+        std::fs::write(path("allowed"), "").unwrap();
+        std::fs::write(path("roles"), "").unwrap();
+        let store = SignupPolicyStore::load(SignupPolicySettings {
+            blocked_domains_path: path("blocked"),
+            allowed_domains_path: path("allowed"),
+            role_accounts_path: path("roles"),
+            reload_interval_seconds: 60,
+        })
+        .unwrap();
+        assert_ok!(store.current().check(&email("ursula@yopmail.com")));
+
+        std::fs::write(path("blocked"), "yopmail.com").unwrap();
+        store.reload().unwrap();
+
+        assert_err!(store.current().check(&email("ursula@yopmail.com")));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn a_failed_reload_keeps_the_previous_policy() {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = |name: &str| directory.join(name).to_str().unwrap().to_string();
+        std::fs::write(path("blocked"), "yopmail.com").unwrap();
+        std::fs::write(path("allowed"), "").unwrap();
+        std::fs::write(path("roles"), "").unwrap();
+        let store = SignupPolicyStore::load(SignupPolicySettings {
+            blocked_domains_path: path("blocked"),
+            allowed_domains_path: path("allowed"),
+            role_accounts_path: path("roles"),
+            reload_interval_seconds: 60,
+        })
+        .unwrap();
+
+        std::fs::remove_file(path("blocked")).unwrap();
+        assert_err!(store.reload());
+
+        assert_err!(store.current().check(&email("ursula@yopmail.com")));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
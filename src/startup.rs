@@ -1,12 +1,37 @@
-use crate::configuration::{DatabaseSettings, Settings};
+use crate::archive::ArchivePages;
+use crate::bot_protection::BotProtection;
+use crate::configuration::{
+    DatabaseSettings, EmailOutboxSettings, PostmarkWebhookSettings, RateLimitStoreKind, Settings,
+};
 use crate::email_client::EmailClient;
-use crate::routes::{confirm, health_check, publish_newsletter, subscribe};
+use crate::email_outbox::run_outbox_relay_until_stopped;
+use crate::email_templates::EmailTemplates;
+use crate::issue_delivery::run_scheduler_until_stopped;
+use crate::rate_limiting::{
+    delete_full_buckets_periodically, delete_full_in_memory_buckets_periodically, InMemoryBuckets,
+    RateLimit, RateLimitStore, RateLimiter,
+};
+use crate::routes::{
+    add_suppression, archive_atom_feed, archive_index, archive_rss_feed, archived_inline_image,
+    archived_issue, cancel_scheduled_issue, confirm, create_draft, export_subscribers, get_draft,
+    get_metrics, handle_postmark_webhook, health_check, issue_engagement, issue_form_token,
+    list_issue_deliveries, list_scheduled_issues, list_subscribers, list_suppressions,
+    opt_out_of_tracking, preview_draft, publish_draft, publish_newsletter,
+    publish_newsletter_with_attachments, remove_suppression, reschedule_issue, send_test_draft,
+    subscribe, track_click, track_open, tracking_opt_out_form, unsubscribe, unsubscribe_form,
+    update_draft,
+};
+use crate::signup_policy::SignupPolicyStore;
+use crate::tracking::TrackingLinks;
+use crate::unsubscribe::UnsubscribeLinks;
 use actix_web::dev::Server;
+use actix_web::guard::GuardContext;
 use actix_web::web::Data;
-use actix_web::{web, App, HttpServer};
+use actix_web::{guard, web, App, HttpServer};
 use sqlx::postgres::PgPoolOptions;
 use sqlx::PgPool;
 use std::net::TcpListener;
+use std::sync::Arc;
 use tracing_actix_web::TracingLogger;
 
 // A new type to hold the newly built server and its port
@@ -20,17 +45,87 @@ impl Application {
         // No longer async, given that we no longer try to connect, for Docker
         let connection_pool = get_connection_pool(&configuration.database);
 
-        let sender_email = configuration
+        let senders = configuration
             .email_client
-            .sender()
-            .expect("Invalid sender email address");
+            .senders()
+            .expect("Invalid sender identities");
         let timeout = configuration.email_client.timeout();
-        let email_client = EmailClient::new(
-            configuration.email_client.base_url,
-            sender_email,
-            configuration.email_client.authorization_token,
+        let throttle = configuration
+            .email_client
+            .throttle()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let email_client = Arc::new(EmailClient::new(
+            configuration.email_client.primary_backend(),
+            configuration.email_client.secondary_backend(),
+            senders,
             timeout,
+            configuration.email_client.supports_smtputf8,
+            throttle,
+        ));
+        let templates = Arc::new(
+            EmailTemplates::load(&configuration.email_templates.directory, &connection_pool)
+                .await
+                .expect("Failed to load the email templates"),
+        );
+        let unsubscribe_links = Arc::new(UnsubscribeLinks::new(
+            configuration.application.base_url.clone(),
+            configuration.application.hmac_secret.clone(),
+        ));
+        let tracking_links = Arc::new(TrackingLinks::new(
+            configuration.application.base_url.clone(),
+            configuration.application.hmac_secret,
+        ));
+        tokio::spawn(run_scheduler_until_stopped(
+            connection_pool.clone(),
+            email_client.clone(),
+            templates.clone(),
+            unsubscribe_links.clone(),
+            tracking_links.clone(),
+            configuration.newsletter_scheduler.poll_interval(),
+            configuration.newsletter_scheduler.lease(),
+        ));
+        tokio::spawn(run_outbox_relay_until_stopped(
+            connection_pool.clone(),
+            email_client.clone(),
+            configuration.email_outbox.poll_interval(),
+            configuration.email_outbox.max_attempts,
+            configuration.email_outbox.lease(),
+        ));
+
+        let signup_policy = Arc::new(
+            SignupPolicyStore::load(configuration.signup_policy)
+                .expect("Failed to load the signup policy lists"),
         );
+        tokio::spawn(signup_policy.clone().reload_periodically());
+        let bot_protection = BotProtection {
+            form_tokens: configuration.bot_protection.form_token_signer(),
+            challenge: configuration.bot_protection.challenge_verifier(),
+        };
+
+        let rate_limit_store = match configuration.rate_limit.store {
+            RateLimitStoreKind::InMemory => {
+                let buckets = Arc::new(InMemoryBuckets::default());
+                tokio::spawn(delete_full_in_memory_buckets_periodically(
+                    buckets.clone(),
+                    configuration.rate_limit.cleanup_interval(),
+                ));
+                RateLimitStore::InMemory(buckets)
+            }
+            RateLimitStoreKind::Postgres => {
+                tokio::spawn(delete_full_buckets_periodically(
+                    connection_pool.clone(),
+                    configuration.rate_limit.cleanup_interval(),
+                ));
+                RateLimitStore::Postgres(connection_pool.clone())
+            }
+        };
+        let rate_limiter = RateLimiter::new(rate_limit_store, configuration.rate_limit);
+        let archive = ArchivePages::new(
+            configuration.archive.title,
+            configuration.application.base_url.clone(),
+            configuration.archive.feed_length,
+        )
+        .expect("Failed to load the archive templates");
 
         let address = format!(
             "{}:{}",
@@ -42,7 +137,16 @@ impl Application {
             listener,
             connection_pool,
             email_client,
+            templates,
+            unsubscribe_links,
+            tracking_links,
             configuration.application.base_url,
+            signup_policy,
+            bot_protection,
+            rate_limiter,
+            configuration.postmark_webhook,
+            configuration.email_outbox,
+            archive,
         )?;
 
         Ok(Self { port, server })
@@ -66,27 +170,179 @@ pub fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
 
 pub struct ApplicationBaseUrl(pub String);
 
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     listener: TcpListener,
     db_pool: PgPool,
-    email_client: EmailClient,
+    email_client: Arc<EmailClient>,
+    templates: Arc<EmailTemplates>,
+    unsubscribe_links: Arc<UnsubscribeLinks>,
+    tracking_links: Arc<TrackingLinks>,
     base_url: String,
+    signup_policy: Arc<SignupPolicyStore>,
+    bot_protection: BotProtection,
+    rate_limiter: RateLimiter,
+    postmark_webhook: PostmarkWebhookSettings,
+    email_outbox: EmailOutboxSettings,
+    archive: ArchivePages,
 ) -> Result<Server, std::io::Error> {
     let db_pool = Data::new(db_pool);
-    let email_client = Data::new(email_client);
+    let email_client = Data::from(email_client);
+    let templates = Data::from(templates);
+    let unsubscribe_links = Data::from(unsubscribe_links);
+    let tracking_links = Data::from(tracking_links);
     let base_url = Data::new(ApplicationBaseUrl(base_url));
+    let signup_policy = Data::from(signup_policy);
+    let bot_protection = Data::new(bot_protection);
+    let rate_limiter = Data::new(rate_limiter);
+    let postmark_webhook = Data::new(postmark_webhook);
+    let email_outbox = Data::new(email_outbox);
+    let archive = Data::new(archive);
     let server = HttpServer::new(move || {
+        let limits = rate_limiter.settings();
         App::new()
             .wrap(TracingLogger::default())
             .route("/health_check", web::get().to(health_check))
-            .route("/subscriptions", web::post().to(subscribe))
-            .route("/subscriptions/confirm", web::get().to(confirm))
-            .route("/newsletter", web::post().to(publish_newsletter))
+            .service(
+                web::resource("/subscriptions")
+                    .wrap(RateLimit::new(
+                        rate_limiter.clone(),
+                        "subscriptions",
+                        limits.subscriptions.clone(),
+                    ))
+                    .route(web::post().to(subscribe)),
+            )
+            .service(
+                web::resource("/subscriptions/confirm")
+                    .wrap(RateLimit::new(
+                        rate_limiter.clone(),
+                        "subscriptions_confirm",
+                        limits.subscriptions_confirm.clone(),
+                    ))
+                    .route(web::get().to(confirm)),
+            )
+            .service(
+                web::resource("/subscriptions/form_token")
+                    .wrap(RateLimit::new(
+                        rate_limiter.clone(),
+                        "subscriptions_form_token",
+                        limits.subscriptions_form_token.clone(),
+                    ))
+                    .route(web::get().to(issue_form_token)),
+            )
+            .route(
+                "/subscriptions/unsubscribe",
+                web::get().to(unsubscribe_form),
+            )
+            .route("/subscriptions/unsubscribe", web::post().to(unsubscribe))
+            .route(
+                "/subscriptions/tracking_opt_out",
+                web::get().to(tracking_opt_out_form),
+            )
+            .route(
+                "/subscriptions/tracking_opt_out",
+                web::post().to(opt_out_of_tracking),
+            )
+            .route("/issues", web::get().to(archive_index))
+            .route("/issues/{slug}", web::get().to(archived_issue))
+            .route(
+                "/issues/{slug}/inline/{content_id}",
+                web::get().to(archived_inline_image),
+            )
+            .route("/feed.xml", web::get().to(archive_atom_feed))
+            .route("/feed.rss", web::get().to(archive_rss_feed))
+            .route("/t/open/{token}.gif", web::get().to(track_open))
+            .route("/t/click/{token}", web::get().to(track_click))
+            .service(
+                web::resource("/newsletter")
+                    .wrap(RateLimit::new(
+                        rate_limiter.clone(),
+                        "newsletter",
+                        limits.newsletter.clone(),
+                    ))
+                    .route(
+                        web::post()
+                            .guard(guard::fn_guard(is_multipart))
+                            .to(publish_newsletter_with_attachments),
+                    )
+                    .route(web::post().to(publish_newsletter)),
+            )
+            .route(
+                "/webhooks/postmark",
+                web::post().to(handle_postmark_webhook),
+            )
+            .route("/admin/metrics", web::get().to(get_metrics))
+            .route("/admin/subscribers", web::get().to(list_subscribers))
+            .route(
+                "/admin/subscribers/export.csv",
+                web::get().to(export_subscribers),
+            )
+            .route(
+                "/admin/issues/scheduled",
+                web::get().to(list_scheduled_issues),
+            )
+            .service(
+                web::resource("/admin/issues/{newsletter_issue_id}/schedule")
+                    .route(web::put().to(reschedule_issue))
+                    .route(web::delete().to(cancel_scheduled_issue)),
+            )
+            .route(
+                "/admin/issues/{newsletter_issue_id}/engagement",
+                web::get().to(issue_engagement),
+            )
+            .route(
+                "/admin/issues/{newsletter_issue_id}/deliveries",
+                web::get().to(list_issue_deliveries),
+            )
+            .service(
+                web::resource("/admin/suppressions")
+                    .route(web::get().to(list_suppressions))
+                    .route(web::post().to(add_suppression)),
+            )
+            .route(
+                "/admin/suppressions/{suppression_id}",
+                web::delete().to(remove_suppression),
+            )
+            .route("/admin/drafts", web::post().to(create_draft))
+            .service(
+                web::resource("/admin/drafts/{newsletter_issue_id}")
+                    .route(web::get().to(get_draft))
+                    .route(web::put().to(update_draft)),
+            )
+            .route(
+                "/admin/drafts/{newsletter_issue_id}/preview",
+                web::get().to(preview_draft),
+            )
+            .route(
+                "/admin/drafts/{newsletter_issue_id}/test",
+                web::post().to(send_test_draft),
+            )
+            .route(
+                "/admin/drafts/{newsletter_issue_id}/publish",
+                web::post().to(publish_draft),
+            )
             .app_data(db_pool.clone())
             .app_data(email_client.clone())
+            .app_data(templates.clone())
+            .app_data(unsubscribe_links.clone())
+            .app_data(tracking_links.clone())
             .app_data(base_url.clone())
+            .app_data(signup_policy.clone())
+            .app_data(bot_protection.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(postmark_webhook.clone())
+            .app_data(email_outbox.clone())
+            .app_data(archive.clone())
     })
     .listen(listener)?
     .run();
     Ok(server)
 }
+
+fn is_multipart(ctx: &GuardContext) -> bool {
+    ctx.head()
+        .headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/form-data"))
+}
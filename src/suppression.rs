@@ -0,0 +1,24 @@
+use crate::domain::SubscriberEmail;
+use sqlx::PgExecutor;
+
+/// Whether `email`, or its whole domain, is on the suppression list.
+/// Every send path checks this before calling the email provider.
+#[tracing::instrument(name = "Check the suppression list", skip(executor, email))]
+pub async fn is_suppressed(
+    executor: impl PgExecutor<'_>,
+    email: &SubscriberEmail,
+) -> Result<bool, sqlx::Error> {
+    let suppressed = sqlx::query!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM suppressions WHERE email = $1 OR domain = $2
+        ) AS "suppressed!"
+        "#,
+        email.normalised(),
+        email.domain()
+    )
+    .fetch_one(executor)
+    .await?
+    .suppressed;
+    Ok(suppressed)
+}
@@ -0,0 +1,270 @@
+use anyhow::Context;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use lol_html::{element, RewriteStrSettings};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use uuid::Uuid;
+
+#[derive(Debug, PartialEq)]
+pub enum TrackingEvent {
+    Open {
+        newsletter_issue_id: Uuid,
+        subscriber_id: Uuid,
+    },
+    Click {
+        newsletter_issue_id: Uuid,
+        subscriber_id: Uuid,
+        url: String,
+    },
+}
+
+impl TrackingEvent {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TrackingEvent::Open { .. } => "open",
+            TrackingEvent::Click { .. } => "click",
+        }
+    }
+
+    // Newline-separated, which neither uuids nor a valid link can contain
+    fn payload(&self) -> String {
+        match self {
+            TrackingEvent::Open {
+                newsletter_issue_id,
+                subscriber_id,
+            } => format!("open\n{}\n{}", newsletter_issue_id, subscriber_id),
+            TrackingEvent::Click {
+                newsletter_issue_id,
+                subscriber_id,
+                url,
+            } => format!("click\n{}\n{}\n{}", newsletter_issue_id, subscriber_id, url),
+        }
+    }
+
+    fn from_payload(payload: &str) -> Option<Self> {
+        let mut parts = payload.splitn(4, '\n');
+        let kind = parts.next()?;
+        let newsletter_issue_id = parts.next()?.parse().ok()?;
+        let subscriber_id = parts.next()?.parse().ok()?;
+        match (kind, parts.next()) {
+            ("open", None) => Some(TrackingEvent::Open {
+                newsletter_issue_id,
+                subscriber_id,
+            }),
+            ("click", Some(url)) => Some(TrackingEvent::Click {
+                newsletter_issue_id,
+                subscriber_id,
+                url: url.into(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+// Builds and checks the signed open pixel and click redirect links embedded in newsletters.
+// A token is the base64 event payload followed by its HMAC-SHA256, so the click redirect
+// cannot be abused to send people anywhere but to the links we wrote.
+pub struct TrackingLinks {
+    base_url: String,
+    secret: Secret<String>,
+}
+
+impl TrackingLinks {
+    pub fn new(base_url: String, secret: Secret<String>) -> Self {
+        Self { base_url, secret }
+    }
+
+    pub fn open_url(&self, newsletter_issue_id: Uuid, subscriber_id: Uuid) -> String {
+        let token = self.token(&TrackingEvent::Open {
+            newsletter_issue_id,
+            subscriber_id,
+        });
+        format!("{}/t/open/{}.gif", self.base_url, token)
+    }
+
+    pub fn click_url(&self, newsletter_issue_id: Uuid, subscriber_id: Uuid, url: &str) -> String {
+        let token = self.token(&TrackingEvent::Click {
+            newsletter_issue_id,
+            subscriber_id,
+            url: url.into(),
+        });
+        format!("{}/t/click/{}", self.base_url, token)
+    }
+
+    /// Returns the event a token was issued for, if it has not been tampered with.
+    pub fn decode(&self, token: &str) -> Option<TrackingEvent> {
+        let (payload, signature) = token.split_once('.')?;
+        let payload = URL_SAFE_NO_PAD.decode(payload).ok()?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        self.mac(&payload).verify_slice(&signature).ok()?;
+        TrackingEvent::from_payload(std::str::from_utf8(&payload).ok()?)
+    }
+
+    /// Point every web link of an issue's HTML to the click redirect and append the open
+    /// pixel, for one subscriber.
+    pub fn instrument(
+        &self,
+        html: &str,
+        newsletter_issue_id: Uuid,
+        subscriber_id: Uuid,
+    ) -> Result<String, anyhow::Error> {
+        let mut html = lol_html::rewrite_str(
+            html,
+            RewriteStrSettings {
+                element_content_handlers: vec![element!("a[href]", |link| {
+                    // `lol_html` hands out attribute values as written in the source
+                    let Some(href) = link
+                        .get_attribute("href")
+                        .map(|href| html_escape::decode_html_entities(&href).into_owned())
+                    else {
+                        return Ok(());
+                    };
+                    // `mailto:`, `tel:` and anchors are left alone
+                    if href.starts_with("http://") || href.starts_with("https://") {
+                        let tracked = self.click_url(newsletter_issue_id, subscriber_id, &href);
+                        link.set_attribute("href", &tracked)?;
+                    }
+                    Ok(())
+                })],
+                ..RewriteStrSettings::default()
+            },
+        )
+        .context("Failed to rewrite the links of a newsletter issue.")?;
+        html.push_str(&format!(
+            r#"<img src="{}" width="1" height="1" alt="">"#,
+            self.open_url(newsletter_issue_id, subscriber_id)
+        ));
+        Ok(html)
+    }
+
+    fn token(&self, event: &TrackingEvent) -> String {
+        let payload = event.payload();
+        let signature = self.mac(payload.as_bytes()).finalize().into_bytes();
+        format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(payload),
+            URL_SAFE_NO_PAD.encode(signature)
+        )
+    }
+
+    fn mac(&self, payload: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.expose_secret().as_bytes())
+            .expect("HMAC can take a key of any size");
+        mac.update(payload);
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TrackingEvent, TrackingLinks};
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn links() -> TrackingLinks {
+        TrackingLinks::new(
+            "https://example.com".into(),
+            Secret::new("a-very-secret-key".into()),
+        )
+    }
+
+    fn token_of(url: &str) -> &str {
+        url.rsplit('/').next().unwrap().trim_end_matches(".gif")
+    }
+
+    #[test]
+    fn tokens_decode_to_the_event_they_were_issued_for() {
+        let (newsletter_issue_id, subscriber_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let open = links().open_url(newsletter_issue_id, subscriber_id);
+        let click = links().click_url(
+            newsletter_issue_id,
+            subscriber_id,
+            "https://example.com/post?a=1",
+        );
+        assert_eq!(
+            links().decode(token_of(&open)),
+            Some(TrackingEvent::Open {
+                newsletter_issue_id,
+                subscriber_id
+            })
+        );
+        assert_eq!(
+            links().decode(token_of(&click)),
+            Some(TrackingEvent::Click {
+                newsletter_issue_id,
+                subscriber_id,
+                url: "https://example.com/post?a=1".into()
+            })
+        );
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let click = links().click_url(Uuid::new_v4(), Uuid::new_v4(), "https://example.com");
+        let (_, signature) = token_of(&click).split_once('.').unwrap();
+        let forged_payload = base64::Engine::encode(
+            &base64::engine::general_purpose::URL_SAFE_NO_PAD,
+            format!(
+                "click\n{}\n{}\nhttps://evil.com",
+                Uuid::new_v4(),
+                Uuid::new_v4()
+            ),
+        );
+        let forged = format!("{}.{}", forged_payload, signature);
+        for token in ["", "abc", "abc.def", forged.as_str()] {
+            assert_eq!(links().decode(token), None);
+        }
+    }
+
+    #[test]
+    fn web_links_are_rewritten_and_a_pixel_appended() {
+        let html = links()
+            .instrument(
+                r#"<p><a href="https://example.com/post">Post</a> <a href="mailto:hi@example.com">Mail</a></p>"#,
+                Uuid::new_v4(),
+                Uuid::new_v4(),
+            )
+            .unwrap();
+        assert!(html.contains(r#"<a href="https://example.com/t/click/"#));
+        assert!(html.contains(r#"<a href="mailto:hi@example.com">"#));
+        assert!(!html.contains(r#"href="https://example.com/post""#));
+        assert!(html.ends_with(r#".gif" width="1" height="1" alt="">"#));
+    }
+
+    #[test]
+    fn links_are_unescaped_before_they_are_signed() {
+        let (newsletter_issue_id, subscriber_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let test_cases = [
+            (
+                "https://example.com/?a=1&amp;b=2",
+                "https://example.com/?a=1&b=2",
+            ),
+            (
+                "https://example.com/&#x2F;it&#39;s",
+                "https://example.com//it's",
+            ),
+            (
+                "https://example.com/?q=&lt;3&copy;",
+                "https://example.com/?q=<3\u{a9}",
+            ),
+        ];
+        for (href, url) in test_cases {
+            let html = links()
+                .instrument(
+                    &format!(r#"<a href="{}">Post</a>"#, href),
+                    newsletter_issue_id,
+                    subscriber_id,
+                )
+                .unwrap();
+            let expected = links().click_url(newsletter_issue_id, subscriber_id, url);
+            assert!(
+                html.contains(&format!(r#"href="{}""#, expected)),
+                "{} was not decoded to {}",
+                href,
+                url
+            );
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use uuid::Uuid;
+
+// What a token lets its bearer do, signed along with the subscriber id
+#[derive(Clone, Copy)]
+pub enum LinkPurpose {
+    Unsubscribe,
+    TrackingOptOut,
+}
+
+impl LinkPurpose {
+    fn label(&self) -> &'static [u8] {
+        match self {
+            // Unlabelled, so that the links sent before opt-out links existed still work
+            LinkPurpose::Unsubscribe => b"",
+            LinkPurpose::TrackingOptOut => b"tracking_opt_out\n",
+        }
+    }
+}
+
+// Builds and checks the signed links that let subscribers leave, or opt out of tracking,
+// without logging in.
+// The token is the base64 HMAC-SHA256 of the link's purpose and the subscriber id, so links
+// never expire.
+pub struct UnsubscribeLinks {
+    base_url: String,
+    secret: Secret<String>,
+}
+
+impl UnsubscribeLinks {
+    pub fn new(base_url: String, secret: Secret<String>) -> Self {
+        Self { base_url, secret }
+    }
+
+    pub fn url_for(&self, subscriber_id: Uuid) -> String {
+        format!(
+            "{}/subscriptions/unsubscribe?subscriber_id={}&token={}",
+            self.base_url,
+            subscriber_id,
+            self.token(LinkPurpose::Unsubscribe, subscriber_id)
+        )
+    }
+
+    pub fn tracking_opt_out_url_for(&self, subscriber_id: Uuid) -> String {
+        format!(
+            "{}/subscriptions/tracking_opt_out?subscriber_id={}&token={}",
+            self.base_url,
+            subscriber_id,
+            self.token(LinkPurpose::TrackingOptOut, subscriber_id)
+        )
+    }
+
+    pub fn token(&self, purpose: LinkPurpose, subscriber_id: Uuid) -> String {
+        let signature = self.mac(purpose, subscriber_id).finalize().into_bytes();
+        base64::Engine::encode(&base64::engine::general_purpose::URL_SAFE_NO_PAD, signature)
+    }
+
+    pub fn verify(&self, purpose: LinkPurpose, subscriber_id: Uuid, token: &str) -> bool {
+        let Ok(signature) =
+            base64::Engine::decode(&base64::engine::general_purpose::URL_SAFE_NO_PAD, token)
+        else {
+            return false;
+        };
+        // `verify_slice` compares in constant time
+        self.mac(purpose, subscriber_id)
+            .verify_slice(&signature)
+            .is_ok()
+    }
+
+    fn mac(&self, purpose: LinkPurpose, subscriber_id: Uuid) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.expose_secret().as_bytes())
+            .expect("HMAC can take a key of any size");
+        mac.update(purpose.label());
+        mac.update(subscriber_id.as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LinkPurpose, UnsubscribeLinks};
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn links() -> UnsubscribeLinks {
+        UnsubscribeLinks::new(
+            "https://example.com".into(),
+            Secret::new("a-very-secret-key".into()),
+        )
+    }
+
+    #[test]
+    fn a_token_is_only_valid_for_its_subscriber() {
+        let subscriber_id = Uuid::new_v4();
+        let token = links().token(LinkPurpose::Unsubscribe, subscriber_id);
+        assert!(links().verify(LinkPurpose::Unsubscribe, subscriber_id, &token));
+        assert!(!links().verify(LinkPurpose::Unsubscribe, Uuid::new_v4(), &token));
+    }
+
+    #[test]
+    fn a_token_is_only_valid_for_its_purpose() {
+        let subscriber_id = Uuid::new_v4();
+        let token = links().token(LinkPurpose::TrackingOptOut, subscriber_id);
+        assert!(links().verify(LinkPurpose::TrackingOptOut, subscriber_id, &token));
+        assert!(!links().verify(LinkPurpose::Unsubscribe, subscriber_id, &token));
+    }
+
+    #[test]
+    fn garbage_tokens_are_rejected() {
+        for token in ["", "!!!", "c2lnbmF0dXJl"] {
+            assert!(!links().verify(LinkPurpose::Unsubscribe, Uuid::new_v4(), token));
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use wiremock::{
+    matchers::{any, body_string_contains, method, path},
+    Mock, ResponseTemplate,
+};
+
+use crate::helpers::{spawn_app, TestApp};
+
+fn draft_body(title: &str) -> serde_json::Value {
+    serde_json::json!({
+        "title": title,
+        "content": {
+            "text": "Draft body as plain text",
+            "html": "<p>Draft body as HTML</p>"
+        }
+    })
+}
+
+// Create a draft through the admin API and return its id
+async fn create_draft(app: &TestApp) -> String {
+    let response = app.post_admin("drafts", draft_body("First draft")).await;
+    assert_eq!(response.status().as_u16(), 201);
+    let body: serde_json::Value = response.json().await.unwrap();
+    body["newsletter_issue_id"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn drafts_can_be_created_updated_and_fetched() {
+    // Arrange
+    let app = spawn_app().await;
+    let draft_id = create_draft(&app).await;
+
+    // Act
+    let update = app
+        .put_admin(&format!("drafts/{}", draft_id), draft_body("Second draft"))
+        .await;
+    let response = app.get_admin(&format!("drafts/{}", draft_id)).await;
+
+    // Assert
+    assert_eq!(update.status().as_u16(), 200);
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["title"], "Second draft");
+    assert_eq!(body["content"]["html"], "<p>Draft body as HTML</p>");
+    assert!(!body["updated_at"].is_null());
+}
+
+#[tokio::test]
+async fn creating_a_draft_does_not_send_any_email() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    create_draft(&app).await;
+
+    // Assert
+    let saved = sqlx::query!("SELECT status FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the draft.");
+    assert_eq!(saved.status, "draft");
+}
+
+#[tokio::test]
+async fn the_preview_returns_the_email_as_it_would_be_sent() {
+    // Arrange
+    let app = spawn_app().await;
+    let draft_id = create_draft(&app).await;
+
+    // Act
+    let response = app.get_admin(&format!("drafts/{}/preview", draft_id)).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["subject"], "First draft");
+    // Rendered through the newsletter template, with placeholder subscriber details
+    let html = body["html"].as_str().unwrap();
+    assert!(html.contains("<p>Draft body as HTML</p>"));
+    assert!(html.contains("Hi Jane Doe,"));
+    assert!(body["text"]
+        .as_str()
+        .unwrap()
+        .contains("Draft body as plain text"));
+    assert_eq!(body["warnings"], serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn markdown_drafts_preview_as_they_would_be_published() {
+    // Arrange
+    let app = spawn_app().await;
+    let response = app
+        .post_admin(
+            "drafts",
+            serde_json::json!({
+                "title": "Markdown draft",
+                "content": {
+                    "markdown": "Read [our post](https://example.com/post)."
+                }
+            }),
+        )
+        .await;
+    assert_eq!(response.status().as_u16(), 201);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let draft_id = body["newsletter_issue_id"].as_str().unwrap();
+
+    // Act
+    let response = app.get_admin(&format!("drafts/{}/preview", draft_id)).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let html = body["html"].as_str().unwrap();
+    assert!(html.contains("<h1>Markdown draft</h1>"));
+    assert!(html.contains(r#"<a href="https://example.com/post""#));
+    let text = body["text"].as_str().unwrap();
+    assert!(text.contains("# Markdown draft"));
+    assert!(text.contains("Read [our post][1]."));
+}
+
+#[tokio::test]
+async fn the_text_of_a_draft_is_derived_from_its_html_when_missing() {
+    // Arrange
+    let app = spawn_app().await;
+    let response = app
+        .post_admin(
+            "drafts",
+            serde_json::json!({
+                "title": "HTML draft",
+                "content": {
+                    "html": r#"<p>Read <a href="https://example.com/post">our post</a>.</p>"#
+                }
+            }),
+        )
+        .await;
+    assert_eq!(response.status().as_u16(), 201);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let draft_id = body["newsletter_issue_id"].as_str().unwrap();
+
+    // Act
+    let response = app.get_admin(&format!("drafts/{}", draft_id)).await;
+
+    // Assert
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body["content"]["text"]
+        .as_str()
+        .unwrap()
+        .contains("Read [our post][1]."));
+}
+
+#[tokio::test]
+async fn drafts_with_both_markdown_and_html_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_admin(
+            "drafts",
+            serde_json::json!({
+                "title": "Confused draft",
+                "content": {
+                    "markdown": "Draft body as **Markdown**",
+                    "html": "<p>Draft body as HTML</p>"
+                }
+            }),
+        )
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn the_preview_warns_when_gmail_would_clip_the_email() {
+    // Arrange
+    let app = spawn_app().await;
+    let response = app
+        .post_admin(
+            "drafts",
+            serde_json::json!({
+                "title": "Long draft",
+                "content": {
+                    "text": "Draft body as plain text",
+                    "html": format!("<p>{}</p>", "a".repeat(110 * 1024))
+                }
+            }),
+        )
+        .await;
+    let body: serde_json::Value = response.json().await.unwrap();
+    let draft_id = body["newsletter_issue_id"].as_str().unwrap();
+
+    // Act
+    let response = app.get_admin(&format!("drafts/{}/preview", draft_id)).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let warnings = body["warnings"].as_array().unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].as_str().unwrap().contains("Gmail"));
+}
+
+#[tokio::test]
+async fn test_sends_only_go_to_the_supplied_recipients() {
+    // Arrange
+    let app = spawn_app().await;
+    let draft_id = create_draft(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .and(body_string_contains("[Test] First draft"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_admin(
+            &format!("drafts/{}/test", draft_id),
+            serde_json::json!({
+                "recipients": ["editor@example.com", "reviewer@example.com"]
+            }),
+        )
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the draft.");
+    assert_eq!(saved.status, "draft");
+}
+
+#[tokio::test]
+async fn test_sends_reject_invalid_recipient_lists() {
+    // Arrange
+    let app = spawn_app().await;
+    let draft_id = create_draft(&app).await;
+    let test_cases = vec![
+        (serde_json::json!([]), "no recipients"),
+        (serde_json::json!(["not-an-email"]), "an invalid address"),
+    ];
+
+    for (recipients, description) in test_cases {
+        // Act
+        let response = app
+            .post_admin(
+                &format!("drafts/{}/test", draft_id),
+                serde_json::json!({ "recipients": recipients }),
+            )
+            .await;
+
+        // Assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the payload had {}.",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn published_drafts_can_no_longer_be_edited() {
+    // Arrange
+    let app = spawn_app().await;
+    let draft_id = create_draft(&app).await;
+
+    // Act
+    let publish = app
+        .post_admin(
+            &format!("drafts/{}/publish", draft_id),
+            serde_json::json!({}),
+        )
+        .await;
+    let update = app
+        .put_admin(&format!("drafts/{}", draft_id), draft_body("Too late"))
+        .await;
+
+    // Assert
+    assert_eq!(publish.status().as_u16(), 200);
+    assert_eq!(update.status().as_u16(), 404);
+    let saved = sqlx::query!("SELECT status FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the issue.");
+    assert_eq!(saved.status, "sent");
+}
+
+#[tokio::test]
+async fn published_drafts_are_archived_under_their_slug() {
+    // Arrange
+    let app = spawn_app().await;
+    let draft_id = create_draft(&app).await;
+
+    // Act
+    let publish = app
+        .post_admin(
+            &format!("drafts/{}/publish", draft_id),
+            serde_json::json!({}),
+        )
+        .await;
+    let page = reqwest::get(format!("{}/issues/first-draft", app.address))
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(publish.status().as_u16(), 200);
+    assert_eq!(page.status().as_u16(), 200);
+}
@@ -0,0 +1,619 @@
+use chrono::{Duration, Utc};
+use secrecy::Secret;
+use sqlx::Executor;
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+use zero2prod::configuration::EmailBackendSettings;
+
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+use crate::newsletter::create_confirmed_subscriber;
+
+// Schedule an issue through the public API and return its id
+async fn schedule_issue(app: &TestApp, title: &str, send_at: chrono::DateTime<Utc>) -> String {
+    let response = app
+        .post_newsletter(serde_json::json!({
+            "title": title,
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>"
+            },
+            "send_at": send_at.to_rfc3339()
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    let body: serde_json::Value = response.json().await.unwrap();
+    body["newsletter_issue_id"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn scheduled_issues_are_listed_by_send_date() {
+    // Arrange
+    let app = spawn_app().await;
+    schedule_issue(&app, "Later", Utc::now() + Duration::days(2)).await;
+    schedule_issue(&app, "Sooner", Utc::now() + Duration::days(1)).await;
+
+    // Act
+    let response = app.get_scheduled_issues().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let titles: Vec<_> = body["issues"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|issue| issue["title"].as_str().unwrap())
+        .collect();
+    assert_eq!(titles, vec!["Sooner", "Later"]);
+}
+
+#[tokio::test]
+async fn scheduled_issues_can_be_rescheduled() {
+    // Arrange
+    let app = spawn_app().await;
+    let issue_id = schedule_issue(&app, "Title", Utc::now() + Duration::days(1)).await;
+    let send_at = Utc::now() + Duration::days(3);
+
+    // Act
+    let response = app
+        .reschedule_issue(
+            &issue_id,
+            serde_json::json!({ "send_at": send_at.to_rfc3339() }),
+        )
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT send_at FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the newsletter issue.");
+    assert_eq!(
+        saved.send_at.unwrap().timestamp_millis(),
+        send_at.timestamp_millis()
+    );
+}
+
+#[tokio::test]
+async fn issues_cannot_be_rescheduled_in_the_past() {
+    // Arrange
+    let app = spawn_app().await;
+    let issue_id = schedule_issue(&app, "Title", Utc::now() + Duration::days(1)).await;
+
+    // Act
+    let response = app
+        .reschedule_issue(
+            &issue_id,
+            serde_json::json!({ "send_at": (Utc::now() - Duration::hours(1)).to_rfc3339() }),
+        )
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn cancelled_issues_are_no_longer_scheduled() {
+    // Arrange
+    let app = spawn_app().await;
+    let issue_id = schedule_issue(&app, "Title", Utc::now() + Duration::days(1)).await;
+
+    // Act
+    let response = app.cancel_scheduled_issue(&issue_id).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = app.get_scheduled_issues().await.json().await.unwrap();
+    assert!(body["issues"].as_array().unwrap().is_empty());
+    let saved = sqlx::query!("SELECT status FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the newsletter issue.");
+    assert_eq!(saved.status, "cancelled");
+}
+
+#[tokio::test]
+async fn issues_that_are_not_scheduled_cannot_be_changed() {
+    // Arrange
+    let app = spawn_app().await;
+    let issue_id = schedule_issue(&app, "Title", Utc::now() + Duration::days(1)).await;
+    app.cancel_scheduled_issue(&issue_id).await;
+    let send_at = Utc::now() + Duration::days(3);
+
+    // Act
+    let cancel = app.cancel_scheduled_issue(&issue_id).await;
+    let reschedule = app
+        .reschedule_issue(
+            &issue_id,
+            serde_json::json!({ "send_at": send_at.to_rfc3339() }),
+        )
+        .await;
+    let unknown = app
+        .cancel_scheduled_issue(&uuid::Uuid::new_v4().to_string())
+        .await;
+
+    // Assert
+    assert_eq!(cancel.status().as_u16(), 404);
+    assert_eq!(reschedule.status().as_u16(), 404);
+    assert_eq!(unknown.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn scheduled_issues_require_authentication() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::get(format!("{}/admin/issues/scheduled", &app.address))
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+// Publish an issue right away and return its id
+async fn publish_issue(app: &TestApp, expected_status: u16) -> String {
+    let response = app
+        .post_newsletter(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>"
+            }
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), expected_status);
+    sqlx::query!("SELECT newsletter_issue_id FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .newsletter_issue_id
+        .to_string()
+}
+
+async fn get_deliveries(app: &TestApp, issue_id: &str) -> serde_json::Value {
+    let response = app
+        .get_admin(&format!("issues/{}/deliveries", issue_id))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    response.json().await.unwrap()
+}
+
+#[tokio::test]
+async fn deliveries_record_the_provider_message_id() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "MessageID": "b7bc2f4a-e38e-4336-af7d-e6c392c2f817",
+            "ErrorCode": 0,
+            "Message": "OK"
+        })))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let issue_id = publish_issue(&app, 200).await;
+    let deliveries = get_deliveries(&app, &issue_id).await;
+
+    // Assert
+    assert_eq!(deliveries["summary"]["sent"], 1);
+    assert_eq!(deliveries["summary"]["failed"], 0);
+    let delivery = &deliveries["deliveries"][0];
+    assert_eq!(delivery["email"], "sara_kuzoi@tuta.io");
+    assert_eq!(delivery["status"], "sent");
+    assert_eq!(
+        delivery["provider_message_id"],
+        "b7bc2f4a-e38e-4336-af7d-e6c392c2f817"
+    );
+    assert_eq!(delivery["provider"], "primary");
+    assert_eq!(delivery["attempts"], 1);
+}
+
+#[tokio::test]
+async fn deliveries_fail_over_to_the_secondary_backend() {
+    // Arrange
+    let secondary_server = MockServer::start().await;
+    let secondary_uri = secondary_server.uri();
+    let app = spawn_app_with(|c| {
+        c.email_client.secondary = Some(EmailBackendSettings {
+            base_url: secondary_uri,
+            authorization_token: Secret::new("secondary-token".into()),
+        })
+    })
+    .await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "MessageID": "0a129aee-e1cd-480d-b08d-4f48548ff48d",
+            "ErrorCode": 0,
+            "Message": "OK"
+        })))
+        .expect(1)
+        .mount(&secondary_server)
+        .await;
+
+    // Act
+    let issue_id = publish_issue(&app, 200).await;
+    let deliveries = get_deliveries(&app, &issue_id).await;
+
+    // Assert
+    assert_eq!(deliveries["summary"]["sent"], 1);
+    let delivery = &deliveries["deliveries"][0];
+    assert_eq!(delivery["status"], "sent");
+    assert_eq!(
+        delivery["provider_message_id"],
+        "0a129aee-e1cd-480d-b08d-4f48548ff48d"
+    );
+    assert_eq!(delivery["provider"], "secondary");
+}
+
+#[tokio::test]
+async fn failed_deliveries_are_reported_with_their_error() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let issue_id = publish_issue(&app, 500).await;
+    let deliveries = get_deliveries(&app, &issue_id).await;
+
+    // Assert
+    assert_eq!(deliveries["summary"]["sent"], 0);
+    assert_eq!(deliveries["summary"]["failed"], 1);
+    let delivery = &deliveries["deliveries"][0];
+    assert_eq!(delivery["status"], "failed");
+    assert!(delivery["provider_message_id"].is_null());
+    assert!(delivery["provider"].is_null());
+    assert!(delivery["last_error"]
+        .as_str()
+        .unwrap()
+        .contains("500 Internal Server Error"));
+}
+
+#[tokio::test]
+async fn taken_over_issues_are_not_sent_to_subscribers_who_confirmed_since() {
+    // Arrange
+    let app = spawn_app_with(|c| c.newsletter_scheduler.poll_interval_milliseconds = 100).await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let issue_id = publish_issue(&app, 500).await;
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, email_normalised, name, subscribed_at, status)
+        VALUES ($1, 'late@example.com', 'late@example.com', 'late', now(), 'confirmed')
+        "#,
+        uuid::Uuid::new_v4()
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    // Act - the issue was left in 'sending' by an instance that died
+    sqlx::query!(
+        "UPDATE newsletter_issues SET status = 'sending', claimed_at = now() - interval '1 hour'"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    // Assert
+    let mut status = String::new();
+    for _ in 0..50 {
+        status = sqlx::query!("SELECT status FROM newsletter_issues")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap()
+            .status;
+        if status == "sent" {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(status, "sent");
+    let deliveries = get_deliveries(&app, &issue_id).await;
+    assert_eq!(deliveries["summary"]["sent"], 1);
+    assert_eq!(deliveries["deliveries"].as_array().unwrap().len(), 1);
+    assert_eq!(deliveries["deliveries"][0]["email"], "sara_kuzoi@tuta.io");
+}
+
+// Publish an issue to the confirmed subscriber, then report a bounce of the given type for it
+async fn bounce_delivery(app: &TestApp, bounce_type: &str) -> serde_json::Value {
+    create_confirmed_subscriber(app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "MessageID": "b7bc2f4a-e38e-4336-af7d-e6c392c2f817",
+            "ErrorCode": 0,
+            "Message": "OK"
+        })))
+        .mount(&app.email_server)
+        .await;
+    let issue_id = publish_issue(app, 200).await;
+    app.post_postmark_webhook(serde_json::json!({
+        "RecordType": "Bounce",
+        "Type": bounce_type,
+        "MessageID": "b7bc2f4a-e38e-4336-af7d-e6c392c2f817",
+        "Email": "sara_kuzoi@tuta.io",
+        "Inactive": false
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+    get_deliveries(app, &issue_id).await
+}
+
+#[tokio::test]
+async fn bounce_webhooks_mark_the_delivery_as_bounced() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let deliveries = bounce_delivery(&app, "HardBounce").await;
+
+    // Assert
+    assert_eq!(deliveries["summary"]["sent"], 0);
+    assert_eq!(deliveries["summary"]["bounced"], 1);
+    assert_eq!(deliveries["deliveries"][0]["last_error"], "HardBounce");
+}
+
+#[tokio::test]
+async fn transient_bounces_and_auto_responders_leave_the_delivery_sent() {
+    for bounce_type in ["Transient", "AutoResponder"] {
+        // Arrange
+        let app = spawn_app().await;
+
+        // Act
+        let deliveries = bounce_delivery(&app, bounce_type).await;
+
+        // Assert
+        assert_eq!(deliveries["summary"]["sent"], 1, "{}", bounce_type);
+        assert_eq!(deliveries["summary"]["bounced"], 0, "{}", bounce_type);
+        assert_eq!(deliveries["deliveries"][0]["status"], "sent");
+        assert_eq!(deliveries["deliveries"][0]["last_error"], bounce_type);
+    }
+}
+
+#[tokio::test]
+async fn a_delivery_that_cannot_be_recorded_does_not_stop_the_others() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let other_id = uuid::Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, email_normalised, name, subscribed_at, status)
+        VALUES ($1, 'other@example.com', 'other@example.com', 'other', now(), 'confirmed')
+        "#,
+        other_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    // Sabotage the record of the other subscriber's delivery
+    app.db_pool
+        .execute(
+            format!(
+                r#"
+                CREATE FUNCTION sabotage() RETURNS trigger AS $$
+                BEGIN RAISE EXCEPTION 'sabotage'; END
+                $$ LANGUAGE plpgsql;
+                CREATE TRIGGER sabotage BEFORE UPDATE ON newsletter_issue_deliveries
+                FOR EACH ROW WHEN (NEW.subscriber_id = '{}' AND NEW.status <> 'queued')
+                EXECUTE FUNCTION sabotage();
+                "#,
+                other_id
+            )
+            .as_str(),
+        )
+        .await
+        .unwrap();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let issue_id = publish_issue(&app, 500).await;
+    let deliveries = get_deliveries(&app, &issue_id).await;
+
+    // Assert
+    assert_eq!(deliveries["summary"]["sent"], 1);
+    assert_eq!(deliveries["summary"]["queued"], 1);
+    let status = sqlx::query!("SELECT status FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .status;
+    assert_eq!(status, "failed");
+}
+
+#[tokio::test]
+async fn deliveries_of_unknown_issues_are_not_found() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .get_admin(&format!("issues/{}/deliveries", uuid::Uuid::new_v4()))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn deliveries_can_be_filtered_by_status() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let issue_id = publish_issue(&app, 200).await;
+
+    // Act
+    let sent = app
+        .get_admin(&format!("issues/{}/deliveries?status=sent", issue_id))
+        .await;
+    let failed = app
+        .get_admin(&format!("issues/{}/deliveries?status=failed", issue_id))
+        .await;
+    let unknown = app
+        .get_admin(&format!("issues/{}/deliveries?status=delivered", issue_id))
+        .await;
+
+    // Assert
+    assert_eq!(sent.status().as_u16(), 200);
+    let sent: serde_json::Value = sent.json().await.unwrap();
+    assert_eq!(sent["deliveries"].as_array().unwrap().len(), 1);
+    assert_eq!(failed.status().as_u16(), 200);
+    let failed: serde_json::Value = failed.json().await.unwrap();
+    assert!(failed["deliveries"].as_array().unwrap().is_empty());
+    assert_eq!(unknown.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn inactive_recipients_do_not_fail_the_issue() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(422).set_body_json(serde_json::json!({
+            "ErrorCode": 406,
+            "Message": "You tried to send to a recipient that has been marked as inactive."
+        })))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let issue_id = publish_issue(&app, 200).await;
+    let deliveries = get_deliveries(&app, &issue_id).await;
+
+    // Assert
+    assert_eq!(deliveries["summary"]["failed"], 1);
+    assert!(deliveries["deliveries"][0]["last_error"]
+        .as_str()
+        .unwrap()
+        .contains("error code 406"));
+}
+
+#[tokio::test]
+async fn following_the_cursor_walks_every_delivery_once() {
+    // Arrange
+    let app = spawn_app().await;
+    for i in 0..5 {
+        let email = format!("reader{}@example.com", i);
+        sqlx::query!(
+            r#"
+            INSERT INTO subscriptions (id, email, email_normalised, name, subscribed_at, status)
+            VALUES ($1, $2, $2, 'reader', now(), 'confirmed')
+            "#,
+            uuid::Uuid::new_v4(),
+            email
+        )
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    }
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(5)
+        .mount(&app.email_server)
+        .await;
+    let issue_id = publish_issue(&app, 200).await;
+
+    // Act
+    let mut seen = Vec::new();
+    let mut after: Option<String> = None;
+    loop {
+        let mut query = format!("issues/{}/deliveries?limit=2", issue_id);
+        if let Some(after) = &after {
+            query.push_str(&format!("&after={}", after));
+        }
+        let response = app.get_admin(&query).await;
+        assert_eq!(response.status().as_u16(), 200);
+        let page: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(page["summary"]["sent"], 5);
+        seen.extend(
+            page["deliveries"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|delivery| delivery["email"].as_str().unwrap().to_string()),
+        );
+        after = page["next_cursor"].as_str().map(str::to_owned);
+        if after.is_none() {
+            break;
+        }
+    }
+
+    // Assert
+    assert_eq!(
+        seen,
+        (0..5)
+            .map(|i| format!("reader{}@example.com", i))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test]
+async fn deliveries_reject_an_invalid_page_size_or_cursor() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let issue_id = publish_issue(&app, 200).await;
+
+    for query in ["limit=0", "limit=501", "after=not-a-cursor"] {
+        // Act
+        let response = app
+            .get_admin(&format!("issues/{}/deliveries?{}", issue_id, query))
+            .await;
+
+        // Assert
+        assert_eq!(response.status().as_u16(), 400, "{} was accepted", query);
+    }
+}
@@ -0,0 +1,77 @@
+use wiremock::{matchers::any, Mock, ResponseTemplate};
+
+use crate::helpers::{spawn_app, spawn_app_with};
+use crate::newsletter::create_confirmed_subscriber;
+
+#[tokio::test]
+async fn metrics_expose_the_email_throttle() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.email_client.messages_per_second = 5.0;
+        c.email_client.max_in_flight = 3;
+    })
+    .await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+        .mount(&app.email_server)
+        .await;
+    app.post_newsletter(serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>"
+        }
+    }))
+    .await;
+
+    // Act
+    let response = app.get_admin("metrics").await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let metrics: serde_json::Value = response.json().await.unwrap();
+    let throttle = &metrics["email_client"]["throttle"];
+    assert_eq!(throttle["messages_per_second"], 5.0);
+    assert_eq!(throttle["max_in_flight"], 3);
+    assert_eq!(throttle["in_flight"], 0);
+    assert_eq!(throttle["rate_limited_responses"], 3);
+}
+
+#[tokio::test]
+async fn metrics_expose_the_email_circuit_breaker() {
+    // Arrange
+    let app = spawn_app_with(|c| c.email_client.circuit_breaker.failure_threshold = 1).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=sara%20kuzoi&email=sara_kuzoi%40tuta.io".into())
+        .await;
+
+    // Act
+    let response = app.get_admin("metrics").await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let metrics: serde_json::Value = response.json().await.unwrap();
+    let backend = &metrics["email_client"]["backends"][0];
+    assert_eq!(backend["name"], "primary");
+    let circuit_breaker = &backend["circuit_breaker"];
+    assert_eq!(circuit_breaker["state"], "open");
+    assert_eq!(circuit_breaker["failure_threshold"], 1);
+}
+
+#[tokio::test]
+async fn metrics_require_authentication() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::get(format!("{}/admin/metrics", &app.address))
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
@@ -0,0 +1,176 @@
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+
+use crate::helpers::{spawn_app, TestApp};
+
+// Insert a subscriber straight into the database, `days_ago` days in the past.
+async fn insert_subscriber(app: &TestApp, email: &str, status: &str, days_ago: i64) {
+    sqlx::query!(
+        r#"INSERT INTO subscriptions (id, email, email_normalised, name, subscribed_at, status)
+        VALUES ($1, $2, $3, $4, $5, $6)"#,
+        Uuid::new_v4(),
+        email,
+        email.to_lowercase(),
+        "sara kuzoi",
+        Utc::now() - Duration::days(days_ago),
+        status,
+    )
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to insert subscriber.");
+}
+
+fn emails(page: &serde_json::Value) -> Vec<String> {
+    page["subscribers"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| s["email"].as_str().unwrap().to_owned())
+        .collect()
+}
+
+#[tokio::test]
+async fn listing_subscribers_requires_authentication() {
+    // Arrange
+    let test_app = spawn_app().await;
+
+    // Act
+    let response = reqwest::get(&format!("{}/admin/subscribers", &test_app.address))
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(
+        r#"Basic realm="admin""#,
+        response.headers()["WWW-Authenticate"]
+    );
+}
+
+#[tokio::test]
+async fn subscribers_can_be_filtered_by_status_and_email() {
+    // Arrange
+    let test_app = spawn_app().await;
+    insert_subscriber(&test_app, "sara@tuta.io", "confirmed", 1).await;
+    insert_subscriber(&test_app, "calin@tuta.io", "pending_confirmation", 2).await;
+    insert_subscriber(&test_app, "sara@gmail.com", "confirmed", 3).await;
+
+    // Act
+    let response = test_app
+        .get_admin_subscribers(&[("status", "confirmed"), ("email", "TUTA")])
+        .await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let page: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(emails(&page), vec!["sara@tuta.io"]);
+    assert!(page["next_cursor"].is_null());
+}
+
+#[tokio::test]
+async fn subscribers_can_be_filtered_by_subscription_date() {
+    // Arrange
+    let test_app = spawn_app().await;
+    insert_subscriber(&test_app, "recent@tuta.io", "confirmed", 1).await;
+    insert_subscriber(&test_app, "old@tuta.io", "confirmed", 30).await;
+    let since = (Utc::now() - Duration::days(7)).to_rfc3339();
+
+    // Act
+    let response = test_app
+        .get_admin_subscribers(&[("subscribed_after", &since)])
+        .await;
+
+    // Assert
+    let page: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(emails(&page), vec!["recent@tuta.io"]);
+}
+
+#[tokio::test]
+async fn following_the_cursor_walks_every_subscriber_once() {
+    // Arrange
+    let test_app = spawn_app().await;
+    for i in 0..5 {
+        insert_subscriber(&test_app, &format!("reader{}@tuta.io", i), "confirmed", i).await;
+    }
+
+    // Act
+    let mut seen = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let mut query = vec![("sort", "email"), ("order", "desc"), ("limit", "2")];
+        if let Some(cursor) = &cursor {
+            query.push(("cursor", cursor.as_str()));
+        }
+        let response = test_app.get_admin_subscribers(&query).await;
+        assert_eq!(200, response.status().as_u16());
+        let page: serde_json::Value = response.json().await.unwrap();
+        seen.extend(emails(&page));
+        cursor = page["next_cursor"].as_str().map(str::to_owned);
+        if cursor.is_none() {
+            break;
+        }
+    }
+
+    // Assert
+    assert_eq!(
+        seen,
+        vec![
+            "reader4@tuta.io",
+            "reader3@tuta.io",
+            "reader2@tuta.io",
+            "reader1@tuta.io",
+            "reader0@tuta.io",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn invalid_listing_parameters_are_rejected_with_a_400() {
+    // Arrange
+    let test_app = spawn_app().await;
+    let test_cases = vec![
+        (("cursor", "definitely-not-a-cursor"), "invalid cursor"),
+        (("limit", "0"), "limit too small"),
+        (("limit", "100000"), "limit too large"),
+        (("sort", "password"), "unknown sort field"),
+        (("subscribed_after", "yesterday"), "invalid timestamp"),
+    ];
+
+    for (query, description) in test_cases {
+        // Act
+        let response = test_app.get_admin_subscribers(&[query]).await;
+
+        // Assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the query had an {}.",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn export_streams_the_filtered_subscribers_as_csv() {
+    // Arrange
+    let test_app = spawn_app().await;
+    insert_subscriber(&test_app, "sara@tuta.io", "confirmed", 1).await;
+    insert_subscriber(&test_app, "calin@tuta.io", "pending_confirmation", 2).await;
+
+    // Act
+    let response = test_app
+        .get_admin_subscribers_export(&[("status", "confirmed")])
+        .await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(
+        "text/csv; charset=utf-8",
+        response.headers()["Content-Type"]
+    );
+    let body = response.text().await.unwrap();
+    let lines: Vec<_> = body.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0], "id,email,name,status,subscribed_at");
+    assert!(lines[1].contains(",sara@tuta.io,sara kuzoi,confirmed,"));
+}
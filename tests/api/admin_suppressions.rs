@@ -0,0 +1,258 @@
+use wiremock::{matchers::any, Mock, ResponseTemplate};
+
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+use crate::newsletter::create_confirmed_subscriber;
+
+async fn suppress(app: &TestApp, body: serde_json::Value) -> serde_json::Value {
+    let response = app.post_admin("suppressions", body).await;
+    assert_eq!(response.status().as_u16(), 201);
+    response.json().await.unwrap()
+}
+
+async fn search(app: &TestApp, query: &str) -> Vec<serde_json::Value> {
+    let response = app.get_admin(&format!("suppressions?q={}", query)).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    body["suppressions"].as_array().unwrap().clone()
+}
+
+#[tokio::test]
+async fn managing_the_suppression_list_requires_authentication() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/admin/suppressions", &app.address))
+        .json(&serde_json::json!({ "email": "ursula@example.com", "reason": "Legal" }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(401, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn suppressions_can_be_added_searched_and_removed() {
+    // Arrange
+    let app = spawn_app().await;
+    let entry = suppress(
+        &app,
+        serde_json::json!({ "email": "Ursula@Example.com", "reason": "Legal request #42" }),
+    )
+    .await;
+    suppress(
+        &app,
+        serde_json::json!({ "domain": "spam.example", "reason": "Spam trap" }),
+    )
+    .await;
+
+    // Act
+    let found = search(&app, "legal").await;
+    let removed = app
+        .delete_admin(&format!(
+            "suppressions/{}",
+            entry["suppression_id"].as_str().unwrap()
+        ))
+        .await;
+    let remaining = search(&app, "").await;
+
+    // Assert
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0]["email"], "ursula@example.com");
+    assert_eq!(found[0]["added_by"], app.test_user.username.as_str());
+    assert_eq!(removed.status().as_u16(), 200);
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(remaining[0]["domain"], "spam.example");
+}
+
+#[tokio::test]
+async fn invalid_suppressions_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    suppress(
+        &app,
+        serde_json::json!({ "email": "ursula@example.com", "reason": "Legal" }),
+    )
+    .await;
+    let test_cases = vec![
+        (
+            serde_json::json!({ "reason": "Legal" }),
+            "neither an email nor a domain",
+        ),
+        (
+            serde_json::json!({ "email": "a@example.com", "domain": "example.com", "reason": "Legal" }),
+            "both an email and a domain",
+        ),
+        (
+            serde_json::json!({ "email": "a@example.com", "reason": " " }),
+            "no reason",
+        ),
+        (
+            serde_json::json!({ "email": "URSULA@example.com", "reason": "Legal" }),
+            "an address that is already suppressed",
+        ),
+    ];
+
+    for (body, description) in test_cases {
+        // Act
+        let response = app.post_admin("suppressions", body).await;
+
+        // Assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the payload had {}.",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn suppressed_addresses_do_not_receive_confirmation_emails() {
+    // Arrange
+    let app = spawn_app().await;
+    suppress(
+        &app,
+        serde_json::json!({ "email": "sara_kuzoi@tuta.io", "reason": "Legal" }),
+    )
+    .await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_subscriptions("name=sara%20kuzoi&email=sara_kuzoi%40tuta.io".into())
+        .await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let subscribers = sqlx::query!("SELECT COUNT(*) AS count FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscribers.count, Some(0));
+}
+
+#[tokio::test]
+async fn queued_emails_to_addresses_suppressed_since_are_skipped() {
+    // Arrange
+    let app = spawn_app_with(|c| c.email_outbox.poll_interval_milliseconds = 100).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=sara%20kuzoi&email=sara_kuzoi%40tuta.io".into())
+        .await;
+
+    // Act
+    suppress(
+        &app,
+        serde_json::json!({ "email": "sara_kuzoi@tuta.io", "reason": "Legal" }),
+    )
+    .await;
+    // Skip the backoff following the failed attempt
+    sqlx::query!("UPDATE email_outbox SET next_attempt_at = now()")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Assert
+    let mut status = String::new();
+    for _ in 0..50 {
+        status = sqlx::query!("SELECT status FROM email_outbox")
+            .fetch_one(&app.db_pool)
+            .await
+            .expect("Failed to fetch the queued email")
+            .status;
+        if status != "pending" {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(status, "skipped");
+}
+
+#[tokio::test]
+async fn suppressed_domains_do_not_receive_newsletters() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    suppress(
+        &app,
+        serde_json::json!({ "domain": "TUTA.io", "reason": "Domain owner asked us to stop" }),
+    )
+    .await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_newsletter(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>"
+            }
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    // Mock verifies on Drop that we haven't sent the newsletter email
+}
+
+#[tokio::test]
+async fn test_sends_to_suppressed_addresses_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    suppress(
+        &app,
+        serde_json::json!({ "email": "ursula@example.com", "reason": "Legal" }),
+    )
+    .await;
+    let draft: serde_json::Value = app
+        .post_admin(
+            "drafts",
+            serde_json::json!({
+                "title": "Draft title",
+                "content": { "text": "Draft body", "html": "<p>Draft body</p>" }
+            }),
+        )
+        .await
+        .json()
+        .await
+        .unwrap();
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_admin(
+            &format!(
+                "drafts/{}/test",
+                draft["newsletter_issue_id"].as_str().unwrap()
+            ),
+            serde_json::json!({ "recipients": ["ursula@example.com"] }),
+        )
+        .await;
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+}
@@ -0,0 +1,255 @@
+use chrono::{Duration, Utc};
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+use crate::helpers::{spawn_app, TestApp};
+use crate::newsletter::create_confirmed_subscriber;
+
+// No subscriber is needed: the issue is published even if nobody receives it
+async fn publish(app: &TestApp, title: &str, extra: serde_json::Value) {
+    let mut body = serde_json::json!({
+        "title": title,
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as <strong>HTML</strong></p>"
+        }
+    });
+    body.as_object_mut()
+        .unwrap()
+        .extend(extra.as_object().unwrap().clone());
+    let response = app.post_newsletter(body).await;
+    assert!(response.status().is_success());
+}
+
+async fn get(app: &TestApp, path: &str) -> reqwest::Response {
+    reqwest::get(format!("{}{}", app.address, path))
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn published_issues_are_listed_in_the_archive() {
+    // Arrange
+    let app = spawn_app().await;
+    publish(&app, "Our first issue", serde_json::json!({})).await;
+
+    // Act
+    let response = get(&app, "/issues").await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "text/html; charset=utf-8"
+    );
+    let page = response.text().await.unwrap();
+    assert!(page.contains(r#"/issues/our-first-issue">Our first issue</a>"#));
+}
+
+#[tokio::test]
+async fn archived_issues_render_their_html_body() {
+    // Arrange
+    let app = spawn_app().await;
+    publish(&app, "Our first issue", serde_json::json!({})).await;
+
+    // Act
+    let response = get(&app, "/issues/our-first-issue").await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let page = response.text().await.unwrap();
+    assert!(page.contains("<h1>Our first issue</h1>"));
+    assert!(page.contains("<p>Newsletter body as <strong>HTML</strong></p>"));
+}
+
+#[tokio::test]
+async fn issues_with_the_same_title_get_distinct_slugs() {
+    // Arrange
+    let app = spawn_app().await;
+    publish(&app, "Weekly digest", serde_json::json!({})).await;
+    publish(&app, "Weekly digest", serde_json::json!({})).await;
+
+    // Act
+    let first = get(&app, "/issues/weekly-digest").await;
+    let second = get(&app, "/issues/weekly-digest-2").await;
+
+    // Assert
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn issues_opted_out_of_the_archive_are_not_public() {
+    // Arrange
+    let app = spawn_app().await;
+    publish(
+        &app,
+        "Members only",
+        serde_json::json!({ "archive": false }),
+    )
+    .await;
+
+    // Act
+    let index = get(&app, "/issues").await.text().await.unwrap();
+    let page = get(&app, "/issues/members-only").await;
+    let feed = get(&app, "/feed.xml").await.text().await.unwrap();
+
+    // Assert
+    assert!(!index.contains("Members only"));
+    assert_eq!(page.status().as_u16(), 404);
+    assert!(!feed.contains("Members only"));
+}
+
+#[tokio::test]
+async fn scheduled_issues_are_not_archived_before_they_are_sent() {
+    // Arrange
+    let app = spawn_app().await;
+    publish(
+        &app,
+        "Coming soon",
+        serde_json::json!({ "send_at": (Utc::now() + Duration::hours(1)).to_rfc3339() }),
+    )
+    .await;
+
+    // Act
+    let index = get(&app, "/issues").await.text().await.unwrap();
+    let page = get(&app, "/issues/coming-soon").await;
+
+    // Assert
+    assert!(!index.contains("Coming soon"));
+    assert_eq!(page.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn unknown_slugs_are_not_found() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = get(&app, "/issues/does-not-exist").await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn the_atom_feed_lists_the_latest_issues_first() {
+    // Arrange
+    let app = spawn_app().await;
+    publish(&app, "First issue", serde_json::json!({})).await;
+    publish(&app, "Second issue", serde_json::json!({})).await;
+
+    // Act
+    let response = get(&app, "/feed.xml").await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "application/atom+xml; charset=utf-8"
+    );
+    let feed = response.text().await.unwrap();
+    let first = feed.find("<title>First issue</title>").unwrap();
+    let second = feed.find("<title>Second issue</title>").unwrap();
+    assert!(second < first);
+    assert!(feed.contains("/issues/first-issue</id>"));
+    assert!(
+        feed.contains("&lt;p&gt;Newsletter body as &lt;strong&gt;HTML&lt;/strong&gt;&lt;/p&gt;")
+    );
+}
+
+#[tokio::test]
+async fn the_rss_feed_lists_published_issues() {
+    // Arrange
+    let app = spawn_app().await;
+    publish(&app, "First issue", serde_json::json!({})).await;
+
+    // Act
+    let response = get(&app, "/feed.rss").await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "application/rss+xml; charset=utf-8"
+    );
+    let feed = response.text().await.unwrap();
+    assert!(feed.contains("/issues/first-issue</link>"));
+}
+
+#[tokio::test]
+async fn scheduled_issues_get_their_slug_when_they_are_published() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    publish(
+        &app,
+        "Coming soon",
+        serde_json::json!({ "send_at": (Utc::now() + Duration::hours(1)).to_rfc3339() }),
+    )
+    .await;
+
+    // Assert
+    let saved = sqlx::query!("SELECT slug FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the issue.");
+    assert_eq!(saved.slug.as_deref(), Some("coming-soon"));
+}
+
+#[tokio::test]
+async fn issues_that_failed_to_reach_every_subscriber_are_not_archived() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+    let response = app
+        .post_newsletter(serde_json::json!({
+            "title": "Lost in the mail",
+            "content": { "html": "<p>Newsletter body as HTML</p>" }
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 500);
+
+    // Act
+    let index = get(&app, "/issues").await.text().await.unwrap();
+    let page = get(&app, "/issues/lost-in-the-mail").await;
+
+    // Assert
+    assert!(!index.contains("Lost in the mail"));
+    assert_eq!(page.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn inline_images_are_served_with_the_archived_issue() {
+    // Arrange
+    let app = spawn_app().await;
+    let response = app
+        .post_newsletter_with_attachments(
+            serde_json::json!({
+                "title": "With a logo",
+                "content": { "html": r#"<p><img src="cid:logo.png"> Hello</p>"# }
+            }),
+            &[("inline", "logo.png", "image/png", b"PNG")],
+        )
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    // Act
+    let page = get(&app, "/issues/with-a-logo").await.text().await.unwrap();
+    let image = get(&app, "/issues/with-a-logo/inline/logo.png").await;
+
+    // Assert
+    assert!(page.contains("/issues/with-a-logo/inline/logo.png"));
+    assert!(!page.contains("cid:"));
+    assert_eq!(image.status().as_u16(), 200);
+    assert_eq!(image.headers()["Content-Type"], "image/png");
+    assert_eq!(image.bytes().await.unwrap().as_ref(), b"PNG");
+}
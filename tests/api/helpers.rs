@@ -2,12 +2,17 @@ use argon2::password_hash::SaltString;
 use argon2::{Argon2, PasswordHasher};
 use linkify::LinkKind;
 use once_cell::sync::Lazy;
+use secrecy::ExposeSecret;
 use sqlx::{Connection, Executor, PgConnection, PgPool};
 use uuid::Uuid;
 use wiremock::MockServer;
-use zero2prod::configuration::{get_configuration, DatabaseSettings};
+use zero2prod::bot_protection::FormTokenSigner;
+use zero2prod::configuration::{
+    get_configuration, DatabaseSettings, PostmarkWebhookSettings, Settings,
+};
 use zero2prod::startup::{get_connection_pool, Application};
 use zero2prod::telemetry::{get_subscriber, init_subscriber};
+use zero2prod::unsubscribe::UnsubscribeLinks;
 
 // Ensure that the `tracing` stack is only initialized once using `one_cell`.
 static TRACING: Lazy<()> = Lazy::new(|| {
@@ -28,6 +33,9 @@ pub struct TestApp {
     pub email_server: MockServer,
     pub port: u16,
     pub test_user: TestUser,
+    pub form_tokens: FormTokenSigner,
+    pub unsubscribe_links: UnsubscribeLinks,
+    pub postmark_webhook: PostmarkWebhookSettings,
 }
 
 // Confirmation links embedded in the request to the email API.
@@ -38,7 +46,7 @@ pub struct ConfirmationLinks {
 
 pub struct TestUser {
     user_id: Uuid,
-    username: String,
+    pub username: String,
     password: String,
 }
 
@@ -70,14 +78,44 @@ impl TestUser {
 }
 
 impl TestApp {
+    // Submit the subscription form as a human would, with a form token issued a while ago.
     pub async fn post_subscriptions(&self, body: String) -> reqwest::Response {
-        reqwest::Client::new()
+        let form_token = self
+            .form_tokens
+            .issue_at(chrono::Utc::now() - chrono::Duration::minutes(1));
+        self.post_subscriptions_without_form_token(format!("{}&form_token={}", body, form_token))
+            .await
+    }
+
+    pub async fn post_subscriptions_without_form_token(&self, body: String) -> reqwest::Response {
+        let response = reqwest::Client::new()
             .post(format!("{}/subscriptions", &self.address))
             .header("Content-Type", "application/x-www-form-urlencoded")
             .body(body)
             .send()
             .await
-            .expect("Failed to execute request.")
+            .expect("Failed to execute request.");
+        self.wait_for_first_relay_attempts().await;
+        response
+    }
+
+    // The confirmation email is relayed in the background once the response is sent.
+    // Wait until every email in the outbox has been tried at least once.
+    pub async fn wait_for_first_relay_attempts(&self) {
+        for _ in 0..100 {
+            let untried = sqlx::query!(
+                r#"SELECT COUNT(*) AS "count!" FROM email_outbox WHERE status IN ('pending', 'sending') AND attempts = 0"#
+            )
+            .fetch_one(&self.db_pool)
+            .await
+            .expect("Failed to count the untried emails.")
+            .count;
+            if untried == 0 {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("The queued emails were not relayed in time.");
     }
 
     // Extract the confirmation links embedded in the request to the email API.
@@ -97,23 +135,177 @@ impl TestApp {
             confirmation_link
         };
 
-        let html = get_link(&body["HtmlBody"].as_str().unwrap());
-        let plain_text = get_link(&body["TextBody"].as_str().unwrap());
+        let html = get_link(body["HtmlBody"].as_str().unwrap());
+        let plain_text = get_link(body["TextBody"].as_str().unwrap());
         ConfirmationLinks { html, plain_text }
     }
 
     pub async fn post_newsletter(&self, body: serde_json::Value) -> reqwest::Response {
         reqwest::Client::new()
-            .post(&format!("{}/newsletter", &self.address))
+            .post(format!("{}/newsletter", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Publish through the `multipart/form-data` variant of the endpoint.
+    /// Each file is given as `(part name, file name, content type, content)`.
+    pub async fn post_newsletter_with_attachments(
+        &self,
+        body: serde_json::Value,
+        files: &[(&str, &str, &str, &[u8])],
+    ) -> reqwest::Response {
+        let boundary = "zero2prod-test-boundary";
+        let mut payload = format!(
+            "--{boundary}\r\n\
+            Content-Disposition: form-data; name=\"body\"\r\n\
+            Content-Type: application/json\r\n\r\n{body}\r\n"
+        )
+        .into_bytes();
+        for (part, file_name, content_type, content) in files {
+            payload.extend_from_slice(
+                format!(
+                    "--{boundary}\r\n\
+                    Content-Disposition: form-data; name=\"{part}\"; filename=\"{file_name}\"\r\n\
+                    Content-Type: {content_type}\r\n\r\n"
+                )
+                .as_bytes(),
+            );
+            payload.extend_from_slice(content);
+            payload.extend_from_slice(b"\r\n");
+        }
+        payload.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+        reqwest::Client::new()
+            .post(format!("{}/newsletter", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .header(
+                "Content-Type",
+                format!("multipart/form-data; boundary={boundary}"),
+            )
+            .body(payload)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_postmark_webhook(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/webhooks/postmark", &self.address))
+            .basic_auth(
+                &self.postmark_webhook.username,
+                Some(self.postmark_webhook.password.expose_secret()),
+            )
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_admin_subscribers(&self, query: &[(&str, &str)]) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}/admin/subscribers", &self.address))
+            .query(query)
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_scheduled_issues(&self) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}/admin/issues/scheduled", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn reschedule_issue(
+        &self,
+        newsletter_issue_id: &str,
+        body: serde_json::Value,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .put(format!(
+                "{}/admin/issues/{}/schedule",
+                &self.address, newsletter_issue_id
+            ))
             .basic_auth(&self.test_user.username, Some(&self.test_user.password))
             .json(&body)
             .send()
             .await
             .expect("Failed to execute request.")
     }
+
+    pub async fn cancel_scheduled_issue(&self, newsletter_issue_id: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .delete(format!(
+                "{}/admin/issues/{}/schedule",
+                &self.address, newsletter_issue_id
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_admin(&self, path: &str, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/{}", &self.address, path))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn put_admin(&self, path: &str, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .put(format!("{}/admin/{}", &self.address, path))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn delete_admin(&self, path: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .delete(format!("{}/admin/{}", &self.address, path))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_admin(&self, path: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}/admin/{}", &self.address, path))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_admin_subscribers_export(&self, query: &[(&str, &str)]) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}/admin/subscribers/export.csv", &self.address))
+            .query(query)
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
 }
 
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+// Spawn the application, letting the test adjust the randomised configuration first.
+pub async fn spawn_app_with(customise: impl FnOnce(&mut Settings)) -> TestApp {
     // The first time `initialize` is invoked the code in `TRACING` is executed. All other invocations will instead skip execution.
     Lazy::force(&TRACING);
 
@@ -128,6 +320,7 @@ pub async fn spawn_app() -> TestApp {
         c.application.port = 0;
         // Use the email mock server as email API
         c.email_client.base_url = email_server.uri();
+        customise(&mut c);
         c
     };
 
@@ -138,7 +331,7 @@ pub async fn spawn_app() -> TestApp {
         .await
         .expect("Failed to build server");
     let application_port = application.port();
-    let _ = tokio::spawn(application.run_until_stopped());
+    tokio::spawn(application.run_until_stopped());
     let test_user = TestUser::generate();
     let test_app = TestApp {
         address: format!("http://localhost:{}", application_port),
@@ -146,6 +339,12 @@ pub async fn spawn_app() -> TestApp {
         db_pool: get_connection_pool(&configuration.database),
         email_server,
         test_user,
+        form_tokens: configuration.bot_protection.form_token_signer(),
+        unsubscribe_links: UnsubscribeLinks::new(
+            configuration.application.base_url.clone(),
+            configuration.application.hmac_secret.clone(),
+        ),
+        postmark_webhook: configuration.postmark_webhook.clone(),
     };
     test_app.test_user.store(&test_app.db_pool).await;
     test_app
@@ -1,5 +1,14 @@
+mod admin_drafts;
+mod admin_issues;
+mod admin_metrics;
+mod admin_subscribers;
+mod admin_suppressions;
+mod archive;
 mod health_check;
 mod helpers;
 mod newsletter;
+mod postmark_webhook;
+mod rate_limiting;
 mod subscriptions;
 mod subscriptions_confirm;
+mod tracking;
@@ -1,9 +1,11 @@
 use wiremock::{
-    matchers::{any, method, path},
+    matchers::{any, body_partial_json, method, path},
     Mock, ResponseTemplate,
 };
 
-use crate::helpers::{spawn_app, ConfirmationLinks, TestApp};
+use crate::helpers::{spawn_app, spawn_app_with, ConfirmationLinks, TestApp};
+use zero2prod::configuration::{MessageStreamSettings, SenderIdentitySettings};
+use zero2prod::unsubscribe::LinkPurpose;
 
 #[tokio::test]
 async fn newsletters_are_not_delivered_to_unconfirmed_subscribers() {
@@ -60,6 +62,126 @@ async fn newsletters_are_delivered_to_confirmed_subscribers() {
 
     // Assert
     assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["warnings"], serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn publishing_an_oversized_newsletter_returns_a_clipping_warning() {
+    // Arrange
+    let test_app = spawn_app().await;
+    create_confirmed_subscriber(&test_app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    // Act
+    let response = test_app
+        .post_newsletter(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": format!("<p>{}</p>", "a".repeat(110 * 1024))
+            }
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let warnings = body["warnings"].as_array().unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].as_str().unwrap().contains("Gmail"));
+}
+
+#[tokio::test]
+async fn newsletters_go_through_the_broadcast_stream() {
+    // Arrange
+    let test_app = spawn_app().await;
+    create_confirmed_subscriber(&test_app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .and(body_partial_json(serde_json::json!({
+            "MessageStream": "broadcast",
+            "Tag": "newsletter"
+        })))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    // Act
+    let response = test_app
+        .post_newsletter(serde_json::json!({
+            "title": "Newsletter title",
+            "content": { "html": "<p>Newsletter body as HTML</p>" }
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let email_request = test_app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert!(body["Metadata"]["newsletter_issue_id"].is_string());
+    assert!(body["Metadata"]["subscriber_id"].is_string());
+}
+
+#[tokio::test]
+async fn newsletters_can_be_sent_from_another_sender_identity() {
+    // Arrange
+    let test_app = spawn_app_with(|c| {
+        c.email_client.sender_identities.insert(
+            "books".into(),
+            SenderIdentitySettings {
+                email: "books@example.com".into(),
+                name: Some("Book Club".into()),
+                reply_to_email: None,
+                message_streams: Some(MessageStreamSettings {
+                    transactional: "outbound".into(),
+                    broadcast: "book-club".into(),
+                }),
+            },
+        );
+    })
+    .await;
+    create_confirmed_subscriber(&test_app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .and(body_partial_json(serde_json::json!({
+            "From": "\"Book Club\" <books@example.com>",
+            "MessageStream": "book-club"
+        })))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+    let body = |sender: &str| {
+        serde_json::json!({
+            "title": "Newsletter title",
+            "content": { "html": "<p>Newsletter body as HTML</p>" },
+            "sender": sender
+        })
+    };
+
+    // Act
+    let unknown = test_app.post_newsletter(body("podcast")).await;
+    let response = test_app.post_newsletter(body("books")).await;
+
+    // Assert
+    assert_eq!(unknown.status().as_u16(), 400);
+    assert_eq!(response.status().as_u16(), 200);
 }
 
 #[tokio::test]
@@ -82,6 +204,23 @@ async fn newsletter_returns_a_400_for_invalid_data() {
             }),
             "missing content",
         ),
+        (
+            serde_json::json!({
+                "title": "Newsletter!",
+                "content": {}
+            }),
+            "empty content",
+        ),
+        (
+            serde_json::json!({
+                "title": "Newsletter!",
+                "content": {
+                    "markdown": "Newsletter body as **Markdown**",
+                    "html": "<p>Newsletter body as HTML</p>",
+                }
+            }),
+            "both Markdown and HTML content",
+        ),
     ];
     for (invalid_body, error_message) in test_cases {
         // Act
@@ -104,7 +243,7 @@ async fn requests_missing_authorization_are_rejected() {
 
     // Act
     let response = reqwest::Client::new()
-        .post(&format!("{}/newsletter", &test_app.address))
+        .post(format!("{}/newsletter", &test_app.address))
         .json(&serde_json::json!({
             "title": "Newsletter title",
             "content": {
@@ -124,6 +263,584 @@ async fn requests_missing_authorization_are_rejected() {
     );
 }
 
+#[tokio::test]
+async fn scheduled_newsletters_are_not_delivered_right_away() {
+    // Arrange
+    let test_app = spawn_app().await;
+    create_confirmed_subscriber(&test_app).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&test_app.email_server)
+        .await;
+
+    // Act
+    let send_at = chrono::Utc::now() + chrono::Duration::hours(1);
+    let response = test_app
+        .post_newsletter(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>"
+            },
+            "send_at": send_at.to_rfc3339()
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let saved = sqlx::query!("SELECT newsletter_issue_id, status FROM newsletter_issues")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .expect("Failed to fetch the newsletter issue.");
+    assert_eq!(
+        body["newsletter_issue_id"],
+        saved.newsletter_issue_id.to_string()
+    );
+    assert_eq!(saved.status, "scheduled");
+}
+
+#[tokio::test]
+async fn scheduled_newsletters_are_delivered_once_due() {
+    // Arrange
+    let test_app =
+        spawn_app_with(|c| c.newsletter_scheduler.poll_interval_milliseconds = 100).await;
+    create_confirmed_subscriber(&test_app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    // Act
+    let send_at = chrono::Utc::now() + chrono::Duration::seconds(1);
+    let response = test_app
+        .post_newsletter(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>"
+            },
+            "send_at": send_at.to_rfc3339()
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+
+    // Assert
+    let mut status = String::new();
+    for _ in 0..50 {
+        status = sqlx::query!("SELECT status FROM newsletter_issues")
+            .fetch_one(&test_app.db_pool)
+            .await
+            .expect("Failed to fetch the newsletter issue.")
+            .status;
+        if status == "sent" {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(status, "sent");
+}
+
+#[tokio::test]
+async fn the_scheduler_takes_over_issues_whose_delivery_stalled() {
+    // Arrange
+    let test_app =
+        spawn_app_with(|c| c.newsletter_scheduler.poll_interval_milliseconds = 100).await;
+    create_confirmed_subscriber(&test_app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    let send_at = chrono::Utc::now() + chrono::Duration::hours(1);
+    for title in ["Stalled issue", "Issue being delivered"] {
+        let response = test_app
+            .post_newsletter(serde_json::json!({
+                "title": title,
+                "content": {
+                    "text": "Newsletter body as plain text",
+                    "html": "<p>Newsletter body as HTML</p>"
+                },
+                "send_at": send_at.to_rfc3339()
+            }))
+            .await;
+        assert_eq!(response.status().as_u16(), 202);
+    }
+
+    // Act - the instance delivering the first issue died long ago, the second one is alive
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET status = 'sending',
+            claimed_at = CASE WHEN title = 'Stalled issue' THEN now() - interval '1 hour' ELSE now() END
+        "#
+    )
+    .execute(&test_app.db_pool)
+    .await
+    .unwrap();
+
+    // Assert
+    let mut status = String::new();
+    for _ in 0..50 {
+        status = sqlx::query!("SELECT status FROM newsletter_issues WHERE title = 'Stalled issue'")
+            .fetch_one(&test_app.db_pool)
+            .await
+            .expect("Failed to fetch the newsletter issue.")
+            .status;
+        if status == "sent" {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(status, "sent");
+    let other =
+        sqlx::query!("SELECT status FROM newsletter_issues WHERE title = 'Issue being delivered'")
+            .fetch_one(&test_app.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(other.status, "sending");
+}
+
+#[tokio::test]
+async fn newsletters_cannot_be_scheduled_in_the_past_or_without_a_time_zone() {
+    // Arrange
+    let test_app = spawn_app().await;
+    let test_cases = vec![
+        (
+            (chrono::Utc::now() - chrono::Duration::minutes(5)).to_rfc3339(),
+            "in the past",
+        ),
+        ("2099-01-01T09:00:00".to_string(), "without a time zone"),
+    ];
+
+    for (send_at, description) in test_cases {
+        // Act
+        let response = test_app
+            .post_newsletter(serde_json::json!({
+                "title": "Newsletter title",
+                "content": {
+                    "text": "Newsletter body as plain text",
+                    "html": "<p>Newsletter body as HTML</p>"
+                },
+                "send_at": send_at
+            }))
+            .await;
+
+        // Assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when send_at was {}.",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn newsletters_are_personalised_for_each_subscriber() {
+    // Arrange
+    let test_app = spawn_app().await;
+    create_confirmed_subscriber(&test_app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    // Act
+    test_app
+        .post_newsletter(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>"
+            }
+        }))
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Assert
+    let email_request = test_app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let text_body = body["TextBody"].as_str().unwrap();
+    assert!(text_body.contains("Hi sara kuzoi,"));
+    assert!(text_body.contains("Newsletter body as plain text"));
+    assert!(text_body.contains("/subscriptions/unsubscribe?subscriber_id="));
+    assert_eq!(body["Headers"][0]["Name"], "List-Unsubscribe");
+    assert_eq!(body["Headers"][1]["Value"], "List-Unsubscribe=One-Click");
+    assert!(body["HtmlBody"]
+        .as_str()
+        .unwrap()
+        .contains("<p>Newsletter body as HTML</p>"));
+}
+
+#[tokio::test]
+async fn the_plain_text_body_is_derived_from_html_when_missing() {
+    // Arrange
+    let test_app = spawn_app().await;
+    create_confirmed_subscriber(&test_app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    // Act
+    let response = test_app
+        .post_newsletter(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "html": r#"<h1>News</h1><p>Read <a href="https://example.com/post">our post</a>.</p>"#
+            }
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let email_request = test_app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let text_body = body["TextBody"].as_str().unwrap();
+    assert!(text_body.contains("# News"));
+    assert!(text_body.contains("Read [our post][1]."));
+    assert!(text_body.contains("[1]: https://example.com/post"));
+}
+
+#[tokio::test]
+async fn markdown_content_produces_both_parts_of_the_email() {
+    // Arrange
+    let test_app = spawn_app().await;
+    create_confirmed_subscriber(&test_app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    // Act
+    let response = test_app
+        .post_newsletter(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "markdown": "Read [our post](https://example.com/post).\n\n<script>alert('hi')</script>"
+            },
+            "tracking": false
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let email_request = test_app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let html_body = body["HtmlBody"].as_str().unwrap();
+    assert!(html_body.contains("<h1>Newsletter title</h1>"));
+    assert!(html_body.contains(r#"<a href="https://example.com/post""#));
+    assert!(!html_body.contains("<script>"));
+    let text_body = body["TextBody"].as_str().unwrap();
+    assert!(text_body.contains("# Newsletter title"));
+    assert!(text_body.contains("Read [our post][1]."));
+}
+
+#[tokio::test]
+async fn html_content_is_sanitised_before_it_is_sent() {
+    // Arrange
+    let test_app = spawn_app().await;
+    create_confirmed_subscriber(&test_app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    // Act
+    let response = test_app
+        .post_newsletter(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "html": r#"<style>p { color: red }</style><p>Read <a href="/posts/1">our post</a></p><script>alert('hi')</script>"#,
+                "text": "Newsletter body as plain text"
+            },
+            "tracking": false
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let email_request = test_app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let html_body = body["HtmlBody"].as_str().unwrap();
+    assert!(!html_body.contains("<script>"));
+    assert!(!html_body.contains("<style>"));
+    assert!(html_body.contains(r#"<p style="color: red">"#));
+    assert!(html_body.contains(r#"href="http://127.0.0.1/posts/1""#));
+}
+
+#[tokio::test]
+async fn newsletters_can_be_published_with_attachments_and_inline_images() {
+    // Arrange
+    let test_app = spawn_app().await;
+    create_confirmed_subscriber(&test_app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .and(body_partial_json(serde_json::json!({
+            "Attachments": [
+                {
+                    "Name": "report.pdf",
+                    "Content": "JVBERi0xLjc=",
+                    "ContentType": "application/pdf"
+                },
+                {
+                    "Name": "logo.png",
+                    "Content": "UE5H",
+                    "ContentType": "image/png",
+                    "ContentID": "cid:logo.png"
+                }
+            ]
+        })))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    // Act
+    let response = test_app
+        .post_newsletter_with_attachments(
+            serde_json::json!({
+                "title": "Newsletter title",
+                "content": {
+                    "html": r#"<p><img src="cid:logo.png"> Our yearly report is attached.</p>"#
+                }
+            }),
+            &[
+                ("attachments", "report.pdf", "application/pdf", b"%PDF-1.7"),
+                ("inline", "logo.png", "image/png", b"PNG"),
+            ],
+        )
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let email_request = test_app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert!(body["HtmlBody"]
+        .as_str()
+        .unwrap()
+        .contains(r#"src="cid:logo.png""#));
+}
+
+#[tokio::test]
+async fn multipart_newsletters_are_validated() {
+    // Arrange
+    let test_app = spawn_app().await;
+    create_confirmed_subscriber(&test_app).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&test_app.email_server)
+        .await;
+    let body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": { "html": "<p>Newsletter body as HTML</p>" }
+    });
+    // Below 10 MB, but not once encoded in base64
+    let oversized = vec![0; 6 * 1000 * 1000];
+    let too_many_files: Vec<(&str, &str, &str, &[u8])> =
+        vec![("attachments", "report.pdf", "application/pdf", b"%PDF-1.7"); 21];
+
+    // Act
+    let unexpected_part = test_app
+        .post_newsletter_with_attachments(
+            body.clone(),
+            &[("photos", "logo.png", "image/png", b"PNG")],
+        )
+        .await;
+    let unsafe_content_id = test_app
+        .post_newsletter_with_attachments(
+            body.clone(),
+            &[("inline", "../logo one.png", "image/png", b"PNG")],
+        )
+        .await;
+    let too_many = test_app
+        .post_newsletter_with_attachments(body.clone(), &too_many_files)
+        .await;
+    let too_large = test_app
+        .post_newsletter_with_attachments(
+            body,
+            &[("attachments", "archive.zip", "application/zip", &oversized)],
+        )
+        .await;
+
+    // Assert
+    assert_eq!(unexpected_part.status().as_u16(), 400);
+    assert_eq!(unsafe_content_id.status().as_u16(), 400);
+    assert_eq!(too_many.status().as_u16(), 400);
+    assert_eq!(too_large.status().as_u16(), 413);
+}
+
+#[tokio::test]
+async fn unsubscribed_subscribers_no_longer_receive_newsletters() {
+    // Arrange
+    let test_app = spawn_app().await;
+    create_confirmed_subscriber(&test_app).await;
+    let subscriber = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap();
+    let unsubscribe_url = format!(
+        "{}/subscriptions/unsubscribe?subscriber_id={}&token={}",
+        test_app.address,
+        subscriber.id,
+        test_app
+            .unsubscribe_links
+            .token(LinkPurpose::Unsubscribe, subscriber.id)
+    );
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&test_app.email_server)
+        .await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(unsubscribe_url)
+        .form(&[("List-Unsubscribe", "One-Click")])
+        .send()
+        .await
+        .unwrap();
+    test_app
+        .post_newsletter(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>"
+            }
+        }))
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn opening_the_unsubscribe_link_only_asks_for_a_confirmation() {
+    // Arrange
+    let test_app = spawn_app().await;
+    create_confirmed_subscriber(&test_app).await;
+    let subscriber = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = reqwest::get(format!(
+        "{}/subscriptions/unsubscribe?subscriber_id={}&token={}",
+        test_app.address,
+        subscriber.id,
+        test_app
+            .unsubscribe_links
+            .token(LinkPurpose::Unsubscribe, subscriber.id)
+    ))
+    .await
+    .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains(r#"<form method="post">"#));
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn forged_unsubscribe_links_are_rejected() {
+    // Arrange
+    let test_app = spawn_app().await;
+    create_confirmed_subscriber(&test_app).await;
+    let subscriber = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/subscriptions/unsubscribe?subscriber_id={}&token={}",
+            test_app.address,
+            subscriber.id,
+            test_app
+                .unsubscribe_links
+                .token(LinkPurpose::Unsubscribe, uuid::Uuid::new_v4())
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}
+
 // Use the public API of the application under test to create an unconfirmed subscriber
 async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
     let body = "name=sara%20kuzoi&email=sara_kuzoi%40tuta.io";
@@ -147,10 +864,10 @@ async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
         .unwrap()
         .pop()
         .unwrap();
-    app.get_confirmation_links(&email_request)
+    app.get_confirmation_links(email_request)
 }
 
-async fn create_confirmed_subscriber(app: &TestApp) {
+pub async fn create_confirmed_subscriber(app: &TestApp) {
     let confirmation_links = create_unconfirmed_subscriber(app).await;
     reqwest::get(confirmation_links.html)
         .await
@@ -0,0 +1,152 @@
+use wiremock::{matchers::any, Mock, ResponseTemplate};
+
+use crate::helpers::{spawn_app, TestApp};
+use crate::newsletter::create_confirmed_subscriber;
+
+const SUBSCRIBER_EMAIL: &str = "sara_kuzoi@tuta.io";
+
+async fn subscriber_status(app: &TestApp) -> String {
+    sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .status
+}
+
+#[tokio::test]
+async fn hard_bounces_stop_further_newsletters() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_postmark_webhook(serde_json::json!({
+            "RecordType": "Bounce",
+            "Type": "HardBounce",
+            "TypeCode": 1,
+            "Email": SUBSCRIBER_EMAIL,
+            "Inactive": true
+        }))
+        .await;
+    app.post_newsletter(serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>"
+        }
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "bounced");
+    // Mock verifies on Drop that we haven't sent the newsletter email
+}
+
+#[tokio::test]
+async fn spam_complaints_mark_the_subscriber_as_complained() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // Act
+    let response = app
+        .post_postmark_webhook(serde_json::json!({
+            "RecordType": "SpamComplaint",
+            "Email": SUBSCRIBER_EMAIL.to_uppercase()
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "complained");
+}
+
+#[tokio::test]
+async fn soft_bounces_are_recorded_without_changing_the_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let body = serde_json::json!({
+        "RecordType": "Bounce",
+        "Type": "SoftBounce",
+        "Email": SUBSCRIBER_EMAIL,
+        "Inactive": false
+    });
+
+    // Act
+    let response = app.post_postmark_webhook(body.clone()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+    let event = sqlx::query!("SELECT record_type, email, payload FROM email_provider_events")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(event.record_type, "Bounce");
+    assert_eq!(event.email.as_deref(), Some(SUBSCRIBER_EMAIL));
+    let payload: serde_json::Value = serde_json::from_str(&event.payload).unwrap();
+    assert_eq!(payload, body);
+}
+
+#[tokio::test]
+async fn webhooks_without_valid_credentials_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let body = serde_json::json!({
+        "RecordType": "SpamComplaint",
+        "Email": SUBSCRIBER_EMAIL
+    });
+    let test_cases = vec![
+        (None, "no credentials"),
+        (Some("not-the-password"), "a wrong password"),
+    ];
+
+    for (password, description) in test_cases {
+        // Act
+        let mut request = reqwest::Client::new()
+            .post(format!("{}/webhooks/postmark", &app.address))
+            .json(&body);
+        if let Some(password) = password {
+            request = request.basic_auth(&app.postmark_webhook.username, Some(password));
+        }
+        let response = request.send().await.expect("Failed to execute request.");
+
+        // Assert
+        assert_eq!(
+            response.status().as_u16(),
+            401,
+            "The API did not reject a webhook with {}.",
+            description
+        );
+        assert_eq!(
+            r#"Basic realm="webhooks""#,
+            response.headers()["WWW-Authenticate"]
+        );
+    }
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn malformed_payloads_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_postmark_webhook(serde_json::json!({ "Email": SUBSCRIBER_EMAIL }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+}
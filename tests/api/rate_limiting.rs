@@ -0,0 +1,172 @@
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+use zero2prod::configuration::{RateLimitStoreKind, RouteLimitSettings};
+use zero2prod::rate_limiting::delete_full_buckets;
+
+use crate::helpers::spawn_app_with;
+
+fn one_request_per_minute() -> RouteLimitSettings {
+    RouteLimitSettings {
+        burst: 2,
+        requests_per_minute: 1,
+    }
+}
+
+async fn get_confirm(address: &str) -> reqwest::Response {
+    reqwest::get(&format!("{}/subscriptions/confirm", address))
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn form_tokens_are_rate_limited() {
+    // Arrange
+    let app =
+        spawn_app_with(|c| c.rate_limit.subscriptions_form_token = one_request_per_minute()).await;
+    let url = format!("{}/subscriptions/form_token", app.address);
+
+    // Act
+    let first = reqwest::get(&url).await.unwrap();
+    let second = reqwest::get(&url).await.unwrap();
+    let third = reqwest::get(&url).await.unwrap();
+
+    // Assert
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 200);
+    assert_eq!(third.status().as_u16(), 429);
+}
+
+#[tokio::test]
+async fn requests_over_the_limit_are_rejected_with_a_429() {
+    // Arrange
+    let app =
+        spawn_app_with(|c| c.rate_limit.subscriptions_confirm = one_request_per_minute()).await;
+
+    // Act
+    let first = get_confirm(&app.address).await;
+    let second = get_confirm(&app.address).await;
+    let third = get_confirm(&app.address).await;
+
+    // Assert
+    assert_eq!(first.status().as_u16(), 400);
+    assert_eq!(first.headers()["RateLimit-Limit"], "2");
+    assert_eq!(first.headers()["RateLimit-Remaining"], "1");
+    assert_eq!(first.headers()["RateLimit-Policy"], "2;w=120");
+    assert_eq!(second.status().as_u16(), 400);
+    assert_eq!(second.headers()["RateLimit-Remaining"], "0");
+    assert_eq!(third.status().as_u16(), 429);
+    assert_eq!(third.headers()["RateLimit-Remaining"], "0");
+    assert_eq!(third.headers()["Retry-After"], "60");
+}
+
+#[tokio::test]
+async fn each_route_has_its_own_budget() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.rate_limit.subscriptions_confirm = RouteLimitSettings {
+            burst: 1,
+            requests_per_minute: 1,
+        }
+    })
+    .await;
+    get_confirm(&app.address).await;
+
+    // Act
+    let response = app.post_subscriptions("name=le%20guin".into()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(response.headers()["RateLimit-Remaining"], "9");
+}
+
+#[tokio::test]
+async fn buckets_can_be_stored_in_postgres() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.rate_limit.store = RateLimitStoreKind::Postgres;
+        c.rate_limit.subscriptions_confirm = one_request_per_minute();
+    })
+    .await;
+
+    // Act
+    get_confirm(&app.address).await;
+    get_confirm(&app.address).await;
+    let response = get_confirm(&app.address).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 429);
+    let saved = sqlx::query!("SELECT key, tokens FROM rate_limit_buckets")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the rate limit bucket.");
+    assert_eq!(saved.key, "subscriptions_confirm:ip:127.0.0.1");
+    assert!(saved.tokens < 1.0);
+}
+
+#[tokio::test]
+async fn full_buckets_are_deleted_from_postgres() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.rate_limit.store = RateLimitStoreKind::Postgres;
+        c.rate_limit.subscriptions_confirm = one_request_per_minute();
+    })
+    .await;
+    get_confirm(&app.address).await;
+    sqlx::query!(
+        r#"
+        INSERT INTO rate_limit_buckets (key, tokens, updated_at, full_at)
+        VALUES ('subscriptions_confirm:ip:192.0.2.1', 2, now() - interval '1 hour', now() - interval '1 hour')
+        "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    // Act
+    let deleted = delete_full_buckets(&app.db_pool).await.unwrap();
+
+    // Assert
+    assert_eq!(deleted, 1);
+    let remaining = sqlx::query!("SELECT key FROM rate_limit_buckets")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the rate limit bucket.");
+    assert_eq!(remaining.key, "subscriptions_confirm:ip:127.0.0.1");
+}
+
+#[tokio::test]
+async fn repeated_subscriptions_for_the_same_address_are_rate_limited() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.rate_limit.subscriptions_per_email = Some(RouteLimitSettings {
+            burst: 1,
+            requests_per_minute: 1,
+        })
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let first = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let second = app
+        .post_subscriptions("name=le%20guin&email=Ursula_Le_Guin%40gmail.com".into())
+        .await;
+    let other = app
+        .post_subscriptions("name=sara&email=sara_kuzoi%40gmail.com".into())
+        .await;
+
+    // Assert
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 429);
+    assert!(second.headers().contains_key("Retry-After"));
+    assert_eq!(other.status().as_u16(), 200);
+}
@@ -1,9 +1,14 @@
+use secrecy::Secret;
+use uuid::Uuid;
 use wiremock::{
-    matchers::{method, path},
-    Mock, ResponseTemplate,
+    matchers::{body_partial_json, body_string_contains, method, path},
+    Mock, MockServer, ResponseTemplate,
 };
+use zero2prod::configuration::{ChallengeSettings, SenderIdentitySettings};
+use zero2prod::email_client::EmailKind;
+use zero2prod::email_normalisation::renormalise_subscriber_emails;
 
-use crate::helpers::spawn_app;
+use crate::helpers::{spawn_app, spawn_app_with};
 
 #[tokio::test]
 async fn subscribe_returns_a_200_for_valid_form_data() {
@@ -94,6 +99,30 @@ async fn subscribe_returns_a_400_when_fields_are_present_but_empty() {
     }
 }
 
+#[tokio::test]
+async fn subscribe_returns_a_400_for_disposable_and_role_addresses() {
+    let test_app = spawn_app().await;
+    let test_cases = vec![
+        (
+            "name=kara&email=karaiozus%40mailinator.com",
+            "mailinator.com",
+        ),
+        ("name=kara&email=postmaster%40gmail.com", "Role addresses"),
+    ];
+
+    for (invalid_body, expected_message) in test_cases {
+        let response = test_app.post_subscriptions(invalid_body.into()).await;
+
+        assert_eq!(400, response.status().as_u16());
+        let body = response.text().await.unwrap();
+        assert!(
+            body.contains(expected_message),
+            "Unexpected validation message: {}",
+            body
+        );
+    }
+}
+
 #[tokio::test]
 async fn subscribe_sends_a_confirmation_email_with_a_link() {
     // Arrange
@@ -111,11 +140,76 @@ async fn subscribe_sends_a_confirmation_email_with_a_link() {
 
     // Assert
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
 
     assert_eq!(confirmation_links.html, confirmation_links.plain_text);
 }
 
+#[tokio::test]
+async fn confirmation_emails_go_through_the_transactional_stream() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=sara%20kuzoi&email=sara_kuzoi%40tuta.io";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .and(body_partial_json(serde_json::json!({
+            "From": "\"Zero To Production\" <test@gmail.com>",
+            "MessageStream": "outbound",
+            "Tag": "confirmation"
+        })))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn confirmation_emails_come_from_the_identity_configured_for_them() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.email_client.sender_identities.insert(
+            "welcome".into(),
+            SenderIdentitySettings {
+                email: "welcome@example.com".into(),
+                name: None,
+                reply_to_email: Some("support@example.com".into()),
+                message_streams: None,
+            },
+        );
+        c.email_client
+            .senders_by_kind
+            .insert(EmailKind::Confirmation, "welcome".into());
+    })
+    .await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .and(body_partial_json(serde_json::json!({
+            "From": "welcome@example.com",
+            "ReplyTo": "support@example.com",
+            "MessageStream": "outbound"
+        })))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_subscriptions("name=sara%20kuzoi&email=sara_kuzoi%40tuta.io".into())
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+}
+
 #[tokio::test]
 async fn subscribing_twice_sends_two_confirmation_emails() {
     let app = spawn_app().await;
@@ -134,6 +228,287 @@ async fn subscribing_twice_sends_two_confirmation_emails() {
     assert_eq!(second_response.status().as_u16(), 200);
 }
 
+#[tokio::test]
+async fn subscribing_with_a_differently_cased_email_reuses_the_subscription() {
+    // Arrange
+    let app = spawn_app().await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let first_response = app
+        .post_subscriptions("name=sara%20kuzoi&email=Sara_Kuzoi%40Tuta.io".into())
+        .await;
+    let second_response = app
+        .post_subscriptions("name=sara%20kuzoi&email=sara_kuzoi%40tuta.io".into())
+        .await;
+
+    // Assert
+    assert_eq!(first_response.status().as_u16(), 200);
+    assert_eq!(second_response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT email, email_normalised FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions");
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].email, "Sara_Kuzoi@Tuta.io");
+    assert_eq!(saved[0].email_normalised, "sara_kuzoi@tuta.io");
+}
+
+#[tokio::test]
+async fn idn_domains_backfilled_in_sql_are_converted_to_a_labels() {
+    // Arrange - the backfill could only lowercase the domain
+    let app = spawn_app().await;
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, email_normalised, name, subscribed_at, status)
+        VALUES ($1, 'Anna@Bücher.de', 'anna@bücher.de', 'anna', now(), 'confirmed')
+        "#,
+        Uuid::new_v4()
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    // Act
+    renormalise_subscriber_emails(&app.db_pool).await.unwrap();
+
+    // Assert
+    let saved = sqlx::query!("SELECT email, email_normalised FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription");
+    assert_eq!(saved.email, "Anna@Bücher.de");
+    assert_eq!(saved.email_normalised, "anna@xn--bcher-kva.de");
+}
+
+#[tokio::test]
+async fn re_normalised_duplicates_are_merged_into_the_confirmed_subscription() {
+    // Arrange
+    let app = spawn_app().await;
+    let legacy_id = Uuid::new_v4();
+    let confirmed_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, email_normalised, name, subscribed_at, status)
+        VALUES
+            ($1, 'anna@bücher.de', 'anna@bücher.de', 'anna', now() - interval '1 day', 'pending_confirmation'),
+            ($2, 'anna@xn--bcher-kva.de', 'anna@xn--bcher-kva.de', 'anna', now(), 'confirmed')
+        "#,
+        legacy_id,
+        confirmed_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        "INSERT INTO subscription_tokens (subscription_token, subscriber_id) VALUES ('legacy', $1)",
+        legacy_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    // Act
+    renormalise_subscriber_emails(&app.db_pool).await.unwrap();
+
+    // Assert
+    let saved = sqlx::query!("SELECT id, email_normalised FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions");
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].id, confirmed_id);
+    assert_eq!(saved[0].email_normalised, "anna@xn--bcher-kva.de");
+    let token = sqlx::query!("SELECT subscriber_id FROM subscription_tokens")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(token.subscriber_id, confirmed_id);
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_400_when_the_backend_cannot_deliver_to_the_address() {
+    // Arrange
+    let app = spawn_app().await;
+    // "用户@例子.测试" needs SMTPUTF8, which the test configuration does not enable
+    let body = "name=sara%20kuzoi&email=%E7%94%A8%E6%88%B7%40%E4%BE%8B%E5%AD%90.%E6%B5%8B%E8%AF%95";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn subscribe_silently_ignores_submissions_filling_the_honeypot() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=sara%20kuzoi&email=sara_kuzoi%40tuta.io&website=http%3A%2F%2Fspam.example";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_optional(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions");
+    assert!(saved.is_none());
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_400_without_a_valid_form_token() {
+    let app = spawn_app().await;
+    let body = "name=sara%20kuzoi&email=sara_kuzoi%40tuta.io";
+    let test_cases = vec![
+        (body.to_string(), "missing form token"),
+        (format!("{}&form_token=123.abc", body), "forged form token"),
+    ];
+
+    for (invalid_body, description) in test_cases {
+        let response = app
+            .post_subscriptions_without_form_token(invalid_body)
+            .await;
+
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not return a 400 Bad Request when the payload had a {}",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn subscribe_rejects_a_form_token_that_was_already_used() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let form_token = app
+        .form_tokens
+        .issue_at(chrono::Utc::now() - chrono::Duration::minutes(1));
+    let body = |email: &str| {
+        format!(
+            "name=sara%20kuzoi&email={}&form_token={}",
+            email, form_token
+        )
+    };
+    let first = app
+        .post_subscriptions_without_form_token(body("sara_kuzoi%40tuta.io"))
+        .await;
+    assert_eq!(first.status().as_u16(), 200);
+
+    // Act
+    let replayed = app
+        .post_subscriptions_without_form_token(body("ursula_le_guin%40gmail.com"))
+        .await;
+
+    // Assert
+    assert_eq!(replayed.status().as_u16(), 400);
+    assert!(replayed.text().await.unwrap().contains("already submitted"));
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_400_when_the_form_is_submitted_too_quickly() {
+    // Arrange
+    let app = spawn_app().await;
+    let form_token: serde_json::Value =
+        reqwest::get(&format!("{}/subscriptions/form_token", app.address))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+    let body = format!(
+        "name=sara%20kuzoi&email=sara_kuzoi%40tuta.io&form_token={}",
+        form_token["form_token"].as_str().unwrap()
+    );
+
+    // Act
+    let response = app.post_subscriptions_without_form_token(body).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    assert!(response.text().await.unwrap().contains("too quickly"));
+}
+
+#[tokio::test]
+async fn subscribe_verifies_the_challenge_response_when_configured() {
+    // Arrange
+    let challenge_server = MockServer::start().await;
+    let challenge_url = format!("{}/siteverify", challenge_server.uri());
+    let app = spawn_app_with(|c| {
+        c.bot_protection.challenge = Some(ChallengeSettings {
+            verify_url: challenge_url,
+            secret: Secret::new("challenge-secret".into()),
+            timeout_milliseconds: 200,
+        })
+    })
+    .await;
+
+    Mock::given(path("/siteverify"))
+        .and(body_string_contains("response=passed"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "success": true
+        })))
+        .mount(&challenge_server)
+        .await;
+    Mock::given(path("/siteverify"))
+        .and(body_string_contains("response=failed"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "success": false
+        })))
+        .mount(&challenge_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let body = "name=sara%20kuzoi&email=sara_kuzoi%40tuta.io";
+
+    // Act
+    let missing = app.post_subscriptions(body.into()).await;
+    let failed = app
+        .post_subscriptions(format!("{}&cf-turnstile-response=failed", body))
+        .await;
+    let passed = app
+        .post_subscriptions(format!("{}&h-captcha-response=passed", body))
+        .await;
+
+    // Assert
+    assert_eq!(missing.status().as_u16(), 400);
+    assert_eq!(failed.status().as_u16(), 400);
+    assert_eq!(passed.status().as_u16(), 200);
+}
+
 #[tokio::test]
 async fn subscribe_fails_if_there_is_a_fatal_database_error() {
     // Arrange
@@ -151,3 +526,239 @@ async fn subscribe_fails_if_there_is_a_fatal_database_error() {
     // Assert
     assert_eq!(response.status().as_u16(), 500);
 }
+
+#[tokio::test]
+async fn subscribe_does_not_queue_a_confirmation_email_if_the_token_cannot_be_stored() {
+    // Arrange
+    let test_app = spawn_app().await;
+    let body = "name=sara%20kuzoi&email=sara_kuzoi%40tuta.io";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&test_app.email_server)
+        .await;
+    // Sabotage db
+    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN subscription_token;",)
+        .execute(&test_app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = test_app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 500);
+    let subscribers = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap();
+    let queued = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM email_outbox")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscribers.count, 0);
+    assert_eq!(queued.count, 0);
+}
+
+#[tokio::test]
+async fn sent_confirmation_emails_are_removed_from_the_outbox() {
+    // Arrange
+    let test_app = spawn_app().await;
+    let body = "name=sara%20kuzoi&email=sara_kuzoi%40tuta.io";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    // Act
+    test_app.post_subscriptions(body.into()).await;
+
+    // Assert
+    let queued = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM email_outbox")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(queued.count, 0);
+}
+
+#[tokio::test]
+async fn subscribe_queues_the_confirmation_email_when_the_provider_is_failing() {
+    // Arrange
+    let test_app = spawn_app().await;
+    let body = "name=sara%20kuzoi&email=sara_kuzoi%40tuta.io";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&test_app.email_server)
+        .await;
+
+    // Act
+    let response = test_app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription");
+    assert_eq!(saved.status, "pending_confirmation");
+    let queued = sqlx::query!("SELECT recipient, subject, status FROM email_outbox")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .expect("Failed to fetch the queued email");
+    assert_eq!(queued.recipient, "sara_kuzoi@tuta.io");
+    assert_eq!(queued.status, "pending");
+}
+
+#[tokio::test]
+async fn subscribe_does_not_wait_for_the_provider() {
+    // Arrange
+    let test_app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(5)))
+        .mount(&test_app.email_server)
+        .await;
+    let form_token = test_app
+        .form_tokens
+        .issue_at(chrono::Utc::now() - chrono::Duration::minutes(1));
+
+    // Act
+    let started = std::time::Instant::now();
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &test_app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(format!(
+            "name=sara%20kuzoi&email=sara_kuzoi%40tuta.io&form_token={}",
+            form_token
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(started.elapsed() < std::time::Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn subscribe_stops_calling_the_provider_once_the_circuit_is_open() {
+    // Arrange
+    let test_app = spawn_app_with(|c| c.email_client.circuit_breaker.failure_threshold = 1).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(503))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    // Act
+    let first = test_app
+        .post_subscriptions("name=sara%20kuzoi&email=sara_kuzoi%40tuta.io".into())
+        .await;
+    let second = test_app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    // Assert
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 200);
+    let queued = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM email_outbox")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .expect("Failed to count the queued emails");
+    assert_eq!(queued.count, 2);
+}
+
+#[tokio::test]
+async fn queued_confirmation_emails_are_relayed_once_the_provider_recovers() {
+    // Arrange
+    let test_app = spawn_app_with(|c| c.email_outbox.poll_interval_milliseconds = 100).await;
+    let body = "name=sara%20kuzoi&email=sara_kuzoi%40tuta.io";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .mount(&test_app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    test_app.post_subscriptions(body.into()).await;
+
+    // Act
+    // Skip the backoff following the failed attempt
+    sqlx::query!("UPDATE email_outbox SET next_attempt_at = now()")
+        .execute(&test_app.db_pool)
+        .await
+        .unwrap();
+
+    // Assert
+    let mut remaining = 1;
+    for _ in 0..50 {
+        remaining = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM email_outbox")
+            .fetch_one(&test_app.db_pool)
+            .await
+            .expect("Failed to count the queued emails")
+            .count;
+        if remaining == 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(remaining, 0);
+}
+
+#[tokio::test]
+async fn emails_left_sending_are_relayed_once_their_lease_runs_out() {
+    // Arrange
+    let test_app = spawn_app_with(|c| c.email_outbox.poll_interval_milliseconds = 100).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    // Act - the instance sending these died, only one of them long enough ago
+    sqlx::query!(
+        r#"
+        INSERT INTO email_outbox (
+            email_outbox_id, recipient, subject, html_body, text_body,
+            status, next_attempt_at, created_at, claimed_at
+        )
+        VALUES
+            ($1, 'sara_kuzoi@tuta.io', 'Stalled', '<p>Hi</p>', 'Hi', 'sending', now(), now(), now() - interval '1 hour'),
+            ($2, 'sara_kuzoi@tuta.io', 'Being sent', '<p>Hi</p>', 'Hi', 'sending', now(), now(), now())
+        "#,
+        Uuid::new_v4(),
+        Uuid::new_v4()
+    )
+    .execute(&test_app.db_pool)
+    .await
+    .unwrap();
+
+    // Assert
+    let mut remaining = Vec::new();
+    for _ in 0..50 {
+        remaining = sqlx::query!("SELECT subject FROM email_outbox")
+            .fetch_all(&test_app.db_pool)
+            .await
+            .expect("Failed to fetch the queued emails")
+            .into_iter()
+            .map(|r| r.subject)
+            .collect();
+        if remaining.len() == 1 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(remaining, vec!["Being sent"]);
+}
@@ -35,7 +35,7 @@ async fn the_link_returned_by_subscribe_returns_a_200_if_called() {
     test_app.post_subscriptions(body.into()).await;
 
     let email_request = &test_app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = test_app.get_confirmation_links(&email_request);
+    let confirmation_links = test_app.get_confirmation_links(email_request);
 
     // Act
     let response = reqwest::get(confirmation_links.html).await.unwrap();
@@ -58,7 +58,7 @@ async fn clicking_on_the_confirmation_link_confirms_a_subscriber() {
 
     test_app.post_subscriptions(body.into()).await;
     let email_request = &test_app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = test_app.get_confirmation_links(&email_request);
+    let confirmation_links = test_app.get_confirmation_links(email_request);
 
     // Act
     reqwest::get(confirmation_links.html)
@@ -92,7 +92,7 @@ async fn clicking_twice_on_the_confirmation_link_confirms_a_subscriber() {
 
     test_app.post_subscriptions(body.into()).await;
     let email_request = &test_app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = test_app.get_confirmation_links(&email_request);
+    let confirmation_links = test_app.get_confirmation_links(email_request);
 
     // Act
     reqwest::get(confirmation_links.html)
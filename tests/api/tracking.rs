@@ -0,0 +1,208 @@
+use linkify::LinkKind;
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+use crate::helpers::{spawn_app, TestApp};
+use crate::newsletter::create_confirmed_subscriber;
+use zero2prod::unsubscribe::LinkPurpose;
+
+// Publish an issue to the confirmed subscribers and return the HTML body they received
+async fn publish_and_get_html(app: &TestApp, tracking: Option<bool>) -> String {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    let mut body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": r#"<p>Read <a href="https://example.com/post">our post</a></p>"#
+        }
+    });
+    if let Some(tracking) = tracking {
+        body["tracking"] = tracking.into();
+    }
+    app.post_newsletter(body).await.error_for_status().unwrap();
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    body["HtmlBody"].as_str().unwrap().to_owned()
+}
+
+// Find the link containing `pattern` in an email and point it to the application under test
+fn find_link(app: &TestApp, html: &str, pattern: &str) -> reqwest::Url {
+    let links: Vec<_> = linkify::LinkFinder::new()
+        .links(html)
+        .filter(|l| *l.kind() == LinkKind::Url && l.as_str().contains(pattern))
+        .collect();
+    assert_eq!(links.len(), 1);
+    // Query strings are HTML-escaped in the email
+    let mut link = reqwest::Url::parse(&links[0].as_str().replace("&amp;", "&")).unwrap();
+    link.set_port(Some(app.port)).unwrap();
+    link
+}
+
+fn without_redirects() -> reqwest::Client {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+}
+
+async fn newsletter_issue_id(app: &TestApp) -> String {
+    sqlx::query!("SELECT newsletter_issue_id FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .newsletter_issue_id
+        .to_string()
+}
+
+#[tokio::test]
+async fn opens_and_clicks_are_recorded_and_reported() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let html = publish_and_get_html(&app, None).await;
+    let click_link = find_link(&app, &html, "/t/click/");
+    let open_link = find_link(&app, &html, "/t/open/");
+
+    // Act
+    let click = without_redirects().get(click_link).send().await.unwrap();
+    let open = reqwest::get(open_link).await.unwrap();
+    let engagement = app
+        .get_admin(&format!(
+            "issues/{}/engagement",
+            newsletter_issue_id(&app).await
+        ))
+        .await;
+
+    // Assert
+    assert_eq!(click.status().as_u16(), 302);
+    assert_eq!(click.headers()["Location"], "https://example.com/post");
+    assert_eq!(open.status().as_u16(), 200);
+    assert_eq!(open.headers()["Content-Type"], "image/gif");
+    assert_eq!(engagement.status().as_u16(), 200);
+    let engagement: serde_json::Value = engagement.json().await.unwrap();
+    assert_eq!(engagement["tracked_recipients"], 1);
+    assert_eq!(engagement["unique_opens"], 1);
+    assert_eq!(engagement["unique_clicks"], 1);
+    assert_eq!(engagement["open_rate"], 1.0);
+    assert_eq!(engagement["links"][0]["url"], "https://example.com/post");
+    assert_eq!(engagement["links"][0]["clicks"], 1);
+}
+
+#[tokio::test]
+async fn issues_can_be_sent_without_tracking() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // Act
+    let html = publish_and_get_html(&app, Some(false)).await;
+
+    // Assert
+    assert!(html.contains(r#"href="https://example.com/post""#));
+    assert!(!html.contains("/t/open/"));
+    assert!(!html.contains("tracking_opt_out"));
+}
+
+#[tokio::test]
+async fn subscribers_who_opted_out_are_not_tracked() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let html = publish_and_get_html(&app, None).await;
+    let opt_out_link = find_link(&app, &html, "/subscriptions/tracking_opt_out");
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(opt_out_link)
+        .send()
+        .await
+        .unwrap();
+    let html = publish_and_get_html(&app, None).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(html.contains(r#"href="https://example.com/post""#));
+    assert!(!html.contains("/t/open/"));
+}
+
+#[tokio::test]
+async fn opening_the_opt_out_link_only_asks_for_a_confirmation() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let html = publish_and_get_html(&app, None).await;
+    let opt_out_link = find_link(&app, &html, "/subscriptions/tracking_opt_out");
+
+    // Act
+    let response = reqwest::get(opt_out_link).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let subscriber = sqlx::query!("SELECT tracking_opt_out FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(!subscriber.tracking_opt_out);
+}
+
+#[tokio::test]
+async fn unsubscribe_tokens_do_not_opt_out_of_tracking() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/subscriptions/tracking_opt_out?subscriber_id={}&token={}",
+            app.address,
+            subscriber.id,
+            app.unsubscribe_links
+                .token(LinkPurpose::Unsubscribe, subscriber.id)
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn tampered_click_links_are_not_followed() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let html = publish_and_get_html(&app, None).await;
+    let mut click_link = find_link(&app, &html, "/t/click/");
+    let tampered = format!("{}x", click_link.path());
+    click_link.set_path(&tampered);
+
+    // Act
+    let response = without_redirects().get(click_link).send().await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 404);
+    let events = sqlx::query!("SELECT COUNT(*) AS count FROM tracking_events")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(events.count, Some(0));
+}